@@ -17,38 +17,69 @@
 //! compressor.compress_to_jpg();
 //! ```
 
-use image::imageops::FilterType;
-use image::{ImageError, ImageFormat};
-use mozjpeg::{ColorSpace, Compress, ScanMode};
-use std::error::Error;
-use std::fs::File;
-use std::io::{BufReader, BufWriter, ErrorKind, Read, Seek, SeekFrom, Write};
+use crate::encoder::{JpegEncoder, OutputEncoder, PngEncoder, WebpEncoder};
+use crate::error::{BuildError, CompressError, FactorError};
+use crate::ssim::ssim;
+use image::codecs::gif::{GifDecoder, GifEncoder, Repeat};
+use image::{AnimationDecoder, Frame, ImageDecoder, ImageError, ImageFormat};
+use log::warn;
+use std::ffi::{OsStr, OsString};
+use std::fs::{File, FileTimes};
+use std::io::{BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
 use std::path::{Path, PathBuf};
-use std::{fs, io};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use std::{fs, io, process};
+
+pub use image::codecs::png::CompressionType;
+pub use image::imageops::FilterType;
+
+/// The lowest jpg quality tried by [`Compressor::compress_to_target_size`] and [`Compressor::compress_to_quality_floor`].
+const MIN_SEARCH_QUALITY: u32 = 20;
+
+/// The highest jpg quality tried by [`Compressor::compress_to_target_size`] and [`Compressor::compress_to_quality_floor`].
+const MAX_SEARCH_QUALITY: u32 = 95;
+
+/// The maximum number of compressions tried by [`Compressor::compress_to_target_size`] and [`Compressor::compress_to_quality_floor`].
+const MAX_SEARCH_ATTEMPTS: u32 = 8;
+
+/// How much lower than the SSIM at the highest quality of [`Compressor::set_quality_range`]
+/// the SSIM of the chosen quality can be.
+const QUALITY_RANGE_SSIM_TOLERANCE: f64 = 0.005;
+
+/// The default template of the file name of the new jpg image.
+const DEFAULT_OUTPUT_NAME_TEMPLATE: &str = "{stem}.jpg";
 
 /// Factor struct that used for setting quality and resize ratio in the new image.
 ///
-/// The [`Compressor`] and [`FolderCompressor`](super::FolderCompressor) need `Factor` for compressing images.
+/// The [`Compressor`] and [`FolderCompressor`] need `Factor` for compressing images.
 ///
 /// So, to create a new `Compressor` or `FolderCompressor` instance
 /// you need to define a new `Factor` instance contains the quality ratio of image and file size ratio to compress.
 ///
 /// The recommended range of quality is 60 to 80.
+///
+#[cfg_attr(
+    feature = "folder",
+    doc = "[`FolderCompressor`]: crate::FolderCompressor"
+)]
+#[cfg_attr(not(feature = "folder"), doc = "[`FolderCompressor`]: crate#features")]
 #[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
 pub struct Factor {
     /// Quality of the new compressed image.
-    /// Values range from 0 to 100 in float.
+    /// Values range from 0 (exclusive) to 100 (inclusive) in float.
     quality: f32,
 
     /// Ratio for resize the new compressed image.
-    /// Values range from 0 to 1 in float.
+    /// Values range from 0 (exclusive) to 1 (inclusive) in float.
     size_ratio: f32,
 }
 
 impl Factor {
     /// Create a new `Factor` instance.
-    /// The `quality` range from 0 to 100 in float,
-    /// and `size_ratio` range from 0 to 1 in float.
+    /// The `quality` range from 0 (exclusive) to 100 (inclusive) in float,
+    /// and `size_ratio` range from 0 (exclusive) to 1 (inclusive) in float.
     ///
     /// # Panics
     ///
@@ -56,15 +87,35 @@ impl Factor {
     /// - If the quality value exceeds 100.
     /// - If the size ratio value is 0 or less.
     /// - If the size ratio value exceeds 1.
+    ///
+    /// Use [`try_new`](Factor::try_new) to get an error instead.
     pub fn new(quality: f32, size_ratio: f32) -> Self {
-        if (quality > 0. && quality <= 100.) && (size_ratio > 0. && size_ratio <= 1.) {
-            Self {
-                quality,
-                size_ratio,
-            }
-        } else {
-            panic!("Wrong Factor argument!");
+        match Self::try_new(quality, size_ratio) {
+            Ok(factor) => factor,
+            Err(e) => panic!("Wrong Factor argument! {}", e),
+        }
+    }
+
+    /// Create a new `Factor` instance, or return an error if a value is out of range.
+    /// The ranges are the same as [`new`](Factor::new), and NaN is rejected.
+    /// # Examples
+    /// ```
+    /// use image_compressor::{Factor, FactorError};
+    ///
+    /// assert!(Factor::try_new(80., 0.8).is_ok());
+    /// assert_eq!(Factor::try_new(0., 0.8), Err(FactorError::Quality(0.)));
+    /// ```
+    pub fn try_new(quality: f32, size_ratio: f32) -> Result<Self, FactorError> {
+        if !(quality > 0. && quality <= 100.) {
+            return Err(FactorError::Quality(quality));
+        }
+        if !(size_ratio > 0. && size_ratio <= 1.) {
+            return Err(FactorError::SizeRatio(size_ratio));
         }
+        Ok(Self {
+            quality,
+            size_ratio,
+        })
     }
 
     /// Getter for `quality` of `Factor`.
@@ -87,259 +138,4610 @@ impl Default for Factor {
     }
 }
 
-/// Compressor struct.
-pub struct Compressor<O: AsRef<Path>, D: AsRef<Path>> {
-    factor: Factor,
-    source_path: O,
-    dest_path: D,
-    delete_source: bool,
+/// Statistics of a compressed image.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CompressionReport {
+    /// Path of the new compressed image.
+    pub path: PathBuf,
+
+    /// File size of the source image in bytes.
+    pub original_size: u64,
+
+    /// File size of the new compressed image in bytes.
+    pub compressed_size: u64,
+
+    /// Width and height of the source image.
+    pub original_dimensions: (u32, u32),
+
+    /// Width and height of the new compressed image.
+    pub dimensions: (u32, u32),
+
+    /// Whether the source file is copied as it is instead of the compressed image,
+    /// because the compressed image is larger. See [`Compressor::set_keep_if_larger`].
+    pub kept_original: bool,
+
+    /// Whether the source file is copied as it is without the compression,
+    /// because it is a small jpg image. See [`Compressor::set_skip_if_smaller_than`].
+    pub skipped: bool,
+
+    /// Time taken to read and decode the source image.
+    pub decode_time: Duration,
+
+    /// Time taken to resize the image.
+    pub resize_time: Duration,
+
+    /// Time taken to encode the new image.
+    pub encode_time: Duration,
 }
 
-impl<O: AsRef<Path>, D: AsRef<Path>> Compressor<O, D> {
-    /// Create a new `Compressor` instance.
-    pub fn new(source_path: O, dest_dir_path: D) -> Self {
-        Compressor {
-            factor: Factor::default(),
-            source_path,
-            dest_path: dest_dir_path,
-            delete_source: false,
+impl CompressionReport {
+    /// Ratio of the compressed file size to the original file size.
+    ///
+    /// Values less than 1 mean the image got smaller.
+    pub fn compression_ratio(&self) -> f64 {
+        if self.original_size == 0 {
+            return 0.;
         }
+        self.compressed_size as f64 / self.original_size as f64
     }
+}
 
-    /// Set factor for the new compressed image.
-    pub fn set_factor(&mut self, factor: Factor) {
-        self.factor = factor;
-    }
+/// Shared calculator function that returns a [`Factor`] from
+/// the width, the height, and the file size in bytes of the source image.
+pub type CalFunc = Arc<dyn Fn(u32, u32, u64) -> Factor + Send + Sync>;
 
-    /// Sets whether the program deletes the source file.
-    pub fn set_delete_source(&mut self, to_delete: bool) {
-        self.delete_source = to_delete;
+/// Pixel data of a resized image.
+///
+/// The pixels are tightly packed, row by row, with 8 bits per channel,
+/// or 16 bits per channel for the 16-bit variants.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ResizedPixels {
+    /// Pixels of an opaque image, 3 bytes per pixel.
+    Rgb(Vec<u8>),
+    /// Pixels of an image with an alpha channel, 4 bytes per pixel.
+    Rgba(Vec<u8>),
+    /// Pixels of an opaque 16-bit image, 3 values per pixel.
+    /// Made only for the encoders that [keep 16 bits](crate::encoder::OutputEncoder::supports_16bit).
+    Rgb16(Vec<u16>),
+    /// Pixels of a 16-bit image with an alpha channel, 4 values per pixel.
+    /// Made only for the encoders that [keep 16 bits](crate::encoder::OutputEncoder::supports_16bit).
+    Rgba16(Vec<u16>),
+}
+
+impl ResizedPixels {
+    /// Take the pixels of the image, keeping the alpha channel only if the image has one.
+    ///
+    /// If `keep_16bit` is true and the image has 16 bits per channel, the 16-bit pixels are taken.
+    fn from_image(img: image::DynamicImage, keep_16bit: bool) -> Self {
+        match (img.color().has_alpha(), keep_16bit && is_16bit(&img)) {
+            (true, true) => ResizedPixels::Rgba16(img.into_rgba16().into_vec()),
+            (false, true) => ResizedPixels::Rgb16(img.into_rgb16().into_vec()),
+            (true, false) => ResizedPixels::Rgba(img.into_rgba8().into_vec()),
+            (false, false) => ResizedPixels::Rgb(img.into_rgb8().into_vec()),
+        }
     }
 
-    /// Compress the image to jpg format.
-    /// The new image will be saved in the destination directory.
-    fn compress(
-        &self,
-        img: image::DynamicImage,
-        target_width: usize,
-        target_height: usize,
-        quality: f32,
-    ) -> Result<Vec<u8>, Box<dyn Error>> {
-        let mut comp = Compress::new(ColorSpace::JCS_RGB);
-        comp.set_scan_optimization_mode(ScanMode::Auto);
-        comp.set_quality(quality);
+    /// Number of bytes per pixel.
+    pub(crate) fn channels(&self) -> usize {
+        match self {
+            ResizedPixels::Rgb(_) => 3,
+            ResizedPixels::Rgba(_) => 4,
+            ResizedPixels::Rgb16(_) => 6,
+            ResizedPixels::Rgba16(_) => 8,
+        }
+    }
 
-        comp.set_size(target_width, target_height);
+    /// Length of the pixels in bytes.
+    pub(crate) fn len(&self) -> usize {
+        match self {
+            ResizedPixels::Rgb(pixels) | ResizedPixels::Rgba(pixels) => pixels.len(),
+            ResizedPixels::Rgb16(pixels) | ResizedPixels::Rgba16(pixels) => pixels.len() * 2,
+        }
+    }
 
-        comp.set_optimize_scans(true);
-        let mut comp = comp.start_compress(Vec::new())?;
+    /// Convert the 16-bit pixels to 8 bits per channel, rounding to the nearest value.
+    /// The 8-bit pixels are returned as they are.
+    pub fn into_8bit(self) -> Self {
+        let to_8bit = |pixels: Vec<u16>| -> Vec<u8> {
+            pixels
+                .into_iter()
+                .map(|v| ((v as u32 + 128) / 257) as u8)
+                .collect()
+        };
+        match self {
+            ResizedPixels::Rgb16(pixels) => ResizedPixels::Rgb(to_8bit(pixels)),
+            ResizedPixels::Rgba16(pixels) => ResizedPixels::Rgba(to_8bit(pixels)),
+            pixels => pixels,
+        }
+    }
 
-        let mut line = 0;
-        let img_vec = img.to_rgb8().into_vec();
-        while line < target_height {
-            comp.write_scanlines(&img_vec[line * target_width * 3..(line + 1) * target_width * 3])?;
-            line += 1;
+    /// Convert the pixels to 8-bit RGB.
+    ///
+    /// Transparent pixels are flattened over a white background,
+    /// which is used for the formats that cannot keep the alpha channel such as jpg.
+    pub fn into_rgb(self) -> Vec<u8> {
+        match self.into_8bit() {
+            ResizedPixels::Rgb(pixels) => pixels,
+            ResizedPixels::Rgba(pixels) => pixels
+                .chunks_exact(4)
+                .flat_map(|p| {
+                    let alpha = p[3] as u32;
+                    let blend =
+                        |c: u8| ((c as u32 * alpha + 255 * (255 - alpha) + 127) / 255) as u8;
+                    [blend(p[0]), blend(p[1]), blend(p[2])]
+                })
+                .collect(),
+            ResizedPixels::Rgb16(_) | ResizedPixels::Rgba16(_) => {
+                unreachable!("the pixels are converted to 8 bits")
+            }
         }
-        let compressed = comp.finish()?;
-        Ok(compressed)
     }
+}
 
-    /// Resize the image vector.
-    fn resize(
-        &self,
-        img: image::DynamicImage,
-        resize_ratio: f32,
-    ) -> Result<(image::DynamicImage, usize, usize), Box<dyn Error>> {
-        let width = img.width() as usize;
-        let height = img.height() as usize;
+/// Check whether the image has 16 bits per channel.
+fn is_16bit(img: &image::DynamicImage) -> bool {
+    matches!(
+        img.color(),
+        image::ColorType::L16
+            | image::ColorType::La16
+            | image::ColorType::Rgb16
+            | image::ColorType::Rgba16
+    )
+}
 
-        let width = width as f32 * resize_ratio;
-        let height = height as f32 * resize_ratio;
+/// Chroma subsampling of the jpg image.
+///
+/// Subsampling stores the color at a lower resolution than the brightness,
+/// which makes files smaller but blurs sharp colored edges such as text in screenshots.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Subsampling {
+    /// 4:2:0, the color is halved horizontally and vertically.
+    #[default]
+    Sub420,
 
-        let resized_img = img.resize(width as u32, height as u32, FilterType::Triangle);
+    /// 4:2:2, the color is halved horizontally.
+    Sub422,
 
-        let resized_width = resized_img.width() as usize;
-        let resized_height = resized_img.height() as usize;
+    /// 4:4:4, the color is kept at full resolution.
+    Sub444,
+}
 
-        Ok((resized_img, resized_width, resized_height))
+impl Subsampling {
+    /// Size of a chroma pixel in luma pixels, for both Cb and Cr channels.
+    pub(crate) fn pixel_sizes(&self) -> (u8, u8) {
+        match self {
+            Subsampling::Sub420 => (2, 2),
+            Subsampling::Sub422 => (2, 1),
+            Subsampling::Sub444 => (1, 1),
+        }
     }
+}
 
-    /// Guess actual image format
-    fn guess_image_format(&self, source_file_path: &Path) -> Result<ImageFormat, ImageError> {
-        let mut file = File::open(source_file_path)?;
-        let _ = file.seek(SeekFrom::Start(0));
-        let mut buf = vec![0; 10];
-        let _ = file.read_exact(&mut buf);
+/// How the size of the new compressed image is decided.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ResizeMode {
+    /// Scale the image by the size ratio of the `Factor`.
+    #[default]
+    Ratio,
 
-        image::guess_format(buf.as_slice())
-    }
+    /// Scale the image to fit within the maximum width and height, keeping its aspect ratio.
+    /// Images that already fit are not resized.
+    MaxDimensions(u32, u32),
+}
 
-    /// Compress a file.
-    ///
-    /// Compress the given image file and save it to target_dir.
-    /// If the extension of the given image file is not jpg or jpeg, convert the image to jpg file.
-    /// If the image module can not open the file, such as pdf, mp4, etc., just copy it to target_dir.
-    /// Compress quality and resize ratio calculate based on file size of the image.
-    /// For a continuous multithreading process, every single error doesn't occur panic or exception and just print error message with return Ok.
-    ///
-    /// If the flag to delete the source is true, the function delete the source file.
-    pub fn compress_to_jpg(&self) -> Result<PathBuf, Box<dyn Error>> {
-        let source_file_path = self.source_path.as_ref();
-        let target_dir = self.dest_path.as_ref();
+/// What [`Compressor`] does with a source file that cannot be opened as an image.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum UnreadablePolicy {
+    /// Copy the file to the destination directory as it is, and return the error.
+    #[default]
+    CopyThrough,
 
-        let file_name = match source_file_path.file_name() {
-            Some(e) => e.to_str().unwrap_or(""),
-            None => "",
-        };
+    /// Leave the file alone and return [`CompressError::Skipped`].
+    Skip,
 
-        let file_stem = source_file_path.file_stem().unwrap();
-
-        let mut target_file_name = PathBuf::from(file_stem);
-        target_file_name.set_extension("jpg");
-        let target_file = target_dir.join(&target_file_name);
-        if target_file.is_file() {
-            return Err(Box::new(io::Error::new(
-                ErrorKind::AlreadyExists,
-                format!(
-                    "A file with the same name exists: {}",
-                    target_file.file_name().unwrap().to_str().unwrap()
-                ),
-            )));
-        }
+    /// Leave the file alone and return the error of the decoding.
+    Fail,
+}
 
-        let Ok(guessed_format) = self.guess_image_format(source_file_path) else {
-            return Err(Box::new(io::Error::new(
-                ErrorKind::InvalidInput,
-                "Unrecognized image format",
-            )));
-        };
+/// How [`Compressor`] compresses a gif image with more than one frame.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum GifMode {
+    /// Compress only the first frame to a jpg image, like any other image.
+    #[default]
+    FirstFrame,
 
-        // let mut converted_file: Option<PathBuf> = None;
-        let image_vec = match image::load(
-            BufReader::new(File::open(source_file_path)?),
-            guessed_format,
-        ) {
-            Ok(p) => p,
-            Err(e) => {
-                let m = format!(
-                    "Cannot open file {} as image. Just copy it: {}",
-                    file_name, e
-                );
-                fs::copy(source_file_path, target_dir.join(file_name))?;
-                return Err(Box::new(io::Error::new(ErrorKind::InvalidData, m)));
-            }
-        };
+    /// Resize every frame and re-encode them as an animated gif image, keeping the frame delays.
+    Animated,
+}
 
-        let (resized_img_data, target_width, target_height) =
-            self.resize(image_vec, self.factor.size_ratio())?;
-        let compressed_img_data = match self.compress(
-            resized_img_data,
-            target_width,
-            target_height,
-            self.factor.quality(),
-        ) {
-            Ok(p) => p,
-            Err(e) => {
-                let m = format!("Cannot compress file {}: {}", file_name, e);
-                return Err(Box::new(io::Error::new(ErrorKind::InvalidData, m)));
-            }
-        };
+/// Format of the new image, for the formats that [`FolderCompressor`] picks per file.
+///
+#[cfg_attr(
+    feature = "folder",
+    doc = "[`FolderCompressor`]: crate::FolderCompressor"
+)]
+#[cfg_attr(not(feature = "folder"), doc = "[`FolderCompressor`]: crate#features")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OutputFormat {
+    /// Compress to jpg format, like [`Compressor::compress_to_jpg`].
+    #[default]
+    Jpeg,
 
-        let mut file = BufWriter::new(File::create(&target_file)?);
-        file.write_all(&compressed_img_data)?;
+    /// Re-encode to png format, like [`Compressor::compress_to_png`].
+    Png,
 
-        // Delete the source file when the flag is true.
-        if self.delete_source {
-            fs::remove_file(&self.source_path)?;
+    /// Compress to lossy webp format, like [`Compressor::compress_to_webp`].
+    Webp,
+}
+
+/// Where [`Compressor`] draws the watermark on the new image.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum WatermarkPosition {
+    /// Draw the watermark once at the bottom right corner, with a small margin.
+    #[default]
+    BottomRight,
+
+    /// Draw the watermark once at the center.
+    Center,
+
+    /// Repeat the watermark over the whole image.
+    Tiled,
+}
+
+/// The watermark image loaded by [`Compressor::set_watermark`] and how it is drawn.
+#[derive(Debug, Clone)]
+struct Watermark {
+    image: Arc<image::RgbaImage>,
+    position: WatermarkPosition,
+    opacity: f32,
+}
+
+/// Resize the decoded image by the ratio with the filter, keeping its aspect ratio.
+///
+/// It is the resize that [`Compressor`] does with the size ratio of the `Factor`,
+/// so it can be used for other pipelines without a `Compressor`.
+/// The new image is at least 1 pixel wide and high, even with a tiny ratio.
+/// # Examples
+/// ```
+/// use image::DynamicImage;
+/// use image_compressor::compressor::resize_image;
+/// use image_compressor::FilterType;
+///
+/// let img = DynamicImage::new_rgb8(64, 32);
+/// let resized = resize_image(&img, 0.5, FilterType::Triangle);
+/// assert_eq!((resized.width(), resized.height()), (32, 16));
+/// ```
+pub fn resize_image(
+    img: &image::DynamicImage,
+    ratio: f32,
+    filter: FilterType,
+) -> image::DynamicImage {
+    let width = (img.width() as f32 * ratio).max(1.);
+    let height = (img.height() as f32 * ratio).max(1.);
+    img.resize(width as u32, height as u32, filter)
+}
+
+/// Decode all the frames of the gif data.
+fn decode_gif_frames<R: BufRead + Seek>(reader: R) -> Result<Vec<Frame>, ImageError> {
+    GifDecoder::new(reader)?.into_frames().collect_frames()
+}
+
+/// Convert the pixels to a luma image, to compare them by SSIM.
+fn luma_of(
+    pixels: &ResizedPixels,
+    width: usize,
+    height: usize,
+) -> Result<image::GrayImage, CompressError> {
+    let rgb_img = image::RgbImage::from_raw(width as u32, height as u32, pixels.clone().into_rgb())
+        .ok_or_else(|| {
+            CompressError::Encode(
+                "The size of the pixels does not match the image size".to_string(),
+            )
+        })?;
+    Ok(image::DynamicImage::ImageRgb8(rgb_img).into_luma8())
+}
+
+/// Check whether the two paths point to the same existing file.
+fn is_same_file(a: &Path, b: &Path) -> bool {
+    match (a.canonicalize(), b.canonicalize()) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => false,
+    }
+}
+
+/// The largest image in bytes that [`Compressor::from_url`] downloads.
+#[cfg(feature = "url")]
+const MAX_DOWNLOAD_SIZE: u64 = 256 * 1024 * 1024;
+
+/// Build the file name of the image downloaded from the URL path.
+///
+/// The extension of the `Content-Type` is used if the path has none, and `image` if the path has no file name.
+#[cfg(feature = "url")]
+fn download_file_name(url_path: &str, extension: Option<&str>) -> PathBuf {
+    let mut file_name = PathBuf::from(
+        Path::new(url_path)
+            .file_name()
+            .unwrap_or(OsStr::new("image")),
+    );
+    if file_name.extension().is_none() {
+        if let Some(extension) = extension {
+            file_name.set_extension(extension);
         }
-        Ok(target_file)
     }
+    file_name
 }
 
-#[cfg(test)]
-mod tests {
-    use std::fs;
+/// Number of the temporary files made by this process, used to give each of them a unique name.
+static TEMP_FILE_COUNT: AtomicU64 = AtomicU64::new(0);
 
-    use super::*;
+/// Build the path of the temporary file in the given directory
+/// that is written before it is renamed to the target file.
+///
+/// The name is unique in the process, so that the targets with the same file name in different directories
+/// do not share a temporary file.
+fn temp_file_path(temp_dir: &Path, target_file: &Path) -> PathBuf {
+    let mut file_name = OsStr::new(".").to_os_string();
+    file_name.push(target_file.file_name().unwrap_or_default());
+    file_name.push(format!(
+        ".{}.{}.tmp",
+        process::id(),
+        TEMP_FILE_COUNT.fetch_add(1, Ordering::Relaxed)
+    ));
+    temp_dir.join(file_name)
+}
 
-    use image::ImageBuffer;
-    use rand::Rng;
-    use std::path::{Path, PathBuf};
+/// Number of the names tried for a temporary file before giving up, when the files of the names already exist.
+const MAX_TEMP_FILE_ATTEMPTS: u32 = 100;
 
-    /// Create test directory and an image file in it.
-    fn setup<T: AsRef<Path>>(test_name: T) -> (PathBuf, Vec<PathBuf>) {
-        let test_dir = test_name.as_ref().to_path_buf();
-        if test_dir.is_dir() {
-            fs::remove_dir_all(&test_dir).unwrap();
+/// Check whether the file cannot be renamed because the target is on another filesystem.
+fn is_cross_device(e: &io::Error) -> bool {
+    // EXDEV on unix, and ERROR_NOT_SAME_DEVICE on windows.
+    #[cfg(unix)]
+    const CROSS_DEVICE: Option<i32> = Some(18);
+    #[cfg(windows)]
+    const CROSS_DEVICE: Option<i32> = Some(17);
+    #[cfg(not(any(unix, windows)))]
+    const CROSS_DEVICE: Option<i32> = None;
+    CROSS_DEVICE.is_some() && e.raw_os_error() == CROSS_DEVICE
+}
+
+/// Read the EXIF data of the image, if there is any.
+fn read_exif<R: BufRead + Seek>(reader: &mut R) -> Option<exif::Exif> {
+    exif::Reader::new().read_from_container(reader).ok()
+}
+
+/// Density in dots per inch of the new jpg image when neither the DPI nor the density of the source is known.
+const DEFAULT_DPI: (u16, u16) = (72, 72);
+
+/// Read the density of the image in dots per inch.
+///
+/// It is read from the JFIF header of a jpg image or the pHYs chunk of a png image,
+/// and from the resolution of the EXIF data for the other formats.
+/// Returns `None` if the image has no density or only an aspect ratio.
+fn read_density<R: Read + Seek>(reader: &mut R, exif: Option<&exif::Exif>) -> Option<(u16, u16)> {
+    reader.seek(SeekFrom::Start(0)).ok()?;
+    let mut header = [0u8; 18];
+    reader.read_exact(&mut header).ok()?;
+    let density = if header.starts_with(&[0xFF, 0xD8, 0xFF, 0xE0]) && &header[6..11] == b"JFIF\0" {
+        let x = u16::from_be_bytes([header[14], header[15]]) as f64;
+        let y = u16::from_be_bytes([header[16], header[17]]) as f64;
+        match header[13] {
+            1 => Some((x, y)),
+            2 => Some((x * 2.54, y * 2.54)),
+            _ => None,
         }
-        fs::create_dir_all(&test_dir).unwrap();
+    } else if header.starts_with(b"\x89PNG\r\n\x1a\n") {
+        read_png_density(reader)
+    } else {
+        None
+    };
+    density
+        .or_else(|| read_exif_density(exif?))
+        .filter(|&(x, y)| x >= 1. && y >= 1.)
+        .map(|(x, y)| {
+            (
+                x.round().min(u16::MAX as f64) as u16,
+                y.round().min(u16::MAX as f64) as u16,
+            )
+        })
+}
 
-        const WIDTH: u32 = 256;
-        const HEIGHT: u32 = 256;
-        let img_stripe = ImageBuffer::from_fn(WIDTH, HEIGHT, |x, _| {
-            if x % 10 == 0 {
-                image::Luma([0u8])
-            } else {
-                image::Luma([255u8])
+/// Read the density in dots per inch from the pHYs chunk of a png image, before the image data.
+fn read_png_density<R: Read + Seek>(reader: &mut R) -> Option<(f64, f64)> {
+    reader.seek(SeekFrom::Start(8)).ok()?;
+    loop {
+        let mut chunk_header = [0u8; 8];
+        reader.read_exact(&mut chunk_header).ok()?;
+        let length = u32::from_be_bytes([
+            chunk_header[0],
+            chunk_header[1],
+            chunk_header[2],
+            chunk_header[3],
+        ]);
+        match &chunk_header[4..] {
+            b"pHYs" => {
+                let mut data = [0u8; 9];
+                reader.read_exact(&mut data).ok()?;
+                // Only the unit of meters gives a density, the other unit is just an aspect ratio.
+                if data[8] != 1 {
+                    return None;
+                }
+                let x = u32::from_be_bytes([data[0], data[1], data[2], data[3]]) as f64;
+                let y = u32::from_be_bytes([data[4], data[5], data[6], data[7]]) as f64;
+                return Some((x * 0.0254, y * 0.0254));
             }
-        });
-        let stripe_path = test_dir.join("img_stripe.png");
-        img_stripe.save(&stripe_path).unwrap();
-        let img_random_rgb = ImageBuffer::from_fn(WIDTH, HEIGHT, |_, _| {
-            let r = rand::thread_rng().gen_range(0..256) as u8;
-            let g = rand::thread_rng().gen_range(0..256) as u8;
-            let b = rand::thread_rng().gen_range(0..256) as u8;
-            image::Rgb([r, g, b])
-        });
-        let rgb_path = test_dir.join("img_random_rgb.gif");
-        img_random_rgb.save(&rgb_path).unwrap();
+            b"IDAT" | b"IEND" => return None,
+            // Skip the data and the CRC of the other chunks.
+            _ => {
+                reader.seek(SeekFrom::Current(length as i64 + 4)).ok()?;
+            }
+        }
+    }
+}
 
-        (test_dir, vec![stripe_path, rgb_path])
+/// Read the density in dots per inch from the resolution tags of the EXIF data.
+fn read_exif_density(exif: &exif::Exif) -> Option<(f64, f64)> {
+    let resolution = |tag| match exif.get_field(tag, exif::In::PRIMARY)?.value {
+        exif::Value::Rational(ref rationals) => rationals.first().map(|r| r.to_f64()),
+        _ => None,
+    };
+    let (x, y) = (
+        resolution(exif::Tag::XResolution)?,
+        resolution(exif::Tag::YResolution)?,
+    );
+    let unit = exif
+        .get_field(exif::Tag::ResolutionUnit, exif::In::PRIMARY)
+        .and_then(|field| field.value.get_uint(0))
+        .unwrap_or(2);
+    match unit {
+        2 => Some((x, y)),
+        3 => Some((x * 2.54, y * 2.54)),
+        _ => None,
     }
+}
 
-    fn cleanup<T: AsRef<Path>>(test_dir: T) {
-        if test_dir.as_ref().is_dir() {
-            fs::remove_dir_all(&test_dir).unwrap();
+/// Read the orientation tag of the EXIF data.
+/// Returns 1, which means the image is upright, if there is no orientation tag.
+fn read_orientation(exif: Option<&exif::Exif>) -> u32 {
+    exif.and_then(|exif| {
+        exif.get_field(exif::Tag::Orientation, exif::In::PRIMARY)
+            .and_then(|field| field.value.get_uint(0))
+    })
+    .unwrap_or(1)
+}
+
+/// Set the orientation tag in the first IFD of the raw TIFF data of an EXIF block to 1, which means upright.
+///
+/// It is used when the pixels are already turned upright, so that viewers do not rotate them again.
+/// Nothing is changed if the data is malformed or has no orientation tag.
+fn reset_orientation(tiff: &mut [u8]) {
+    let little_endian = match tiff.get(..4) {
+        Some(b"II*\0") => true,
+        Some(b"MM\0*") => false,
+        _ => return,
+    };
+    let read_u16 = |tiff: &[u8], offset: usize| -> Option<u16> {
+        let bytes = [*tiff.get(offset)?, *tiff.get(offset + 1)?];
+        Some(if little_endian {
+            u16::from_le_bytes(bytes)
+        } else {
+            u16::from_be_bytes(bytes)
+        })
+    };
+    let ifd_offset = match tiff.get(4..8) {
+        Some(b) if little_endian => u32::from_le_bytes([b[0], b[1], b[2], b[3]]) as usize,
+        Some(b) => u32::from_be_bytes([b[0], b[1], b[2], b[3]]) as usize,
+        None => return,
+    };
+    let Some(entry_count) = read_u16(tiff, ifd_offset) else {
+        return;
+    };
+    for i in 0..entry_count as usize {
+        let entry = ifd_offset + 2 + i * 12;
+        if read_u16(tiff, entry) == Some(0x0112) && read_u16(tiff, entry + 2) == Some(3) {
+            let upright = if little_endian {
+                1u16.to_le_bytes()
+            } else {
+                1u16.to_be_bytes()
+            };
+            if let Some(value) = tiff.get_mut(entry + 8..entry + 10) {
+                value.copy_from_slice(&upright);
+            }
+            return;
         }
     }
+}
 
-    #[test]
-    fn skip_wrong_ext_test() {
-        let (test_dir, _) = setup("skip_wrong_ext_test_dir");
-        let txt_data = "Hello, World!";
-        let mut txt_path = PathBuf::from(&test_dir).join("skip_wrong_ext_test.txt");
-        let mut txt_file = File::create(&txt_path).unwrap();
-        write!(txt_file, "{}", txt_data).unwrap();
-
-        let compressor = Compressor::new(&txt_path, &test_dir);
-        assert!(compressor.compress_to_jpg().is_err());
-        assert!(txt_path.is_file());
-        txt_path.set_extension("jpg");
-        assert!(!txt_path.is_file());
-        cleanup(test_dir);
+/// Rotate and flip the image so that its pixels are upright for the given EXIF orientation.
+fn apply_orientation(img: image::DynamicImage, orientation: u32) -> image::DynamicImage {
+    match orientation {
+        2 => img.fliph(),
+        3 => img.rotate180(),
+        4 => img.flipv(),
+        5 => img.rotate90().fliph(),
+        6 => img.rotate90(),
+        7 => img.rotate270().fliph(),
+        8 => img.rotate270(),
+        _ => img,
     }
+}
 
-    #[test]
-    fn compress_to_jpg_test() {
-        let (test_dir, mut test_images) = setup("compress_to_jpg_test");
+/// Metadata of the source image that can be carried over to the new compressed image.
+#[derive(Debug, Clone, Default)]
+struct ImageMetadata {
+    /// ICC color profile of the source image.
+    icc_profile: Option<Vec<u8>>,
 
-        let dest_dir = PathBuf::from("compress_to_jpg_dest_dir");
-        fs::create_dir_all(&dest_dir).unwrap();
+    /// Raw TIFF data of the EXIF block of the source image, read only when the metadata is preserved.
+    exif: Option<Vec<u8>>,
 
-        for test_image in &test_images {
-            let mut compressor = Compressor::new(test_image, &dest_dir);
-            compressor.set_factor(Factor::new(80., 1.0));
-            compressor.compress_to_jpg().unwrap();
+    /// Density of the source image in dots per inch, read only when the DPI is not set.
+    density: Option<(u16, u16)>,
+}
+
+/// Decoded source image and its metadata.
+struct SourceImage {
+    image: image::DynamicImage,
+    metadata: ImageMetadata,
+    decode_time: Duration,
+}
+
+/// A jpg image compressed in memory with the information for its report.
+struct EncodedJpg {
+    data: Vec<u8>,
+    original_size: u64,
+    original_dimensions: (u32, u32),
+    width: usize,
+    height: usize,
+    quality: f32,
+    decode_time: Duration,
+    resize_time: Duration,
+    encode_time: Duration,
+}
+
+impl EncodedJpg {
+    /// Make the report of the image saved to the path.
+    fn report(&self, path: PathBuf) -> CompressionReport {
+        CompressionReport {
+            path,
+            original_size: self.original_size,
+            compressed_size: self.data.len() as u64,
+            original_dimensions: self.original_dimensions,
+            dimensions: (self.width as u32, self.height as u32),
+            kept_original: false,
+            skipped: false,
+            decode_time: self.decode_time,
+            resize_time: self.resize_time,
+            encode_time: self.encode_time,
         }
-        test_images = test_images
-            .iter()
-            .map(|image| dest_dir.join(image.file_name().unwrap()))
-            .collect();
-        for new_image in &test_images {
-            let mut new_test_image = new_image.clone();
-            new_test_image.set_extension("jpg");
-            assert!(new_test_image.is_file());
+    }
+
+    /// Make the report of the source file kept as it is at the path.
+    fn kept_source_report(&self, path: PathBuf) -> CompressionReport {
+        CompressionReport {
+            path,
+            original_size: self.original_size,
+            compressed_size: self.original_size,
+            original_dimensions: self.original_dimensions,
+            dimensions: self.original_dimensions,
+            kept_original: true,
+            skipped: false,
+            decode_time: self.decode_time,
+            resize_time: self.resize_time,
+            encode_time: self.encode_time,
         }
+    }
+}
+
+/// Compressor struct.
+pub struct Compressor<O: AsRef<Path>, D: AsRef<Path>> {
+    factor: Factor,
+    cal_func: Option<CalFunc>,
+    source_path: O,
+    dest_path: D,
+    delete_source: bool,
+    png_compression: CompressionType,
+    resize_mode: ResizeMode,
+    crop_aspect: Option<(u32, u32)>,
+    watermark: Option<Watermark>,
+    min_dimensions: Option<(u32, u32)>,
+    filter_type: FilterType,
+    filter_for_size: Option<(FilterType, FilterType, u64)>,
+    auto_orient: bool,
+    preserve_icc: bool,
+    preserve_metadata: bool,
+    subsampling: Subsampling,
+    progressive: bool,
+    grayscale: bool,
+    smoothing: u8,
+    trellis: bool,
+    quality_range: Option<(f32, f32)>,
+    min_quality: Option<f32>,
+    dpi: Option<(u16, u16)>,
+    overwrite: bool,
+    unreadable_policy: UnreadablePolicy,
+    gif_mode: GifMode,
+    dry_run: bool,
+    preserve_timestamps: bool,
+    temp_dir: PathBuf,
+    output_stem: Option<String>,
+    output_name_template: String,
+    output_extension: Option<String>,
+    keep_if_larger: bool,
+    skip_if_smaller_than: Option<u64>,
+    encoder: Option<Box<dyn OutputEncoder>>,
+    source_data: Option<Vec<u8>>,
+    remote_source: bool,
+}
+
+/// Builder of a [`Compressor`], made with [`Compressor::builder`].
+///
+/// The source and destination must be set; the other options take the defaults of [`Compressor::new`].
+#[derive(Debug, Clone)]
+pub struct CompressorBuilder<O, D> {
+    source_path: Option<O>,
+    dest_path: Option<D>,
+    factor: Factor,
+    delete_source: bool,
+    overwrite: bool,
+}
+
+impl<O: AsRef<Path>, D: AsRef<Path>> CompressorBuilder<O, D> {
+    /// Set the source image file.
+    pub fn source(mut self, source_path: O) -> Self {
+        self.source_path = Some(source_path);
+        self
+    }
+
+    /// Set the destination directory.
+    pub fn dest(mut self, dest_dir_path: D) -> Self {
+        self.dest_path = Some(dest_dir_path);
+        self
+    }
+
+    /// Set factor for the new compressed image.
+    pub fn factor(mut self, factor: Factor) -> Self {
+        self.factor = factor;
+        self
+    }
+
+    /// Sets whether the program deletes the source file.
+    pub fn delete_source(mut self, to_delete: bool) -> Self {
+        self.delete_source = to_delete;
+        self
+    }
+
+    /// Sets whether the program replaces the file that already exists in the destination directory.
+    pub fn overwrite(mut self, overwrite: bool) -> Self {
+        self.overwrite = overwrite;
+        self
+    }
+
+    /// Build the `Compressor`.
+    ///
+    /// # Error
+    /// - When the source or the destination is not set.
+    pub fn build(self) -> Result<Compressor<O, D>, BuildError> {
+        let source_path = self.source_path.ok_or(BuildError::MissingSource)?;
+        let dest_path = self.dest_path.ok_or(BuildError::MissingDest)?;
+        let mut compressor = Compressor::new(source_path, dest_path);
+        compressor.set_factor(self.factor);
+        compressor.set_delete_source(self.delete_source);
+        compressor.set_overwrite(self.overwrite);
+        Ok(compressor)
+    }
+}
+
+impl<O: AsRef<Path>, D: AsRef<Path>> Compressor<O, D> {
+    /// Create a builder of a `Compressor`.
+    /// # Examples
+    /// ```
+    /// use image_compressor::compressor::Compressor;
+    /// use image_compressor::Factor;
+    ///
+    /// let compressor = Compressor::builder()
+    ///     .source("source/file1.png")
+    ///     .dest("dest")
+    ///     .factor(Factor::new(70., 0.5))
+    ///     .overwrite(true)
+    ///     .build()
+    ///     .unwrap();
+    /// ```
+    pub fn builder() -> CompressorBuilder<O, D> {
+        CompressorBuilder {
+            source_path: None,
+            dest_path: None,
+            factor: Factor::default(),
+            delete_source: false,
+            overwrite: false,
+        }
+    }
+
+    /// Create a new `Compressor` instance.
+    pub fn new(source_path: O, dest_dir_path: D) -> Self {
+        Compressor {
+            factor: Factor::default(),
+            cal_func: None,
+            source_path,
+            dest_path: dest_dir_path,
+            delete_source: false,
+            png_compression: CompressionType::Default,
+            resize_mode: ResizeMode::default(),
+            crop_aspect: None,
+            watermark: None,
+            min_dimensions: None,
+            filter_type: FilterType::Triangle,
+            filter_for_size: None,
+            auto_orient: true,
+            preserve_icc: true,
+            preserve_metadata: false,
+            subsampling: Subsampling::default(),
+            progressive: true,
+            grayscale: false,
+            smoothing: 0,
+            trellis: true,
+            quality_range: None,
+            min_quality: None,
+            dpi: None,
+            overwrite: false,
+            unreadable_policy: UnreadablePolicy::default(),
+            gif_mode: GifMode::default(),
+            dry_run: false,
+            preserve_timestamps: false,
+            temp_dir: std::env::temp_dir(),
+            output_stem: None,
+            output_name_template: DEFAULT_OUTPUT_NAME_TEMPLATE.to_string(),
+            output_extension: None,
+            keep_if_larger: false,
+            skip_if_smaller_than: None,
+            encoder: None,
+            source_data: None,
+            remote_source: false,
+        }
+    }
+
+    /// Set factor for the new compressed image.
+    pub fn set_factor(&mut self, factor: Factor) {
+        self.factor = factor;
+    }
+
+    /// Sets whether the program replaces the file that already exists in the destination directory.
+    /// The default is false, which returns an `AlreadyExists` error.
+    pub fn set_overwrite(&mut self, overwrite: bool) {
+        self.overwrite = overwrite;
+    }
+
+    /// Set what to do with the file that cannot be opened as an image.
+    /// The default is [`UnreadablePolicy::CopyThrough`], which copies the file to the destination directory.
+    pub fn set_unreadable_policy(&mut self, policy: UnreadablePolicy) {
+        self.unreadable_policy = policy;
+    }
+
+    /// Set how to compress an animated gif image. The default is [`GifMode::FirstFrame`].
+    ///
+    /// With [`GifMode::Animated`], [`compress_to_jpg`](Compressor::compress_to_jpg) saves a gif image
+    /// with more than one frame as an animated gif image instead of a jpg image.
+    /// Its file name is made from the output name template with the `gif` extension,
+    /// or with the output extension if it is one of gif images, such as `GIF`.
+    /// Every frame is resized with the size ratio of the `Factor` and gets the watermark if it is set.
+    /// A gif image with a single frame is still compressed to jpg format.
+    pub fn set_gif_mode(&mut self, gif_mode: GifMode) {
+        self.gif_mode = gif_mode;
+    }
+
+    /// Sets whether the program only compresses the image in memory, without writing or deleting any file.
+    #[cfg(feature = "folder")]
+    pub(crate) fn set_dry_run(&mut self, dry_run: bool) {
+        self.dry_run = dry_run;
+    }
+
+    /// Sets the data of the source file read in advance, which is decoded instead of reading the file.
+    #[cfg(feature = "folder")]
+    pub(crate) fn set_source_data(&mut self, data: Vec<u8>) {
+        self.source_data = Some(data);
+    }
+
+    /// Sets the file stem of the new image used instead of the file stem of the source.
+    #[cfg(feature = "folder")]
+    pub(crate) fn set_output_stem(&mut self, output_stem: String) {
+        self.output_stem = Some(output_stem);
+    }
+
+    /// Set a calculator function that returns a factor for the new compressed image.
+    ///
+    /// The function is called after the source image is opened,
+    /// with the width and the height of the image and the file size of the source in bytes.
+    /// If both a factor and a calculator function are set, the calculator function is used.
+    /// # Examples
+    /// ```
+    /// use image_compressor::compressor::Compressor;
+    /// use image_compressor::Factor;
+    ///
+    /// let mut compressor = Compressor::new("source.png", "dest");
+    /// compressor.set_cal_func(|width, height, _| {
+    ///     if width * height > 1920 * 1080 {
+    ///         Factor::new(60., 0.5)
+    ///     } else {
+    ///         Factor::new(80., 0.8)
+    ///     }
+    /// });
+    /// ```
+    pub fn set_cal_func<F>(&mut self, cal_func: F)
+    where
+        F: Fn(u32, u32, u64) -> Factor + Send + Sync + 'static,
+    {
+        self.cal_func = Some(Arc::new(cal_func));
+    }
+
+    /// Sets whether the program deletes the source file.
+    pub fn set_delete_source(&mut self, to_delete: bool) {
+        self.delete_source = to_delete;
+    }
+
+    /// Set how the size of the new compressed image is decided.
+    pub fn set_resize_mode(&mut self, resize_mode: ResizeMode) {
+        self.resize_mode = resize_mode;
+    }
+
+    /// Resize the image to fit within `max_width` and `max_height`, keeping its aspect ratio.
+    ///
+    /// This overrides the size ratio of the `Factor`.
+    /// Images smaller than the bounds keep their original size rather than being upscaled.
+    pub fn set_max_dimensions(&mut self, max_width: u32, max_height: u32) {
+        self.resize_mode = ResizeMode::MaxDimensions(max_width, max_height);
+    }
+
+    /// Keep the new image at least `min_width` wide and `min_height` high, whatever the size ratio of the `Factor` is.
+    ///
+    /// The size ratio is raised until both sides reach the minimum, keeping the aspect ratio,
+    /// but an image smaller than the minimum keeps its original size rather than being upscaled.
+    /// It prevents tiny source images from being shrunk too much.
+    /// It also applies with [`set_max_dimensions`](Compressor::set_max_dimensions),
+    /// where the minimum wins if the image cannot fit within the maximum without going below it.
+    pub fn set_min_dimensions(&mut self, min_width: u32, min_height: u32) {
+        self.min_dimensions = Some((min_width, min_height));
+    }
+
+    /// Center-crop the image to the aspect ratio of `width:height` before resizing it.
+    ///
+    /// The largest rectangle of the ratio that fits in the image is kept, e.g. `(1, 1)` for square tiles,
+    /// so the size of the new image is predictable. The crop is ignored if `width` or `height` is 0.
+    pub fn set_crop_aspect(&mut self, width: u32, height: u32) {
+        self.crop_aspect = Some((width, height));
+    }
+
+    /// Draw a watermark on the new image, e.g. for the previews of stock photos.
+    ///
+    /// The watermark image is loaded once here, and scaled to a quarter of the width of each resized image.
+    /// Its alpha channel is multiplied by `opacity` from 0 to 1, and it is blended onto the resized image before encoding.
+    /// A 16-bit image is converted to 8 bits per channel to draw the watermark.
+    ///
+    /// # Error
+    /// - When the watermark image cannot be opened.
+    pub fn set_watermark<P: AsRef<Path>>(
+        &mut self,
+        path: P,
+        position: WatermarkPosition,
+        opacity: f32,
+    ) -> Result<(), CompressError> {
+        self.watermark = Some(Watermark {
+            image: Arc::new(image::open(path)?.into_rgba8()),
+            position,
+            opacity: opacity.clamp(0., 1.),
+        });
+        Ok(())
+    }
+
+    /// Set the filter used to resize the image. The default is [`FilterType::Triangle`].
+    ///
+    /// e.g. [`FilterType::Lanczos3`] gives sharper photographs, and [`FilterType::Nearest`] keeps pixel art crisp.
+    pub fn set_filter_type(&mut self, filter_type: FilterType) {
+        self.filter_type = filter_type;
+    }
+
+    /// Choose the filter used to resize the image by its number of pixels, instead of the filter type.
+    ///
+    /// Images with fewer than `threshold_px` pixels, such as avatars, are resized with the fast `small_filter`,
+    /// and the others with the quality `large_filter`.
+    /// # Examples
+    /// ```
+    /// use image_compressor::compressor::Compressor;
+    /// use image_compressor::FilterType;
+    ///
+    /// let mut compressor = Compressor::new("source.png", "dest");
+    /// compressor.set_filter_for_size(FilterType::Nearest, FilterType::Lanczos3, 256 * 256);
+    /// ```
+    pub fn set_filter_for_size(
+        &mut self,
+        small_filter: FilterType,
+        large_filter: FilterType,
+        threshold_px: u64,
+    ) {
+        self.filter_for_size = Some((small_filter, large_filter, threshold_px));
+    }
+
+    /// Set whether to rotate and flip the image by its EXIF orientation tag. The default is true.
+    ///
+    /// Since the tag is not written to the new compressed image,
+    /// the pixels themselves are turned upright before resizing.
+    pub fn set_auto_orient(&mut self, auto_orient: bool) {
+        self.auto_orient = auto_orient;
+    }
+
+    /// Set whether to write the ICC color profile of the source image into the new jpg image. The default is true.
+    ///
+    /// Without the profile, colors of wide-gamut images may shift.
+    /// Set it false to get the smallest files.
+    pub fn set_preserve_icc(&mut self, preserve_icc: bool) {
+        self.preserve_icc = preserve_icc;
+    }
+
+    /// Set whether to write the EXIF metadata of the source image, such as the camera model,
+    /// the GPS position, and the date taken, into the new jpg image. The default is false.
+    ///
+    /// The EXIF data is read from jpg sources and the other formats that can contain it, such as png, webp, and tiff.
+    /// When the image is turned upright by [`set_auto_orient`](Compressor::set_auto_orient),
+    /// the orientation tag is reset so that viewers do not rotate it again.
+    /// Keep it false to strip the private information.
+    /// It is the opposite of [`set_strip_all_metadata`](Compressor::set_strip_all_metadata).
+    pub fn set_preserve_metadata(&mut self, preserve_metadata: bool) {
+        self.preserve_metadata = preserve_metadata;
+    }
+
+    /// Set whether to guarantee that no EXIF, XMP, or IPTC metadata is written into the new jpg image.
+    /// The default is true.
+    ///
+    /// When true, no APP1 or APP13 marker is written, so the GPS position and the camera information never survive.
+    /// The result can be verified with [`contains_metadata`](crate::metadata::contains_metadata).
+    /// It is the opposite of [`set_preserve_metadata`](Compressor::set_preserve_metadata).
+    pub fn set_strip_all_metadata(&mut self, strip_all_metadata: bool) {
+        self.preserve_metadata = !strip_all_metadata;
+    }
+
+    /// Set the chroma subsampling of the jpg image. The default is [`Subsampling::Sub420`].
+    ///
+    /// [`Subsampling::Sub444`] dramatically improves the legibility of text-heavy images at the same quality.
+    pub fn set_subsampling(&mut self, subsampling: Subsampling) {
+        self.subsampling = subsampling;
+    }
+
+    /// Set whether to make a progressive jpg image. The default is true.
+    ///
+    /// Progressive images are usually smaller and are shown gradually while loading,
+    /// but some old decoders and downstream tools only handle baseline images.
+    /// When false, a baseline image is made without the scan optimization.
+    pub fn set_progressive(&mut self, progressive: bool) {
+        self.progressive = progressive;
+    }
+
+    /// Set whether to make a grayscale jpg image. The default is false.
+    ///
+    /// It saves space for images that are effectively black-and-white, such as scanned documents.
+    pub fn set_grayscale(&mut self, grayscale: bool) {
+        self.grayscale = grayscale;
+    }
+
+    /// Set the smoothing factor of mozjpeg from 0 to 100 for the jpg image. The default is 0, which means no smoothing.
+    ///
+    /// Smoothing reduces the noise and the artifacts of low quality images, but also blurs fine detail.
+    /// Values above 100 are treated as 100.
+    pub fn set_smoothing(&mut self, smoothing: u8) {
+        self.smoothing = smoothing.min(100);
+    }
+
+    /// Set whether to use the trellis quantization of mozjpeg for the jpg image. The default is true.
+    ///
+    /// Trellis quantization makes files smaller at the same quality, but may smear fine detail at low quality.
+    /// When false, only the trellis quantization is turned off,
+    /// and the optimized Huffman tables and quantization tables of mozjpeg are still used.
+    pub fn set_trellis(&mut self, trellis: bool) {
+        self.trellis = trellis;
+    }
+
+    /// Set the range of the jpg quality to search for each image, instead of using the quality of the `Factor`.
+    ///
+    /// The image is compressed with `max` first, then the lowest quality between `min` and `max`
+    /// whose SSIM against the resized image is at most 0.005 lower than the SSIM at `max` is binary searched in memory.
+    /// The smallest of the results that pass is chosen, so forgiving images get a lower quality than detailed ones.
+    /// If it is larger than the image compressed with the midpoint of the range, the midpoint image is chosen instead,
+    /// so the file is never larger than with the fixed midpoint quality.
+    /// The search takes up to 10 compressions per image, with the other jpg options of this instance.
+    /// The values are swapped if `min` is greater than `max`.
+    /// The default is no range, which uses the quality of the `Factor`.
+    ///
+    /// # Error
+    /// - When a value is not greater than 0 and at most 100, the range is not changed.
+    pub fn set_quality_range(&mut self, min: f32, max: f32) -> Result<(), FactorError> {
+        for quality in [min, max] {
+            if !(quality > 0. && quality <= 100.) {
+                return Err(FactorError::Quality(quality));
+            }
+        }
+        self.quality_range = Some((min.min(max), min.max(max)));
+        Ok(())
+    }
+
+    /// Set the lowest quality that the image is compressed with.
+    ///
+    /// The quality of the `Factor`, or the one returned by the calculator function, is raised to `min_quality` if it is lower,
+    /// so that no image is compressed with unacceptable artifacts.
+    /// It is also the lowest quality searched with [`set_quality_range`](Compressor::set_quality_range).
+    /// The value is clamped to 0 to 100. The default is no minimum.
+    ///
+    /// It is a jpg quality, unlike the SSIM of [`compress_to_quality_floor`](Compressor::compress_to_quality_floor).
+    pub fn set_min_quality(&mut self, min_quality: f32) {
+        self.min_quality = Some(min_quality.clamp(0., 100.));
+    }
+
+    /// Set the density of the jpg image in dots per inch, written to its JFIF header.
+    ///
+    /// The default keeps the density of the source image if it has one, read from its JFIF header,
+    /// png pHYs chunk, or EXIF resolution, and is 72 DPI otherwise.
+    /// It matters for the images to be printed, e.g. 300 DPI.
+    pub fn set_dpi(&mut self, x: u16, y: u16) {
+        self.dpi = Some((x, y));
+    }
+
+    /// Set whether the new image keeps the modified and accessed times of the source file. The default is false.
+    ///
+    /// It is useful to keep the compressed photos sorted chronologically.
+    pub fn set_preserve_timestamps(&mut self, preserve_timestamps: bool) {
+        self.preserve_timestamps = preserve_timestamps;
+    }
+
+    /// Set the directory where the new image is written before it is renamed to the destination.
+    /// The default is [`std::env::temp_dir`].
+    ///
+    /// A fast local directory speeds up the writes to a slow mount.
+    /// If the directory is on another filesystem than the destination, the file cannot be renamed from it,
+    /// so it is written again to a hidden file in the destination directory and renamed from there.
+    /// Note that the default is often a tmpfs, so every image is written twice by default;
+    /// set a directory on the filesystem of the destination to write it once.
+    pub fn set_temp_dir(&mut self, temp_dir: PathBuf) {
+        self.temp_dir = temp_dir;
+    }
+
+    /// Set the template of the file name of the new jpg image. The default is `{stem}.jpg`.
+    ///
+    /// The placeholders below are replaced with the values of the new image.
+    /// - `{stem}`: the file stem of the source image.
+    /// - `{width}`, `{height}`: the size of the new image in pixels.
+    /// - `{quality}`: the jpg quality of the new image.
+    ///
+    /// It avoids replacing the source images when the source and the destination directory are the same.
+    /// # Examples
+    /// ```
+    /// use image_compressor::compressor::Compressor;
+    ///
+    /// let mut compressor = Compressor::new("source.png", "dest");
+    /// compressor.set_output_name_template("{stem}_{width}x{height}.jpg".to_string());
+    /// ```
+    pub fn set_output_name_template(&mut self, template: String) {
+        self.output_name_template = template;
+    }
+
+    /// Set the extension of the new image file, without the leading `.`, such as `JPG` or `jpeg`.
+    /// The default is `None`, which uses the lowercase extension of the encoder, such as `jpg`.
+    ///
+    /// It replaces the extension of the file name made from the output name template.
+    /// The compression returns an `UnsupportedExtension` error
+    /// if the extension is not one that the encoder produces, e.g. `png` for a jpg image.
+    /// # Examples
+    /// ```
+    /// use image_compressor::compressor::Compressor;
+    ///
+    /// let mut compressor = Compressor::new("source.png", "dest");
+    /// compressor.set_output_extension("JPG".to_string());
+    /// ```
+    pub fn set_output_extension(&mut self, extension: String) {
+        self.output_extension = Some(extension);
+    }
+
+    /// Set whether to copy the source file as it is when the compressed jpg image is larger. The default is false.
+    ///
+    /// The jpg image is compressed in memory first and compared with the file size of the source.
+    /// If it is larger, e.g. for a tiny png icon, the source file is copied to the destination directory
+    /// with its own extension, and [`CompressionReport::kept_original`] is true.
+    /// So the compression never makes a file bigger.
+    pub fn set_keep_if_larger(&mut self, keep_if_larger: bool) {
+        self.keep_if_larger = keep_if_larger;
+    }
+
+    /// Set the file size in bytes under which a jpg source file is copied as it is without the compression.
+    /// The default is no threshold.
+    ///
+    /// A small jpg image, such as a thumbnail, is usually optimized already,
+    /// so compressing it again only adds the generation loss.
+    /// The source file is copied to the destination directory with its own extension,
+    /// and [`CompressionReport::skipped`] is true.
+    /// The source files of the other formats are compressed regardless of their sizes.
+    pub fn set_skip_if_smaller_than(&mut self, bytes: u64) {
+        self.skip_if_smaller_than = Some(bytes);
+    }
+
+    /// Set compression level used when the image is saved in png format.
+    pub fn set_png_compression(&mut self, compression: CompressionType) {
+        self.png_compression = compression;
+    }
+
+    /// Set the encoder used by [`compress_with_encoder`](Compressor::compress_with_encoder).
+    ///
+    /// Any [`OutputEncoder`] can be set, including the built-in ones in the [`encoder`](crate::encoder) module,
+    /// so the image can be saved in a format that this crate does not support.
+    pub fn set_encoder(&mut self, encoder: Box<dyn OutputEncoder>) {
+        self.encoder = Some(encoder);
+    }
+
+    /// Compress the image to jpg format with the jpg options of this instance.
+    ///
+    /// The EXIF data and the ICC profile are written if the flags to preserve them are true.
+    fn compress(
+        &self,
+        pixels: ResizedPixels,
+        target_width: usize,
+        target_height: usize,
+        quality: f32,
+        metadata: &ImageMetadata,
+    ) -> Result<Vec<u8>, CompressError> {
+        let mut encoder = JpegEncoder::new();
+        encoder.set_subsampling(self.subsampling);
+        encoder.set_progressive(self.progressive);
+        encoder.set_grayscale(self.grayscale);
+        encoder.set_smoothing(self.smoothing);
+        encoder.set_trellis(self.trellis);
+        let (x_density, y_density) = self.dpi.or(metadata.density).unwrap_or(DEFAULT_DPI);
+        encoder.set_density(x_density, y_density);
+        encoder.encode_with_metadata(
+            pixels,
+            target_width,
+            target_height,
+            quality,
+            metadata.exif.as_deref().filter(|_| self.preserve_metadata),
+            metadata
+                .icc_profile
+                .as_deref()
+                .filter(|_| self.preserve_icc),
+        )
+    }
+
+    /// Compress the image to jpg format with the quality picked from the quality range if it is set,
+    /// otherwise with `quality`. Returns the used quality and the compressed image.
+    fn compress_in_quality_range(
+        &self,
+        pixels: ResizedPixels,
+        width: usize,
+        height: usize,
+        quality: f32,
+        metadata: &ImageMetadata,
+    ) -> Result<(f32, Vec<u8>), CompressError> {
+        let Some((min, max)) = self.quality_range else {
+            return Ok((
+                quality,
+                self.compress(pixels, width, height, quality, metadata)?,
+            ));
+        };
+        let reference = luma_of(&pixels, width, height)?;
+        let ssim_at = |compressed: &[u8]| -> Result<f64, CompressError> {
+            let decoded =
+                image::load_from_memory_with_format(compressed, ImageFormat::Jpeg)?.into_luma8();
+            Ok(ssim(&reference, &decoded))
+        };
+
+        let mut best = (
+            max,
+            self.compress(pixels.clone(), width, height, max, metadata)?,
+        );
+        let min_ssim = ssim_at(&best.1)? - QUALITY_RANGE_SSIM_TOLERANCE;
+        // The minimum quality raises the lower bound of the search, but never above `max`.
+        let min = min.max(self.min_quality.unwrap_or(0.)).min(max);
+        let midpoint = (min + max) / 2.;
+        let at_midpoint = (
+            midpoint,
+            self.compress(pixels.clone(), width, height, midpoint, metadata)?,
+        );
+        let (mut low, mut high) = (min.round().max(1.) as u32, max.round() as u32);
+        for _ in 0..MAX_SEARCH_ATTEMPTS {
+            if low >= high {
+                break;
+            }
+            let quality = (low + high) / 2;
+            let compressed =
+                self.compress(pixels.clone(), width, height, quality as f32, metadata)?;
+            if ssim_at(&compressed)? >= min_ssim {
+                if compressed.len() <= best.1.len() {
+                    best = (quality as f32, compressed);
+                }
+                high = quality;
+            } else {
+                low = quality + 1;
+            }
+        }
+        // A detailed image may need a quality above the midpoint to pass,
+        // but the file is never larger than with the midpoint quality.
+        if best.1.len() > at_midpoint.1.len() {
+            best = at_midpoint;
+        }
+        Ok(best)
+    }
+
+    /// Resize the image vector.
+    ///
+    /// The image is center-cropped to the aspect ratio first if it is set by [`set_crop_aspect`](Compressor::set_crop_aspect),
+    /// then scaled by `resize_ratio` or fitted within the maximum dimensions, depending on the resize mode.
+    /// The pixels are returned as RGBA if the source image has an alpha channel, otherwise as RGB.
+    /// The new image is at least 1 pixel wide and high, even with a tiny ratio.
+    ///
+    /// # Error
+    /// - When the source image has no pixels.
+    fn resize(
+        &self,
+        img: image::DynamicImage,
+        resize_ratio: f32,
+    ) -> Result<(ResizedPixels, usize, usize), CompressError> {
+        self.resize_with_depth(img, resize_ratio, false)
+    }
+
+    /// Resize the image vector like [`resize`](Compressor::resize).
+    ///
+    /// If `keep_16bit` is true, a 16-bit image is returned as 16-bit pixels.
+    /// Otherwise the precision of a 16-bit image is dropped to 8 bits with a warning in the log.
+    fn resize_with_depth(
+        &self,
+        img: image::DynamicImage,
+        resize_ratio: f32,
+        keep_16bit: bool,
+    ) -> Result<(ResizedPixels, usize, usize), CompressError> {
+        let resized_img = self.resize_image(img, resize_ratio)?;
+
+        let resized_width = resized_img.width() as usize;
+        let resized_height = resized_img.height() as usize;
+        if !keep_16bit && is_16bit(&resized_img) {
+            warn!(
+                "The 16-bit image is converted to 8 bits per channel: {}",
+                self.source_path.as_ref().display()
+            );
+        }
+
+        Ok((
+            ResizedPixels::from_image(resized_img, keep_16bit),
+            resized_width,
+            resized_height,
+        ))
+    }
+
+    /// Crop, scale and watermark the image like [`resize`](Compressor::resize),
+    /// keeping the pixels as they are in the image.
+    ///
+    /// Every frame of an animated image is resized by this, so that all frames get the same size and watermark.
+    fn resize_image(
+        &self,
+        img: image::DynamicImage,
+        resize_ratio: f32,
+    ) -> Result<image::DynamicImage, CompressError> {
+        if img.width() == 0 || img.height() == 0 {
+            return Err(CompressError::Encode(format!(
+                "Cannot compress an image with no pixels: {}x{}",
+                img.width(),
+                img.height()
+            )));
+        }
+        let img = self.crop_to_aspect(img);
+        let filter_type = self.filter_for(&img);
+        let min_ratio = self.min_ratio(&img);
+        let resize_to_min = |img: image::DynamicImage| {
+            if min_ratio >= 1. {
+                img
+            } else {
+                // Round instead of truncating, so that a side is not a pixel below the minimum.
+                let width = (img.width() as f32 * min_ratio).round().max(1.);
+                let height = (img.height() as f32 * min_ratio).round().max(1.);
+                img.resize_exact(width as u32, height as u32, filter_type)
+            }
+        };
+        let resized_img = match self.resize_mode {
+            ResizeMode::Ratio if min_ratio > resize_ratio => resize_to_min(img),
+            ResizeMode::Ratio => resize_image(&img, resize_ratio, filter_type),
+            ResizeMode::MaxDimensions(max_width, max_height) => {
+                let fit_ratio = (max_width.max(1) as f32 / img.width() as f32)
+                    .min(max_height.max(1) as f32 / img.height() as f32);
+                if fit_ratio >= 1. {
+                    img
+                } else if min_ratio > fit_ratio {
+                    resize_to_min(img)
+                } else {
+                    img.resize(max_width.max(1), max_height.max(1), filter_type)
+                }
+            }
+        };
+
+        Ok(self.draw_watermark(resized_img))
+    }
+
+    /// Blend the watermark onto the resized image, if it is set.
+    fn draw_watermark(&self, img: image::DynamicImage) -> image::DynamicImage {
+        let Some(watermark) = &self.watermark else {
+            return img;
+        };
+        let has_alpha = img.color().has_alpha();
+        let mut canvas = img.into_rgba8();
+        let (width, height) = canvas.dimensions();
+
+        // The height is computed in u64, since a tall watermark on a wide image overflows u32.
+        let mark_width = (width / 4).max(1);
+        let mark_height = (watermark.image.height() as u64 * mark_width as u64
+            / watermark.image.width().max(1) as u64)
+            .clamp(1, height as u64) as u32;
+        let mut mark = image::imageops::resize(
+            watermark.image.as_ref(),
+            mark_width,
+            mark_height,
+            FilterType::Triangle,
+        );
+        for pixel in mark.pixels_mut() {
+            pixel[3] = (pixel[3] as f32 * watermark.opacity).round() as u8;
+        }
+        let (mark_width, mark_height) = (mark.width() as i64, mark.height() as i64);
+        let (width, height) = (width as i64, height as i64);
+        match watermark.position {
+            WatermarkPosition::BottomRight => {
+                let margin = width / 50;
+                image::imageops::overlay(
+                    &mut canvas,
+                    &mark,
+                    width - mark_width - margin,
+                    height - mark_height - margin,
+                );
+            }
+            WatermarkPosition::Center => {
+                image::imageops::overlay(
+                    &mut canvas,
+                    &mark,
+                    (width - mark_width) / 2,
+                    (height - mark_height) / 2,
+                );
+            }
+            WatermarkPosition::Tiled => {
+                // Leave a gap of half the watermark between the tiles.
+                let (step_x, step_y) = (mark_width * 3 / 2, mark_height * 3 / 2);
+                for y in (0..height).step_by(step_y.max(1) as usize) {
+                    for x in (0..width).step_by(step_x.max(1) as usize) {
+                        image::imageops::overlay(&mut canvas, &mark, x, y);
+                    }
+                }
+            }
+        }
+
+        if has_alpha {
+            image::DynamicImage::ImageRgba8(canvas)
+        } else {
+            image::DynamicImage::ImageRgb8(image::DynamicImage::ImageRgba8(canvas).into_rgb8())
+        }
+    }
+
+    /// The lowest size ratio that keeps the image within the minimum dimensions, at most 1.
+    ///
+    /// Returns 0 if the minimum dimensions are not set.
+    fn min_ratio(&self, img: &image::DynamicImage) -> f32 {
+        match self.min_dimensions {
+            Some((min_width, min_height)) => (min_width as f32 / img.width() as f32)
+                .max(min_height as f32 / img.height() as f32)
+                .min(1.),
+            None => 0.,
+        }
+    }
+
+    /// The filter to resize the image, chosen by its number of pixels if the filters for the size are set.
+    fn filter_for(&self, img: &image::DynamicImage) -> FilterType {
+        match self.filter_for_size {
+            Some((small_filter, large_filter, threshold_px)) => {
+                if (img.width() as u64) * (img.height() as u64) < threshold_px {
+                    small_filter
+                } else {
+                    large_filter
+                }
+            }
+            None => self.filter_type,
+        }
+    }
+
+    /// Center-crop the image to the aspect ratio set by [`set_crop_aspect`](Compressor::set_crop_aspect).
+    /// The image is returned as it is if the aspect ratio is not set.
+    fn crop_to_aspect(&self, img: image::DynamicImage) -> image::DynamicImage {
+        let Some((aspect_width, aspect_height)) = self.crop_aspect else {
+            return img;
+        };
+        if aspect_width == 0 || aspect_height == 0 {
+            return img;
+        }
+        let (width, height) = (img.width() as u64, img.height() as u64);
+        let (aspect_width, aspect_height) = (aspect_width as u64, aspect_height as u64);
+        let (crop_width, crop_height) = if width * aspect_height > height * aspect_width {
+            ((height * aspect_width / aspect_height).max(1), height)
+        } else {
+            (width, (width * aspect_height / aspect_width).max(1))
+        };
+        let x = (width - crop_width) / 2;
+        let y = (height - crop_height) / 2;
+        img.crop_imm(x as u32, y as u32, crop_width as u32, crop_height as u32)
+    }
+
+    /// Make a thumbnail whose longer side is `thumb_max`, keeping the aspect ratio.
+    ///
+    /// If `crop_square` is true, the center square of the image is cropped first.
+    fn thumbnail(
+        &self,
+        img: &image::DynamicImage,
+        thumb_max: u32,
+        crop_square: bool,
+    ) -> image::DynamicImage {
+        if crop_square {
+            let side = img.width().min(img.height());
+            let x = (img.width() - side) / 2;
+            let y = (img.height() - side) / 2;
+            img.crop_imm(x, y, side, side)
+                .resize_exact(thumb_max, thumb_max, self.filter_for(img))
+        } else {
+            img.resize(thumb_max, thumb_max, self.filter_for(img))
+        }
+    }
+
+    /// Get the factor to compress the given image with.
+    /// The calculator function is used if it is set, otherwise the factor is returned as it is.
+    /// The quality is raised to the minimum quality if it is set.
+    fn effective_factor(&self, img: &image::DynamicImage, file_size: u64) -> Factor {
+        let factor = match &self.cal_func {
+            Some(cal_func) => cal_func(img.width(), img.height(), file_size),
+            None => self.factor,
+        };
+        match self.min_quality {
+            Some(min_quality) if factor.quality < min_quality => Factor {
+                quality: min_quality,
+                ..factor
+            },
+            _ => factor,
+        }
+    }
+
+    /// Get the file size of the source file in bytes.
+    fn source_file_size(&self) -> u64 {
+        match &self.source_data {
+            Some(data) => data.len() as u64,
+            None => fs::metadata(&self.source_path)
+                .map(|m| m.len())
+                .unwrap_or(0),
+        }
+    }
+
+    /// Guess the actual image format by the content, regardless of the extension.
+    ///
+    /// Returns `None` if the content is not recognized as an image.
+    fn guess_image_format<R: BufRead + Seek>(&self, reader: R) -> Option<ImageFormat> {
+        image::io::Reader::new(reader)
+            .with_guessed_format()
+            .ok()
+            .and_then(|reader| reader.format())
+    }
+
+    /// Compress the image data in memory to jpg format.
+    ///
+    /// Decode `data` as an image of the given `format`, then resize and compress it with the same `Factor`
+    /// that [`compress_to_jpg`](Compressor::compress_to_jpg) uses.
+    /// Nothing is read from or written to the filesystem; the bytes of the compressed jpg image are returned.
+    pub fn compress_from_memory(
+        &self,
+        data: &[u8],
+        format: ImageFormat,
+    ) -> Result<Vec<u8>, CompressError> {
+        let SourceImage {
+            image: image_vec,
+            metadata,
+            ..
+        } = self.decode_image(io::Cursor::new(data), format)?;
+        self.compress_decoded(image_vec, &metadata, data.len() as u64)
+    }
+
+    /// Compress the decoded image in memory to jpg format.
+    ///
+    /// Works like [`compress_from_memory`](Compressor::compress_from_memory), but the image is not decoded,
+    /// so an image generated in memory can be compressed without writing it to a file first.
+    /// The calculator function gets the size of the raw pixel data as the file size.
+    /// # Examples
+    /// ```
+    /// use image::{DynamicImage, Rgb, RgbImage};
+    /// use image_compressor::compressor::Compressor;
+    ///
+    /// let img = DynamicImage::ImageRgb8(RgbImage::from_pixel(64, 64, Rgb([255, 0, 0])));
+    /// let compressor = Compressor::new("unused", "unused");
+    /// let jpg = compressor.compress_image(&img).unwrap();
+    /// ```
+    pub fn compress_image(&self, img: &image::DynamicImage) -> Result<Vec<u8>, CompressError> {
+        self.compress_decoded(
+            img.clone(),
+            &ImageMetadata::default(),
+            img.as_bytes().len() as u64,
+        )
+    }
+
+    /// Resize and compress the decoded image to jpg format with the factor for the given file size.
+    fn compress_decoded(
+        &self,
+        img: image::DynamicImage,
+        metadata: &ImageMetadata,
+        file_size: u64,
+    ) -> Result<Vec<u8>, CompressError> {
+        let factor = self.effective_factor(&img, file_size);
+        let (resized_img_data, target_width, target_height) =
+            self.resize(img, factor.size_ratio())?;
+        let (_, compressed) = self.compress_in_quality_range(
+            resized_img_data,
+            target_width,
+            target_height,
+            factor.quality(),
+            metadata,
+        )?;
+        Ok(compressed)
+    }
+
+    /// The file stem of the new image.
+    fn file_stem(&self) -> &OsStr {
+        match &self.output_stem {
+            Some(stem) => stem.as_ref(),
+            None => self.source_path.as_ref().file_stem().unwrap(),
+        }
+    }
+
+    /// Build the path of the new image in the destination directory with the given extension.
+    ///
+    /// # Error
+    /// - When a file with the same name already exists in the destination directory and the overwrite flag is false.
+    fn target_file_path(&self, extension: &str) -> Result<PathBuf, CompressError> {
+        let mut target_file_name = PathBuf::from(self.file_stem());
+        target_file_name.set_extension(extension);
+        self.checked_target_file(target_file_name)
+    }
+
+    /// The extension of the new image made by the encoder, which is the output extension if it is set.
+    ///
+    /// # Error
+    /// - When the output extension is not one that the encoder produces.
+    fn output_extension(&self, encoder: &dyn OutputEncoder) -> Result<String, CompressError> {
+        match &self.output_extension {
+            Some(extension) if !encoder.produces_extension(extension) => {
+                Err(CompressError::UnsupportedExtension(extension.clone()))
+            }
+            Some(extension) => Ok(extension.clone()),
+            None => Ok(encoder.extension().to_string()),
+        }
+    }
+
+    /// The extension of the new jpg image, which is the output extension if it is set.
+    ///
+    /// # Error
+    /// - When the output extension is not one of jpg images.
+    fn jpg_extension(&self) -> Result<String, CompressError> {
+        self.output_extension(&JpegEncoder::new())
+    }
+
+    /// Build the file name of the new jpg image from the output name template.
+    ///
+    /// The size and the quality are given as `(width, height, quality)` after the image is compressed.
+    /// Returns `None` if the template needs them but they are not given yet.
+    /// The extension of the name is replaced with the output extension if it is set.
+    fn templated_file_name(&self, image_info: Option<(usize, usize, f32)>) -> Option<OsString> {
+        let mut file_name = self.output_name_template.clone();
+        match image_info {
+            Some((width, height, quality)) => {
+                file_name = file_name
+                    .replace("{width}", &width.to_string())
+                    .replace("{height}", &height.to_string())
+                    .replace("{quality}", &format!("{:.0}", quality));
+            }
+            None => {
+                if ["{width}", "{height}", "{quality}"]
+                    .iter()
+                    .any(|p| file_name.contains(p))
+                {
+                    return None;
+                }
+            }
+        }
+        // The stem is put in last, so that a file name that is not valid UTF-8 is kept as it is.
+        let mut templated = OsString::new();
+        for (i, part) in file_name.split("{stem}").enumerate() {
+            if i > 0 {
+                templated.push(self.file_stem());
+            }
+            templated.push(part);
+        }
+        if let Some(extension) = &self.output_extension {
+            templated = Path::new(&templated)
+                .with_extension(extension)
+                .into_os_string();
+        }
+        Some(templated)
+    }
+
+    /// Build the path of the new image in the destination directory with the given file name.
+    ///
+    /// # Error
+    /// - When a file with the same name already exists in the destination directory and the overwrite flag is false.
+    fn checked_target_file<P: AsRef<Path>>(&self, file_name: P) -> Result<PathBuf, CompressError> {
+        let target_file = self.dest_path.as_ref().join(file_name);
+        if !self.overwrite && target_file.is_file() {
+            return Err(CompressError::AlreadyExists(target_file));
+        }
+        Ok(target_file)
+    }
+
+    /// Decode the image data of the given format with its metadata.
+    ///
+    /// If the auto orient flag is true, the image is rotated and flipped by its EXIF orientation.
+    fn decode_image<R: BufRead + Seek>(
+        &self,
+        mut reader: R,
+        format: ImageFormat,
+    ) -> Result<SourceImage, ImageError> {
+        let start = Instant::now();
+        let mut decoder = image::io::Reader::with_format(&mut reader, format).into_decoder()?;
+        let mut metadata = ImageMetadata {
+            icc_profile: decoder.icc_profile().ok().flatten(),
+            exif: None,
+            density: None,
+        };
+        let mut image = image::DynamicImage::from_decoder(decoder)?;
+        if self.auto_orient || self.preserve_metadata || self.dpi.is_none() {
+            reader.seek(SeekFrom::Start(0))?;
+            let exif = read_exif(&mut reader);
+            if self.dpi.is_none() {
+                metadata.density = read_density(&mut reader, exif.as_ref());
+            }
+            let orientation = read_orientation(exif.as_ref());
+            if self.auto_orient {
+                image = apply_orientation(image, orientation);
+            }
+            if self.preserve_metadata {
+                metadata.exif = exif.map(|exif| {
+                    let mut tiff = exif.buf().to_vec();
+                    if self.auto_orient && orientation != 1 {
+                        reset_orientation(&mut tiff);
+                    }
+                    tiff
+                });
+            }
+        }
+        Ok(SourceImage {
+            image,
+            metadata,
+            decode_time: start.elapsed(),
+        })
+    }
+
+    /// Decode the source file as an image, from the data read in advance if it is set.
+    fn decode_source(&self) -> Result<SourceImage, CompressError> {
+        let source_file_path = self.source_path.as_ref();
+        let guessed_format = match &self.source_data {
+            Some(data) => self.guess_image_format(io::Cursor::new(data.as_slice())),
+            None => File::open(source_file_path)
+                .ok()
+                .and_then(|file| self.guess_image_format(BufReader::new(file))),
+        };
+        let Some(guessed_format) = guessed_format else {
+            return Err(CompressError::UnsupportedInput {
+                path: source_file_path.to_path_buf(),
+                format: None,
+                reason: "The content is not recognized as an image".to_string(),
+            });
+        };
+        if !guessed_format.reading_enabled() {
+            return Err(CompressError::UnsupportedInput {
+                path: source_file_path.to_path_buf(),
+                format: Some(guessed_format),
+                reason: "The image module is built without the decoder of the format".to_string(),
+            });
+        }
+        let decoded = match &self.source_data {
+            Some(data) => self.decode_image(io::Cursor::new(data.as_slice()), guessed_format),
+            None => self.decode_image(
+                BufReader::new(File::open(source_file_path)?),
+                guessed_format,
+            ),
+        };
+        decoded.map_err(|e| match e {
+            // e.g. a TIFF image with a compression method that the decoder does not implement.
+            ImageError::Unsupported(e) => CompressError::UnsupportedInput {
+                path: source_file_path.to_path_buf(),
+                format: Some(guessed_format),
+                reason: e.to_string(),
+            },
+            e => CompressError::Decode(e),
+        })
+    }
+
+    /// Open the source file as an image, from the data read in advance if it is set.
+    ///
+    /// If the image module can not open the file, handle it with the [`UnreadablePolicy`].
+    fn open_image(&self) -> Result<SourceImage, CompressError> {
+        let source_file_path = self.source_path.as_ref();
+        let target_dir = self.dest_path.as_ref();
+
+        let file_name = source_file_path.file_name().unwrap_or_default();
+
+        let error = match self.decode_source() {
+            Ok(p) => return Ok(p),
+            Err(CompressError::Io(e)) => return Err(CompressError::Io(e)),
+            Err(e) => e,
+        };
+
+        match self.unreadable_policy {
+            UnreadablePolicy::CopyThrough => {
+                let copied_file = target_dir.join(file_name);
+                match self.source_file() {
+                    // Do not copy the file onto itself when the destination is the source directory.
+                    Some(source_file) => {
+                        if !is_same_file(source_file, &copied_file) {
+                            fs::copy(source_file, copied_file)?;
+                        }
+                    }
+                    None => fs::write(copied_file, self.source_bytes()?)?,
+                }
+                Err(error)
+            }
+            UnreadablePolicy::Skip => Err(CompressError::Skipped(source_file_path.to_path_buf())),
+            UnreadablePolicy::Fail => Err(error),
+        }
+    }
+
+    /// Write the compressed image data to the target file.
+    ///
+    /// If the flag to delete the source is true, the function delete the source file.
+    /// Nothing is written or deleted in a dry run.
+    ///
+    /// When the target file is the source file itself, e.g. `photo.jpg` compressed in its own directory,
+    /// the source is replaced only after the write succeeds and is not deleted.
+    /// So the only copy of the image is never lost.
+    fn write_target_file(&self, target_file: &Path, data: &[u8]) -> Result<(), CompressError> {
+        if self.dry_run {
+            return Ok(());
+        }
+        let source_file = self.source_file();
+        let replaces_source = source_file.is_some_and(|source| is_same_file(source, target_file));
+        self.write_file(target_file, data)?;
+
+        // Delete the source file when the flag is true.
+        if let Some(source_file) = source_file.filter(|_| self.delete_source && !replaces_source) {
+            fs::remove_file(source_file)?;
+        }
+        Ok(())
+    }
+
+    /// The path of the source file, or `None` if the source is not a local file,
+    /// e.g. an image downloaded by [`from_url`](Compressor::from_url).
+    ///
+    /// A remote source is never deleted, copied, or read for its timestamps,
+    /// since its path names no file of it and may name an unrelated local file.
+    fn source_file(&self) -> Option<&Path> {
+        (!self.remote_source).then(|| self.source_path.as_ref())
+    }
+
+    /// Write the data to the file, keeping the timestamps of the source file if the flag is true.
+    ///
+    /// The data is written to a temporary file in the temporary directory first,
+    /// which is renamed to the file only after the whole data is written.
+    /// So the readers of the directory never see a partially written image.
+    fn write_file(&self, target_file: &Path, data: &[u8]) -> Result<(), CompressError> {
+        let temp_file = self.write_temp_file(&self.temp_dir, target_file, data)?;
+        let e = match fs::rename(&temp_file, target_file) {
+            Ok(_) => return Ok(()),
+            Err(e) => e,
+        };
+        let _ = fs::remove_file(&temp_file);
+        if !is_cross_device(&e) {
+            return Err(e.into());
+        }
+
+        // The temporary directory is on another filesystem, which the file cannot be renamed across.
+        // So the data is written again next to the file and renamed from there.
+        let target_dir = target_file.parent().unwrap_or(Path::new(""));
+        let staging_file = self.write_temp_file(target_dir, target_file, data)?;
+        if let Err(e) = fs::rename(&staging_file, target_file) {
+            let _ = fs::remove_file(&staging_file);
+            return Err(e.into());
+        }
+        Ok(())
+    }
+
+    /// Write the data to a new temporary file for the target file in the directory
+    /// like [`fill_temp_file`](Compressor::fill_temp_file), and return the path of the temporary file.
+    ///
+    /// The file is only created if nothing exists at its path, so that a file or a symlink
+    /// put at the path by another user of a shared directory is never written through.
+    /// Another name is tried if the path is already taken.
+    /// The partially written file is removed if the write fails.
+    fn write_temp_file(
+        &self,
+        dir: &Path,
+        target_file: &Path,
+        data: &[u8],
+    ) -> Result<PathBuf, CompressError> {
+        let mut attempts = 1;
+        let (temp_file, file) = loop {
+            let temp_file = temp_file_path(dir, target_file);
+            match fs::OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(&temp_file)
+            {
+                Err(e)
+                    if e.kind() == io::ErrorKind::AlreadyExists
+                        && attempts < MAX_TEMP_FILE_ATTEMPTS =>
+                {
+                    attempts += 1;
+                }
+                result => break (temp_file, result?),
+            }
+        };
+        if let Err(e) = self.fill_temp_file(file, data) {
+            let _ = fs::remove_file(&temp_file);
+            return Err(e);
+        }
+        Ok(temp_file)
+    }
+
+    /// Write the data to the temporary file, keeping the timestamps of the source file if the flag is true.
+    fn fill_temp_file(&self, file: File, data: &[u8]) -> Result<(), CompressError> {
+        let mut file = BufWriter::new(file);
+        file.write_all(data)?;
+        let file = file.into_inner().map_err(|e| e.into_error())?;
+
+        // Copy the timestamps of the source file when the flag is true.
+        if let Some(source_file) = self.source_file().filter(|_| self.preserve_timestamps) {
+            let source_metadata = fs::metadata(source_file)?;
+            let times = FileTimes::new()
+                .set_accessed(source_metadata.accessed()?)
+                .set_modified(source_metadata.modified()?);
+            file.set_times(times)?;
+        }
+        Ok(())
+    }
+
+    /// Compress a file.
+    ///
+    /// Compress the given image file and save it to target_dir.
+    /// The format of the source is detected by its content, so files with a wrong extension or without an extension,
+    /// such as cached downloads, are compressed to `{stem}.jpg` as well.
+    /// If the image module can not open the file, such as pdf, mp4, etc., just copy it to target_dir.
+    /// This can be changed with [`set_unreadable_policy`](Compressor::set_unreadable_policy).
+    /// Compress quality and resize ratio calculate based on file size of the image.
+    /// For a continuous multithreading process, every single error doesn't occur panic or exception and just print error message with return Ok.
+    ///
+    /// If the flag to delete the source is true, the function delete the source file.
+    pub fn compress_to_jpg(&self) -> Result<PathBuf, CompressError> {
+        self.compress_to_jpg_reported().map(|report| report.path)
+    }
+
+    /// Compress a file like [`compress_to_jpg`](Compressor::compress_to_jpg)
+    /// and return the statistics of the compression.
+    pub fn compress_to_jpg_reported(&self) -> Result<CompressionReport, CompressError> {
+        self.compress_to_jpg_with_report_and_bytes()
+            .map(|(report, _)| report)
+    }
+
+    /// Compress a file like [`compress_to_jpg`](Compressor::compress_to_jpg)
+    /// and return the path of the new image with the bytes written to it.
+    ///
+    /// The bytes are the same buffer that is written to the file, so the file does not need to be read again,
+    /// e.g. to upload it to a CDN.
+    /// If the source file is kept with [`set_keep_if_larger`](Compressor::set_keep_if_larger),
+    /// the bytes are the content of the source file.
+    /// # Examples
+    /// ```no_run
+    /// use image_compressor::compressor::Compressor;
+    ///
+    /// let compressor = Compressor::new("source.png", "dest");
+    /// let (path, bytes) = compressor.compress_to_jpg_with_bytes().unwrap();
+    /// ```
+    pub fn compress_to_jpg_with_bytes(&self) -> Result<(PathBuf, Vec<u8>), CompressError> {
+        self.compress_to_jpg_with_report_and_bytes()
+            .map(|(report, data)| (report.path, data))
+    }
+
+    /// Compress a file like [`compress_to_jpg`](Compressor::compress_to_jpg)
+    /// and return the report with the bytes written to the new image.
+    fn compress_to_jpg_with_report_and_bytes(
+        &self,
+    ) -> Result<(CompressionReport, Vec<u8>), CompressError> {
+        if self.gif_mode == GifMode::Animated {
+            let decode_start = Instant::now();
+            if let Some(frames) = self.decode_animation() {
+                return self.compress_animation(frames, decode_start.elapsed());
+            }
+        }
+        self.jpg_extension()?;
+        if self.is_small_jpg() {
+            return self.skip_small_jpg();
+        }
+        // Skip early before opening the image when the file name is already known.
+        if let Some(target_file_name) = self.templated_file_name(None) {
+            self.checked_target_file(target_file_name)?;
+        }
+        let source_image = self.open_image()?;
+        self.compress_source_to_jpg(source_image)
+    }
+
+    /// Compress a file to jpg format like [`compress_to_jpg`](Compressor::compress_to_jpg)
+    /// and also make a thumbnail named `{stem}_thumb.jpg` in the destination directory.
+    /// The thumbnail has the output extension instead of `jpg` if it is set.
+    ///
+    /// The thumbnail is scaled so that its longer side is `thumb_max`.
+    /// If `crop_square` is true, the center square of the image is cropped to make a square thumbnail.
+    /// The source image is opened only once for both images.
+    /// Both images are made in memory first, so neither is written if the other one cannot be made.
+    ///
+    /// Returns the paths of the compressed image and the thumbnail.
+    /// # Examples
+    /// ```no_run
+    /// use image_compressor::compressor::Compressor;
+    ///
+    /// let compressor = Compressor::new("source.png", "dest");
+    /// let (image, thumbnail) = compressor.compress_with_thumbnail(256, true).unwrap();
+    /// ```
+    pub fn compress_with_thumbnail(
+        &self,
+        thumb_max: u32,
+        crop_square: bool,
+    ) -> Result<(PathBuf, PathBuf), CompressError> {
+        let extension = self.jpg_extension()?;
+        if let Some(target_file_name) = self.templated_file_name(None) {
+            self.checked_target_file(target_file_name)?;
+        }
+        let mut thumb_file_name = self.file_stem().to_os_string();
+        thumb_file_name.push(format!("_thumb.{}", extension));
+        let thumb_file = self.checked_target_file(thumb_file_name)?;
+
+        let source_image = self.open_image()?;
+        let factor = self.effective_factor(&source_image.image, self.source_file_size());
+        let thumbnail = self.thumbnail(&source_image.image, thumb_max, crop_square);
+        let (thumb_width, thumb_height) = (thumbnail.width() as usize, thumbnail.height() as usize);
+        let thumb_data = self.compress(
+            ResizedPixels::from_image(thumbnail, false),
+            thumb_width,
+            thumb_height,
+            factor.quality(),
+            &source_image.metadata,
+        )?;
+
+        // The source file is copied instead of the compressed image like compress_to_jpg.
+        let (target_file, data, to_write) = if self.is_small_jpg() {
+            let (target_file, to_write) = self.checked_kept_source_path()?;
+            (target_file, self.source_bytes()?, to_write)
+        } else {
+            let encoded = self.encode_source_to_jpg(source_image)?;
+            if self.is_larger_than_source(&encoded) {
+                let (target_file, to_write) = self.checked_kept_source_path()?;
+                (target_file, self.source_bytes()?, to_write)
+            } else {
+                let target_file_name = self
+                    .templated_file_name(Some((encoded.width, encoded.height, encoded.quality)))
+                    .unwrap_or_default();
+                (
+                    self.checked_target_file(target_file_name)?,
+                    encoded.data,
+                    true,
+                )
+            }
+        };
+
+        if !self.dry_run {
+            self.write_file(&thumb_file, &thumb_data)?;
+        }
+        if to_write {
+            // The source file may be deleted after the image is written, so the image is written last.
+            if let Err(e) = self.write_target_file(&target_file, &data) {
+                let _ = fs::remove_file(&thumb_file);
+                return Err(e);
+            }
+        }
+        Ok((target_file, thumb_file))
+    }
+
+    /// Compress the opened source image to jpg format and save it to the destination directory.
+    ///
+    /// Returns the report with the bytes written to the new image.
+    fn compress_source_to_jpg(
+        &self,
+        source_image: SourceImage,
+    ) -> Result<(CompressionReport, Vec<u8>), CompressError> {
+        let encoded = self.encode_source_to_jpg(source_image)?;
+        if self.is_larger_than_source(&encoded) {
+            return self.keep_source(&encoded);
+        }
+        let target_file_name = self
+            .templated_file_name(Some((encoded.width, encoded.height, encoded.quality)))
+            .unwrap_or_default();
+        let target_file = self.checked_target_file(target_file_name)?;
+        self.write_target_file(&target_file, &encoded.data)?;
+        let report = encoded.report(target_file);
+        Ok((report, encoded.data))
+    }
+
+    /// Decode the frames of the source file if it is a gif image with more than one frame.
+    fn decode_animation(&self) -> Option<Vec<Frame>> {
+        let frames = match &self.source_data {
+            Some(data) => decode_gif_frames(io::Cursor::new(data.as_slice())),
+            None => File::open(self.source_path.as_ref())
+                .map_err(ImageError::IoError)
+                .and_then(|file| decode_gif_frames(BufReader::new(file))),
+        };
+        frames.ok().filter(|frames| frames.len() > 1)
+    }
+
+    /// Resize the frames of the animated source image and save them as an animated gif image.
+    ///
+    /// Returns the report with the bytes written to the new image.
+    fn compress_animation(
+        &self,
+        frames: Vec<Frame>,
+        decode_time: Duration,
+    ) -> Result<(CompressionReport, Vec<u8>), CompressError> {
+        // Skip early before resizing the frames when the file name is already known.
+        if let Some(target_file_name) = self.templated_file_name(None) {
+            self.checked_target_file(self.animation_file_name(target_file_name))?;
+        }
+        let original_size = self.source_file_size();
+        let first_frame = frames[0].buffer();
+        let original_dimensions = first_frame.dimensions();
+        let factor = self.effective_factor(
+            &image::DynamicImage::ImageRgba8(first_frame.clone()),
+            original_size,
+        );
+
+        // The frames of a gif image have the size of the whole canvas, so they are all resized to the same size.
+        let resize_start = Instant::now();
+        let resized_frames = frames
+            .into_iter()
+            .map(|frame| {
+                let delay = frame.delay();
+                let img = image::DynamicImage::ImageRgba8(frame.into_buffer());
+                let resized = self.resize_image(img, factor.size_ratio())?;
+                Ok(Frame::from_parts(resized.into_rgba8(), 0, 0, delay))
+            })
+            .collect::<Result<Vec<_>, CompressError>>()?;
+        let resize_time = resize_start.elapsed();
+        let (width, height) = resized_frames[0].buffer().dimensions();
+
+        let target_file_name = self
+            .templated_file_name(Some((width as usize, height as usize, factor.quality())))
+            .unwrap();
+        let target_file = self.checked_target_file(self.animation_file_name(target_file_name))?;
+
+        let encode_start = Instant::now();
+        let mut data = Vec::new();
+        {
+            let mut encoder = GifEncoder::new_with_speed(&mut data, 10);
+            encoder
+                .set_repeat(Repeat::Infinite)
+                .and_then(|_| encoder.encode_frames(resized_frames))
+                .map_err(|e| CompressError::Encode(e.to_string()))?;
+        }
+        let encode_time = encode_start.elapsed();
+
+        self.write_target_file(&target_file, &data)?;
+        let report = CompressionReport {
+            path: target_file,
+            original_size,
+            compressed_size: data.len() as u64,
+            original_dimensions,
+            dimensions: (width, height),
+            kept_original: false,
+            skipped: false,
+            decode_time,
+            resize_time,
+            encode_time,
+        };
+        Ok((report, data))
+    }
+
+    /// Replace the extension of the file name made from the output name template for an animated gif image.
+    ///
+    /// The output extension is kept if it is one of gif images, such as `GIF`.
+    /// Otherwise the extension is `gif`, since the image is not a jpg image.
+    fn animation_file_name(&self, file_name: OsString) -> PathBuf {
+        let extension = match &self.output_extension {
+            Some(extension) if extension.eq_ignore_ascii_case("gif") => extension.as_str(),
+            _ => "gif",
+        };
+        Path::new(&file_name).with_extension(extension)
+    }
+
+    /// Check whether the source file should be kept because the compressed image is larger.
+    fn is_larger_than_source(&self, encoded: &EncodedJpg) -> bool {
+        self.keep_if_larger && encoded.data.len() as u64 > encoded.original_size
+    }
+
+    /// Build the path of the source file copied to the destination directory, keeping its extension.
+    fn kept_source_path(&self) -> PathBuf {
+        let mut file_name = PathBuf::from(self.file_stem());
+        if let Some(extension) = self.source_path.as_ref().extension() {
+            file_name.set_extension(extension);
+        }
+        self.dest_path.as_ref().join(file_name)
+    }
+
+    /// Copy the source file to the destination directory instead of the compressed image,
+    /// and return the report of the copy with the content of the source file.
+    ///
+    /// Nothing is copied or deleted when the destination is the source file itself.
+    fn keep_source(
+        &self,
+        encoded: &EncodedJpg,
+    ) -> Result<(CompressionReport, Vec<u8>), CompressError> {
+        let (target_file, data) = self.copy_source()?;
+        Ok((encoded.kept_source_report(target_file), data))
+    }
+
+    /// Copy the source file to the destination directory with its own extension,
+    /// and return the path of the copy with the content of the source file.
+    ///
+    /// Nothing is copied or deleted when the destination is the source file itself.
+    fn copy_source(&self) -> Result<(PathBuf, Vec<u8>), CompressError> {
+        let (target_file, to_write) = self.checked_kept_source_path()?;
+        let data = self.source_bytes()?;
+        if to_write {
+            self.write_target_file(&target_file, &data)?;
+        }
+        Ok((target_file, data))
+    }
+
+    /// Build the path of the source file copied to the destination directory, keeping its extension.
+    ///
+    /// Returns the path with whether it must be written, which is false when it is the source file itself.
+    ///
+    /// # Error
+    /// - When a file with the same name already exists in the destination directory and the overwrite flag is false.
+    fn checked_kept_source_path(&self) -> Result<(PathBuf, bool), CompressError> {
+        let target_file = self.kept_source_path();
+        if self
+            .source_file()
+            .is_some_and(|source| is_same_file(source, &target_file))
+        {
+            return Ok((target_file, false));
+        }
+        let target_file = self.checked_target_file(target_file.file_name().unwrap_or_default())?;
+        Ok((target_file, true))
+    }
+
+    /// Read the content of the source file, or the data read in advance if it is set.
+    fn source_bytes(&self) -> Result<Vec<u8>, CompressError> {
+        match &self.source_data {
+            Some(data) => Ok(data.clone()),
+            None => Ok(fs::read(self.source_path.as_ref())?),
+        }
+    }
+
+    /// Check whether the source file is a jpg image smaller than the threshold
+    /// set by [`set_skip_if_smaller_than`](Compressor::set_skip_if_smaller_than).
+    fn is_small_jpg(&self) -> bool {
+        match self.skip_if_smaller_than {
+            Some(threshold) if self.source_file_size() < threshold => {
+                let format = match &self.source_data {
+                    Some(data) => self.guess_image_format(io::Cursor::new(data.as_slice())),
+                    None => File::open(self.source_path.as_ref())
+                        .ok()
+                        .and_then(|file| self.guess_image_format(BufReader::new(file))),
+                };
+                format == Some(ImageFormat::Jpeg)
+            }
+            _ => false,
+        }
+    }
+
+    /// Make the report of the small jpg source file that is copied without the compression to the path.
+    fn skipped_report(&self, path: PathBuf, data: &[u8]) -> CompressionReport {
+        let dimensions = image::io::Reader::with_format(io::Cursor::new(data), ImageFormat::Jpeg)
+            .into_dimensions()
+            .unwrap_or_default();
+        CompressionReport {
+            path,
+            original_size: data.len() as u64,
+            compressed_size: data.len() as u64,
+            original_dimensions: dimensions,
+            dimensions,
+            kept_original: false,
+            skipped: true,
+            decode_time: Duration::ZERO,
+            resize_time: Duration::ZERO,
+            encode_time: Duration::ZERO,
+        }
+    }
+
+    /// Copy the small jpg source file to the destination directory without the compression,
+    /// and return the report of the copy with the content of the source file.
+    fn skip_small_jpg(&self) -> Result<(CompressionReport, Vec<u8>), CompressError> {
+        let (target_file, data) = self.copy_source()?;
+        Ok((self.skipped_report(target_file, &data), data))
+    }
+
+    /// Compress a file to the output format in memory and return the report with the new image.
+    ///
+    /// Nothing is written to the destination directory,
+    /// and the path of the report is the path that [`compress_to_jpg`](Compressor::compress_to_jpg)
+    /// or the other compress functions would write.
+    #[cfg(feature = "folder")]
+    pub(crate) fn compress_to_memory_reported(
+        &self,
+        format: OutputFormat,
+    ) -> Result<(CompressionReport, Vec<u8>), CompressError> {
+        if self.is_small_jpg() {
+            let data = self.source_bytes()?;
+            return Ok((self.skipped_report(self.kept_source_path(), &data), data));
+        }
+        if let Some(encoder) = self.format_encoder(format) {
+            let mut target_file_name = PathBuf::from(self.file_stem());
+            target_file_name.set_extension(self.output_extension(encoder.as_ref())?);
+            let target_file = self.dest_path.as_ref().join(target_file_name);
+            return self.encode_with(encoder.as_ref(), target_file);
+        }
+        self.jpg_extension()?;
+        let source_image = self.open_image()?;
+        let encoded = self.encode_source_to_jpg(source_image)?;
+        if self.is_larger_than_source(&encoded) {
+            let report = encoded.kept_source_report(self.kept_source_path());
+            return Ok((report, self.source_bytes()?));
+        }
+        let target_file_name = self
+            .templated_file_name(Some((encoded.width, encoded.height, encoded.quality)))
+            .unwrap_or_default();
+        let report = encoded.report(self.dest_path.as_ref().join(target_file_name));
+        Ok((report, encoded.data))
+    }
+
+    /// Compress a file to jpg format like [`compress_to_jpg`](Compressor::compress_to_jpg),
+    /// but write the jpg image to the given writer instead of a file in the destination directory.
+    ///
+    /// It can stream the image directly to an HTTP response body or a zip writer.
+    /// Nothing is written to the destination directory, even if the file cannot be opened as an image,
+    /// and the source file is not deleted even if the flag to delete the source is true.
+    /// Returns the number of bytes written.
+    ///
+    /// # Error
+    /// - [`CompressError::UnsupportedInput`] when the source file is not an image,
+    ///   regardless of the [`UnreadablePolicy`].
+    /// # Examples
+    /// ```no_run
+    /// use image_compressor::compressor::Compressor;
+    ///
+    /// let compressor = Compressor::new("source.png", "dest");
+    /// let mut buffer = Vec::new();
+    /// let written = compressor.compress_to_writer(&mut buffer).unwrap();
+    /// ```
+    pub fn compress_to_writer<W: Write>(&self, mut writer: W) -> Result<u64, CompressError> {
+        let source_image = self.decode_source()?;
+        let encoded = self.encode_source_to_jpg(source_image)?;
+        writer.write_all(&encoded.data)?;
+        writer.flush()?;
+        Ok(encoded.data.len() as u64)
+    }
+
+    /// Predict the file size in bytes of the jpg image that [`compress_to_jpg`](Compressor::compress_to_jpg) would write.
+    ///
+    /// The image is resized and compressed in memory, and nothing is written or deleted,
+    /// even if the file cannot be opened as an image.
+    /// With [`set_keep_if_larger`](Compressor::set_keep_if_larger), the size of the source file is returned if the jpg image is larger.
+    /// # Examples
+    /// ```no_run
+    /// use image_compressor::compressor::Compressor;
+    ///
+    /// let compressor = Compressor::new("source.png", "dest");
+    /// println!("About {} bytes", compressor.estimate().unwrap());
+    /// ```
+    pub fn estimate(&self) -> Result<u64, CompressError> {
+        let source_image = self.decode_source()?;
+        let encoded = self.encode_source_to_jpg(source_image)?;
+        if self.is_larger_than_source(&encoded) {
+            return Ok(encoded.original_size);
+        }
+        Ok(encoded.data.len() as u64)
+    }
+
+    /// Compress the source image to jpg format in memory at each of the qualities,
+    /// and return the pairs of the quality and the size in bytes of the jpg image.
+    ///
+    /// The image is decoded and resized with the size ratio of the `Factor` only once,
+    /// and nothing is written or deleted. It shows the tradeoff between the quality and the size,
+    /// which helps to choose the `Factor` for a new kind of images.
+    /// # Examples
+    /// ```no_run
+    /// use image_compressor::compressor::Compressor;
+    ///
+    /// let compressor = Compressor::new("source.png", "dest");
+    /// for (quality, size) in compressor.sweep(&[90., 80., 70., 60.]).unwrap() {
+    ///     println!("{}: {} bytes", quality, size);
+    /// }
+    /// ```
+    pub fn sweep(&self, qualities: &[f32]) -> Result<Vec<(f32, u64)>, CompressError> {
+        let SourceImage {
+            image: image_vec,
+            metadata,
+            ..
+        } = self.decode_source()?;
+        let factor = self.effective_factor(&image_vec, self.source_file_size());
+        let (resized_img_data, target_width, target_height) =
+            self.resize(image_vec, factor.size_ratio())?;
+
+        qualities
+            .iter()
+            .map(|&quality| {
+                let compressed = self.compress(
+                    resized_img_data.clone(),
+                    target_width,
+                    target_height,
+                    quality,
+                    &metadata,
+                )?;
+                Ok((quality, compressed.len() as u64))
+            })
+            .collect()
+    }
+
+    /// Resize and compress the opened source image to jpg format in memory.
+    fn encode_source_to_jpg(&self, source_image: SourceImage) -> Result<EncodedJpg, CompressError> {
+        let file_name = self
+            .source_path
+            .as_ref()
+            .file_name()
+            .unwrap_or_default()
+            .to_string_lossy();
+        let SourceImage {
+            image: image_vec,
+            metadata,
+            decode_time,
+        } = source_image;
+        let original_size = self.source_file_size();
+        let original_dimensions = (image_vec.width(), image_vec.height());
+        let factor = self.effective_factor(&image_vec, original_size);
+
+        let resize_start = Instant::now();
+        let (resized_img_data, target_width, target_height) =
+            self.resize(image_vec, factor.size_ratio())?;
+        let resize_time = resize_start.elapsed();
+        let encode_start = Instant::now();
+        let (quality, compressed_img_data) = self
+            .compress_in_quality_range(
+                resized_img_data,
+                target_width,
+                target_height,
+                factor.quality(),
+                &metadata,
+            )
+            .map_err(|e| {
+                CompressError::Encode(format!("Cannot compress file {}: {}", file_name, e))
+            })?;
+
+        Ok(EncodedJpg {
+            data: compressed_img_data,
+            original_size,
+            original_dimensions,
+            width: target_width,
+            height: target_height,
+            quality,
+            decode_time,
+            resize_time,
+            encode_time: encode_start.elapsed(),
+        })
+    }
+
+    /// Compress a file to jpg format under the given file size.
+    ///
+    /// The image is resized with the size ratio of the `Factor`,
+    /// then the jpg quality is binary searched between 20 and 95 in memory
+    /// to find the highest quality whose result is at most `max_bytes`.
+    /// The search stops after 8 compressions and the best result is saved to the destination directory.
+    ///
+    /// # Error
+    /// - When the image compressed with quality 20 still exceeds `max_bytes`.
+    pub fn compress_to_target_size(&self, max_bytes: u64) -> Result<PathBuf, CompressError> {
+        let target_file = self.target_file_path(&self.jpg_extension()?)?;
+        let SourceImage {
+            image: image_vec,
+            metadata,
+            ..
+        } = self.open_image()?;
+        let factor = self.effective_factor(&image_vec, self.source_file_size());
+
+        let (resized_img_data, target_width, target_height) =
+            self.resize(image_vec, factor.size_ratio())?;
+
+        let mut best = self.compress(
+            resized_img_data.clone(),
+            target_width,
+            target_height,
+            MIN_SEARCH_QUALITY as f32,
+            &metadata,
+        )?;
+        if best.len() as u64 > max_bytes {
+            return Err(CompressError::TargetSizeUnreachable {
+                path: self.source_path.as_ref().to_path_buf(),
+                max_bytes,
+                smallest: best.len() as u64,
+            });
+        }
+
+        let mut low = MIN_SEARCH_QUALITY + 1;
+        let mut high = MAX_SEARCH_QUALITY;
+        for _ in 1..MAX_SEARCH_ATTEMPTS {
+            if low > high {
+                break;
+            }
+            let quality = (low + high) / 2;
+            let compressed = self.compress(
+                resized_img_data.clone(),
+                target_width,
+                target_height,
+                quality as f32,
+                &metadata,
+            )?;
+            if compressed.len() as u64 <= max_bytes {
+                best = compressed;
+                low = quality + 1;
+            } else {
+                high = quality - 1;
+            }
+        }
+
+        self.write_target_file(&target_file, &best)?;
+        Ok(target_file)
+    }
+
+    /// Compress a file to jpg format with the lowest quality whose SSIM against the resized image is at least `min_ssim`.
+    ///
+    /// The image is resized with the size ratio of the `Factor`,
+    /// then the jpg quality is binary searched between 20 and 95 in memory.
+    /// Each candidate is decoded and compared with the resized image by the mean SSIM of their luma,
+    /// so the images of a heterogeneous set get a consistent perceptual quality.
+    /// The search stops after 8 compressions.
+    /// If no quality reaches `min_ssim`, the image is compressed with quality 95.
+    /// # Examples
+    /// ```no_run
+    /// use image_compressor::compressor::Compressor;
+    ///
+    /// let compressor = Compressor::new("source.png", "dest");
+    /// compressor.compress_to_quality_floor(0.95).unwrap();
+    /// ```
+    pub fn compress_to_quality_floor(&self, min_ssim: f64) -> Result<PathBuf, CompressError> {
+        let target_file = self.target_file_path(&self.jpg_extension()?)?;
+        let SourceImage {
+            image: image_vec,
+            metadata,
+            ..
+        } = self.open_image()?;
+        let factor = self.effective_factor(&image_vec, self.source_file_size());
+
+        let (resized_img_data, target_width, target_height) =
+            self.resize(image_vec, factor.size_ratio())?;
+        let (_, compressed) = self.search_quality_for_ssim(
+            resized_img_data,
+            target_width,
+            target_height,
+            &metadata,
+            min_ssim,
+        )?;
+
+        self.write_target_file(&target_file, &compressed)?;
+        Ok(target_file)
+    }
+
+    /// Binary search the lowest jpg quality whose SSIM against the pixels is at least `min_ssim`.
+    /// Returns the quality and the compressed image.
+    fn search_quality_for_ssim(
+        &self,
+        pixels: ResizedPixels,
+        width: usize,
+        height: usize,
+        metadata: &ImageMetadata,
+        min_ssim: f64,
+    ) -> Result<(u32, Vec<u8>), CompressError> {
+        let reference = luma_of(&pixels, width, height)?;
+
+        let mut best = None;
+        let mut low = MIN_SEARCH_QUALITY;
+        let mut high = MAX_SEARCH_QUALITY;
+        for _ in 0..MAX_SEARCH_ATTEMPTS {
+            if low > high {
+                break;
+            }
+            let quality = (low + high) / 2;
+            let compressed =
+                self.compress(pixels.clone(), width, height, quality as f32, metadata)?;
+            let decoded =
+                image::load_from_memory_with_format(&compressed, ImageFormat::Jpeg)?.into_luma8();
+            if ssim(&reference, &decoded) >= min_ssim {
+                best = Some((quality, compressed));
+                high = quality - 1;
+            } else {
+                low = quality + 1;
+            }
+        }
+
+        match best {
+            Some(best) => Ok(best),
+            None => Ok((
+                MAX_SEARCH_QUALITY,
+                self.compress(pixels, width, height, MAX_SEARCH_QUALITY as f32, metadata)?,
+            )),
+        }
+    }
+
+    /// Compress a file with the encoder set by [`set_encoder`](Compressor::set_encoder).
+    ///
+    /// Works like [`compress_to_jpg`](Compressor::compress_to_jpg),
+    /// but the resized image is encoded by the encoder and saved with its extension.
+    /// If no encoder is set, the image is compressed to jpg format with [`compress_to_jpg`](Compressor::compress_to_jpg).
+    pub fn compress_with_encoder(&self) -> Result<PathBuf, CompressError> {
+        match &self.encoder {
+            Some(encoder) => self.compress_with(encoder.as_ref()),
+            None => self.compress_to_jpg(),
+        }
+    }
+
+    /// Compress a file to webp format.
+    ///
+    /// Works like [`compress_to_jpg`](Compressor::compress_to_jpg),
+    /// but the resized image is encoded as a lossy webp image with the quality of the `Factor`
+    /// and saved with the `webp` extension.
+    /// The quality is converted to the webp scale by [`WebpEncoder`], so it looks about the same as the jpg image.
+    /// Note that earlier versions used the quality as it is, so the same `Factor` now gives a lower webp quality,
+    /// e.g. 65 instead of 80.
+    pub fn compress_to_webp(&self) -> Result<PathBuf, CompressError> {
+        self.compress_with(&WebpEncoder)
+    }
+
+    /// Compress a file to png format.
+    ///
+    /// Works like [`compress_to_jpg`](Compressor::compress_to_jpg),
+    /// but the resized image is losslessly re-encoded as a png image
+    /// with the compression level set by [`set_png_compression`](Compressor::set_png_compression) and adaptive filtering.
+    /// The alpha channel is kept if the source image has one.
+    pub fn compress_to_png(&self) -> Result<PathBuf, CompressError> {
+        self.compress_with(&PngEncoder::new(self.png_compression))
+    }
+
+    /// Resize the source image, encode it with the encoder, and save it with the extension of the encoder.
+    ///
+    /// The output extension is used instead if it is set.
+    fn compress_with(&self, encoder: &dyn OutputEncoder) -> Result<PathBuf, CompressError> {
+        self.compress_with_reported(encoder)
+            .map(|report| report.path)
+    }
+
+    /// Compress a file like [`compress_with`](Compressor::compress_with)
+    /// and return the statistics of the compression.
+    fn compress_with_reported(
+        &self,
+        encoder: &dyn OutputEncoder,
+    ) -> Result<CompressionReport, CompressError> {
+        if self.is_small_jpg() {
+            return self.skip_small_jpg().map(|(report, _)| report);
+        }
+        let target_file = self.target_file_path(&self.output_extension(encoder)?)?;
+        let (report, encoded) = self.encode_with(encoder, target_file)?;
+        self.write_target_file(&report.path, &encoded)?;
+        Ok(report)
+    }
+
+    /// Resize the source image and encode it with the encoder in memory.
+    ///
+    /// Returns the report with the path of `target_file`, which is not written, and the encoded image.
+    fn encode_with(
+        &self,
+        encoder: &dyn OutputEncoder,
+        target_file: PathBuf,
+    ) -> Result<(CompressionReport, Vec<u8>), CompressError> {
+        let SourceImage {
+            image: image_vec,
+            decode_time,
+            ..
+        } = self.open_image()?;
+        let original_size = self.source_file_size();
+        let original_dimensions = (image_vec.width(), image_vec.height());
+        let factor = self.effective_factor(&image_vec, original_size);
+
+        let resize_start = Instant::now();
+        let (resized_img_data, target_width, target_height) =
+            self.resize_with_depth(image_vec, factor.size_ratio(), encoder.supports_16bit())?;
+        let resize_time = resize_start.elapsed();
+        let encode_start = Instant::now();
+        let encoded = encoder.encode(
+            resized_img_data,
+            target_width as u32,
+            target_height as u32,
+            factor,
+        )?;
+
+        let report = CompressionReport {
+            path: target_file,
+            original_size,
+            compressed_size: encoded.len() as u64,
+            original_dimensions,
+            dimensions: (target_width as u32, target_height as u32),
+            kept_original: false,
+            skipped: false,
+            decode_time,
+            resize_time,
+            encode_time: encode_start.elapsed(),
+        };
+        Ok((report, encoded))
+    }
+
+    /// The encoder of the output format, or `None` for jpg format,
+    /// which is compressed with the jpg options of this instance instead.
+    #[cfg(feature = "folder")]
+    fn format_encoder(&self, format: OutputFormat) -> Option<Box<dyn OutputEncoder>> {
+        match format {
+            OutputFormat::Jpeg => None,
+            OutputFormat::Png => Some(Box::new(PngEncoder::new(self.png_compression))),
+            OutputFormat::Webp => Some(Box::new(WebpEncoder)),
+        }
+    }
+
+    /// Compress a file to the output format like [`compress_to_jpg_reported`](Compressor::compress_to_jpg_reported).
+    #[cfg(feature = "folder")]
+    pub(crate) fn compress_to_format_reported(
+        &self,
+        format: OutputFormat,
+    ) -> Result<CompressionReport, CompressError> {
+        match self.format_encoder(format) {
+            Some(encoder) => self.compress_with_reported(encoder.as_ref()),
+            None => self.compress_to_jpg_reported(),
+        }
+    }
+}
+
+#[cfg(feature = "url")]
+impl<D: AsRef<Path>> Compressor<PathBuf, D> {
+    /// Download the image at the `http` or `https` URL into memory and make a new compressor of it.
+    ///
+    /// Redirects are followed, and the format of the image is detected by its content like a file.
+    /// The source is named after the final URL, so `https://example.com/photo.png` is compressed to `photo.jpg`.
+    /// If the URL has no extension, the one of the `Content-Type` header is used.
+    /// The source is not a local file, so the flags to delete the source and keep its timestamps have no effect,
+    /// even if a local file has the same name as the source.
+    /// Available with the `url` feature.
+    ///
+    /// # Error
+    /// - When the image cannot be downloaded, e.g. the server returns an error status,
+    ///   or the image is larger than 256 MiB.
+    /// # Examples
+    /// ```no_run
+    /// use image_compressor::compressor::Compressor;
+    ///
+    /// let compressor = Compressor::from_url("https://example.com/photo.png", "dest").unwrap();
+    /// let path = compressor.compress_to_jpg().unwrap();
+    /// ```
+    pub fn from_url(url: &str, dest_dir_path: D) -> Result<Self, CompressError> {
+        use ureq::ResponseExt;
+
+        let mut response = ureq::get(url).call().map_err(io::Error::other)?;
+        let extension = response
+            .body()
+            .mime_type()
+            .and_then(ImageFormat::from_mime_type)
+            .map(|format| format.extensions_str()[0]);
+        let file_name = download_file_name(response.get_uri().path(), extension);
+        let data = response
+            .body_mut()
+            .with_config()
+            .limit(MAX_DOWNLOAD_SIZE)
+            .read_to_vec()
+            .map_err(io::Error::other)?;
+
+        let mut compressor = Compressor::new(file_name, dest_dir_path);
+        compressor.set_unreadable_policy(UnreadablePolicy::Fail);
+        compressor.source_data = Some(data);
+        compressor.remote_source = true;
+        Ok(compressor)
+    }
+}
+
+#[cfg(feature = "async")]
+impl<O, D> Compressor<O, D>
+where
+    O: AsRef<Path> + Send + 'static,
+    D: AsRef<Path> + Send + 'static,
+{
+    /// Compress a file like [`compress_to_jpg`](Compressor::compress_to_jpg) without blocking the async executor.
+    ///
+    /// The compression runs in the blocking thread pool of tokio with `spawn_blocking`,
+    /// so it must be called inside a tokio runtime. Available with the `async` feature.
+    /// # Examples
+    /// ```no_run
+    /// use image_compressor::compressor::Compressor;
+    ///
+    /// # async fn run() {
+    /// let compressor = Compressor::new("source.png", "dest");
+    /// let path = compressor.compress_to_jpg_async().await;
+    /// # }
+    /// ```
+    pub async fn compress_to_jpg_async(self) -> Result<PathBuf, CompressError> {
+        tokio::task::spawn_blocking(move || self.compress_to_jpg())
+            .await
+            .map_err(|e| CompressError::Io(io::Error::other(e)))?
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    use crate::encoder::icc_markers;
+    use image::ImageBuffer;
+    use mozjpeg::{ColorSpace, Compress, Marker};
+    use rand::Rng;
+    use std::path::{Path, PathBuf};
+    use std::sync::Mutex;
+
+    /// Create test directory and an image file in it.
+    fn setup<T: AsRef<Path>>(test_name: T) -> (PathBuf, Vec<PathBuf>) {
+        let test_dir = test_name.as_ref().to_path_buf();
+        if test_dir.is_dir() {
+            fs::remove_dir_all(&test_dir).unwrap();
+        }
+        fs::create_dir_all(&test_dir).unwrap();
+
+        const WIDTH: u32 = 256;
+        const HEIGHT: u32 = 256;
+        let img_stripe = ImageBuffer::from_fn(WIDTH, HEIGHT, |x, _| {
+            if x % 10 == 0 {
+                image::Luma([0u8])
+            } else {
+                image::Luma([255u8])
+            }
+        });
+        let stripe_path = test_dir.join("img_stripe.png");
+        img_stripe.save(&stripe_path).unwrap();
+        let img_random_rgb = ImageBuffer::from_fn(WIDTH, HEIGHT, |_, _| {
+            let r = rand::thread_rng().gen_range(0..256) as u8;
+            let g = rand::thread_rng().gen_range(0..256) as u8;
+            let b = rand::thread_rng().gen_range(0..256) as u8;
+            image::Rgb([r, g, b])
+        });
+        let rgb_path = test_dir.join("img_random_rgb.gif");
+        img_random_rgb.save(&rgb_path).unwrap();
+
+        (test_dir, vec![stripe_path, rgb_path])
+    }
+
+    fn cleanup<T: AsRef<Path>>(test_dir: T) {
+        if test_dir.as_ref().is_dir() {
+            fs::remove_dir_all(&test_dir).unwrap();
+        }
+    }
+
+    #[test]
+    fn skip_wrong_ext_test() {
+        let (test_dir, _) = setup("skip_wrong_ext_test_dir");
+        let txt_data = "Hello, World!";
+        let mut txt_path = PathBuf::from(&test_dir).join("skip_wrong_ext_test.txt");
+        let mut txt_file = File::create(&txt_path).unwrap();
+        write!(txt_file, "{}", txt_data).unwrap();
+
+        let compressor = Compressor::new(&txt_path, &test_dir);
+        assert!(compressor.compress_to_jpg().is_err());
+        assert!(txt_path.is_file());
+        txt_path.set_extension("jpg");
+        assert!(!txt_path.is_file());
+        cleanup(test_dir);
+    }
+
+    #[test]
+    fn mislabeled_extension_test() {
+        let test_dir = PathBuf::from("mislabeled_extension_test");
+        cleanup(&test_dir);
+        fs::create_dir_all(&test_dir).unwrap();
+        let img = ImageBuffer::from_fn(64, 32, |x, y| image::Rgb([x as u8, y as u8, 128u8]));
+        let mislabeled = test_dir.join("mislabeled.jpg");
+        img.save_with_format(&mislabeled, ImageFormat::Png).unwrap();
+        let no_extension = test_dir.join("no_extension");
+        img.save_with_format(&no_extension, ImageFormat::WebP)
+            .unwrap();
+
+        let dest_dir = PathBuf::from("mislabeled_extension_dest_dir");
+        cleanup(&dest_dir);
+        fs::create_dir_all(&dest_dir).unwrap();
+        for (source, expected) in [
+            (&mislabeled, "mislabeled.jpg"),
+            (&no_extension, "no_extension.jpg"),
+        ] {
+            let mut compressor = Compressor::new(source, &dest_dir);
+            compressor.set_factor(Factor::new(80., 1.0));
+            let compressed = compressor.compress_to_jpg().unwrap();
+            assert_eq!(compressed, dest_dir.join(expected));
+            let data = fs::read(&compressed).unwrap();
+            assert_eq!(image::guess_format(&data).unwrap(), ImageFormat::Jpeg);
+            assert_eq!(image::image_dimensions(&compressed).unwrap(), (64, 32));
+        }
+        cleanup(test_dir);
+        cleanup(dest_dir);
+    }
+
+    #[test]
+    fn extensionless_jpg_test() {
+        let test_dir = PathBuf::from("extensionless_jpg_test");
+        cleanup(&test_dir);
+        fs::create_dir_all(&test_dir).unwrap();
+        let source = test_dir.join("cached_image");
+        ImageBuffer::from_fn(64, 64, |x, y| image::Rgb([x as u8, y as u8, 128u8]))
+            .save_with_format(&source, ImageFormat::Jpeg)
+            .unwrap();
+
+        let dest_dir = PathBuf::from("extensionless_jpg_dest_dir");
+        cleanup(&dest_dir);
+        fs::create_dir_all(&dest_dir).unwrap();
+        let mut compressor = Compressor::new(&source, &dest_dir);
+        compressor.set_factor(Factor::new(80., 0.5));
+        let compressed = compressor.compress_to_jpg().unwrap();
+        assert_eq!(compressed, dest_dir.join("cached_image.jpg"));
+        assert_eq!(image::image_dimensions(&compressed).unwrap(), (32, 32));
+        assert!(!dest_dir.join("cached_image").exists());
+        cleanup(test_dir);
+        cleanup(dest_dir);
+    }
+
+    #[test]
+    fn unreadable_policy_test() {
+        let (test_dir, _) = setup("unreadable_policy_test_dir");
+        let txt_path = test_dir.join("unreadable_policy_test.txt");
+        fs::write(&txt_path, "Hello, World!").unwrap();
+        let dest_dir = PathBuf::from("unreadable_policy_test_dest_dir");
+        cleanup(&dest_dir);
+        fs::create_dir_all(&dest_dir).unwrap();
+        let copied_path = dest_dir.join("unreadable_policy_test.txt");
+
+        let mut compressor = Compressor::new(&txt_path, &dest_dir);
+        compressor.set_unreadable_policy(UnreadablePolicy::Skip);
+        match compressor.compress_to_jpg() {
+            Err(CompressError::Skipped(path)) => assert_eq!(path, txt_path),
+            r => panic!("Unexpected result: {:?}", r),
+        }
+        assert!(!copied_path.exists());
+
+        compressor.set_unreadable_policy(UnreadablePolicy::Fail);
+        assert!(matches!(
+            compressor.compress_to_jpg(),
+            Err(CompressError::UnsupportedInput { format: None, .. })
+        ));
+        assert!(!copied_path.exists());
+
+        compressor.set_unreadable_policy(UnreadablePolicy::CopyThrough);
+        assert!(matches!(
+            compressor.compress_to_jpg(),
+            Err(CompressError::UnsupportedInput { format: None, .. })
+        ));
+        assert_eq!(fs::read(&copied_path).unwrap(), b"Hello, World!");
+        assert!(txt_path.is_file());
+        cleanup(test_dir);
+        cleanup(dest_dir);
+    }
+
+    #[cfg(feature = "async")]
+    #[tokio::test]
+    async fn compress_to_jpg_async_test() {
+        let (test_dir, test_images) = setup("compress_to_jpg_async_test");
+
+        let dest_dir = PathBuf::from("compress_to_jpg_async_dest_dir");
+        fs::create_dir_all(&dest_dir).unwrap();
+        let compressor = Compressor::new(test_images[0].clone(), dest_dir.clone());
+        let compressed = compressor.compress_to_jpg_async().await.unwrap();
+        assert_eq!(compressed, dest_dir.join("img_stripe.jpg"));
+        assert!(compressed.is_file());
+        cleanup(test_dir);
+        cleanup(dest_dir);
+    }
+
+    #[test]
+    fn compress_to_writer_test() {
+        let (test_dir, test_images) = setup("compress_to_writer_test");
+
+        let mut compressor = Compressor::new(&test_images[0], &test_dir);
+        compressor.set_factor(Factor::new(80., 0.5));
+        let mut buffer = Vec::new();
+        let written = compressor.compress_to_writer(&mut buffer).unwrap();
+        assert_eq!(written, buffer.len() as u64);
+        assert_eq!(image::guess_format(&buffer).unwrap(), ImageFormat::Jpeg);
+        let decoded = image::load_from_memory(&buffer).unwrap();
+        assert_eq!((decoded.width(), decoded.height()), (128, 128));
+        assert!(!test_dir.join("img_stripe.jpg").exists());
+
+        let txt_path = test_dir.join("compress_to_writer_test.txt");
+        fs::write(&txt_path, "Hello, World!").unwrap();
+        let dest_dir = test_dir.join("dest");
+        fs::create_dir_all(&dest_dir).unwrap();
+        let compressor = Compressor::new(&txt_path, &dest_dir);
+        assert!(matches!(
+            compressor.compress_to_writer(&mut Vec::new()),
+            Err(CompressError::UnsupportedInput { format: None, .. })
+        ));
+        assert_eq!(fs::read_dir(&dest_dir).unwrap().count(), 0);
+        cleanup(test_dir);
+    }
+
+    #[test]
+    fn compress_to_jpg_with_bytes_test() {
+        let (test_dir, test_images) = setup("compress_to_jpg_with_bytes_test");
+
+        let compressor = Compressor::new(&test_images[0], &test_dir);
+        let (path, bytes) = compressor.compress_to_jpg_with_bytes().unwrap();
+        assert_eq!(path, test_dir.join("img_stripe.jpg"));
+        assert_eq!(bytes, fs::read(&path).unwrap());
+        assert_eq!(image::guess_format(&bytes).unwrap(), ImageFormat::Jpeg);
+        cleanup(test_dir);
+    }
+
+    #[test]
+    #[cfg(feature = "url")]
+    fn from_url_test() {
+        use std::io::BufRead;
+        use std::net::TcpListener;
+
+        let (test_dir, test_images) = setup("from_url_test");
+        let png = fs::read(&test_images[0]).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = std::thread::spawn(move || {
+            for stream in listener.incoming().take(4) {
+                let mut stream = stream.unwrap();
+                let mut lines = BufReader::new(stream.try_clone().unwrap()).lines();
+                let request_line = lines.next().unwrap().unwrap();
+                for line in lines.by_ref() {
+                    if line.unwrap().is_empty() {
+                        break;
+                    }
+                }
+                let header = match request_line.split(' ').nth(1).unwrap() {
+                    "/old" => "HTTP/1.1 302 Found\r\nLocation: /images/photo\r\nContent-Length: 0"
+                        .to_string(),
+                    "/images/photo" | "/from_url_test_local.png" => format!(
+                        "HTTP/1.1 200 OK\r\nContent-Type: image/png\r\nContent-Length: {}",
+                        png.len()
+                    ),
+                    _ => "HTTP/1.1 404 Not Found\r\nContent-Length: 0".to_string(),
+                };
+                write!(stream, "{}\r\nConnection: close\r\n\r\n", header).unwrap();
+                if header.contains("200 OK") {
+                    stream.write_all(&png).unwrap();
+                }
+            }
+        });
+
+        let compressor =
+            Compressor::from_url(&format!("http://{}/old", address), &test_dir).unwrap();
+        let compressed = compressor.compress_to_jpg().unwrap();
+        assert_eq!(compressed, test_dir.join("photo.jpg"));
+        assert!(image::open(&compressed).is_ok());
+        assert!(
+            Compressor::from_url(&format!("http://{}/missing.png", address), &test_dir).is_err()
+        );
+
+        // A local file with the name of the downloaded image is not deleted or read.
+        let local_file = PathBuf::from("from_url_test_local.png");
+        fs::write(&local_file, b"local").unwrap();
+        let mut compressor = Compressor::from_url(
+            &format!("http://{}/from_url_test_local.png", address),
+            &test_dir,
+        )
+        .unwrap();
+        compressor.set_delete_source(true);
+        compressor.set_preserve_timestamps(true);
+        let compressed = compressor.compress_to_jpg();
+        assert_eq!(fs::read(&local_file).unwrap(), b"local");
+        fs::remove_file(&local_file).unwrap();
+        assert_eq!(
+            compressed.unwrap(),
+            test_dir.join("from_url_test_local.jpg")
+        );
+        server.join().unwrap();
+        cleanup(test_dir);
+    }
+
+    #[test]
+    fn estimate_test() {
+        let (test_dir, test_images) = setup("estimate_test");
+        let dest_dir = PathBuf::from("estimate_dest_dir");
+        cleanup(&dest_dir);
+        fs::create_dir_all(&dest_dir).unwrap();
+
+        for test_image in &test_images {
+            let mut compressor = Compressor::new(test_image, &dest_dir);
+            compressor.set_factor(Factor::new(70., 0.5));
+            let estimated = compressor.estimate().unwrap();
+            assert!(fs::read_dir(&dest_dir).unwrap().next().is_none());
+            let compressed = compressor.compress_to_jpg().unwrap();
+            assert_eq!(estimated, fs::metadata(&compressed).unwrap().len());
+            fs::remove_file(compressed).unwrap();
+        }
+
+        let txt_path = test_dir.join("estimate_test.txt");
+        fs::write(&txt_path, "Hello, World!").unwrap();
+        let compressor = Compressor::new(&txt_path, &dest_dir);
+        assert!(matches!(
+            compressor.estimate(),
+            Err(CompressError::UnsupportedInput { format: None, .. })
+        ));
+        assert!(!dest_dir.join("estimate_test.txt").exists());
+        cleanup(test_dir);
+        cleanup(dest_dir);
+    }
+
+    #[test]
+    fn sweep_test() {
+        let (test_dir, test_images) = setup("sweep_test");
+        let dest_dir = PathBuf::from("sweep_dest_dir");
+        fs::create_dir_all(&dest_dir).unwrap();
+
+        let compressor = Compressor::new(&test_images[0], &dest_dir);
+        let qualities = [95., 80., 60., 40., 20., 5.];
+        let sizes = compressor.sweep(&qualities).unwrap();
+        assert_eq!(
+            sizes
+                .iter()
+                .map(|&(quality, _)| quality)
+                .collect::<Vec<_>>(),
+            qualities
+        );
+        for pair in sizes.windows(2) {
+            assert!(pair[1].1 <= pair[0].1, "{:?}", sizes);
+        }
+        assert!(fs::read_dir(&dest_dir).unwrap().next().is_none());
+        cleanup(test_dir);
+        cleanup(dest_dir);
+    }
+
+    #[test]
+    fn compress_image_test() {
+        let gradient = image::DynamicImage::ImageRgb8(ImageBuffer::from_fn(200, 100, |x, y| {
+            image::Rgb([x as u8, y as u8, 128])
+        }));
+        let mut compressor =
+            Compressor::new("compress_image_test_unused", "compress_image_test_unused");
+        compressor.set_factor(Factor::new(80., 0.5));
+        let jpg = compressor.compress_image(&gradient).unwrap();
+        assert_eq!(image::guess_format(&jpg).unwrap(), ImageFormat::Jpeg);
+        let decoded = image::load_from_memory(&jpg).unwrap();
+        assert_eq!((decoded.width(), decoded.height()), (100, 50));
+        assert!(!Path::new("compress_image_test_unused").exists());
+    }
+
+    #[test]
+    fn search_quality_for_ssim_test() {
+        let compressor = Compressor::new("unused", "unused");
+        let flat = ImageBuffer::from_fn(128, 128, |x, y| {
+            image::Rgb([(x / 2) as u8, (y / 2) as u8, 128])
+        });
+        let detailed = ImageBuffer::from_fn(128, 128, |_, _| {
+            let v = rand::thread_rng().gen_range(0..256) as u8;
+            image::Rgb([v, v, v])
+        });
+
+        let mut qualities = Vec::new();
+        for img in [flat, detailed] {
+            let (quality, compressed) = compressor
+                .search_quality_for_ssim(
+                    ResizedPixels::Rgb(img.into_vec()),
+                    128,
+                    128,
+                    &ImageMetadata::default(),
+                    0.95,
+                )
+                .unwrap();
+            assert_eq!(image::guess_format(&compressed).unwrap(), ImageFormat::Jpeg);
+            qualities.push(quality);
+        }
+        assert!(qualities[0] < qualities[1]);
+    }
+
+    #[test]
+    fn compress_to_quality_floor_test() {
+        let (test_dir, test_images) = setup("compress_to_quality_floor_test");
+
+        let dest_dir = PathBuf::from("compress_to_quality_floor_dest_dir");
+        fs::create_dir_all(&dest_dir).unwrap();
+        let compressor = Compressor::new(&test_images[1], &dest_dir);
+        let compressed = compressor.compress_to_quality_floor(0.9).unwrap();
+        assert_eq!(
+            image::guess_format(&fs::read(compressed).unwrap()).unwrap(),
+            ImageFormat::Jpeg
+        );
+        cleanup(test_dir);
+        cleanup(dest_dir);
+    }
+
+    #[test]
+    fn compress_error_test() {
+        let (test_dir, test_images) = setup("compress_error_test_dir");
+        let txt_path = test_dir.join("compress_error_test.txt");
+        fs::write(&txt_path, "Hello, World!").unwrap();
+        let corrupt_path = test_dir.join("corrupt.png");
+        fs::write(&corrupt_path, b"\x89PNG\r\n\x1a\nnot a png").unwrap();
+
+        let compressor = Compressor::new(&txt_path, &test_dir);
+        match compressor.compress_to_jpg() {
+            Err(CompressError::UnsupportedInput {
+                path, format: None, ..
+            }) => assert_eq!(path, txt_path),
+            r => panic!("Unexpected result: {:?}", r),
+        }
+
+        let compressor = Compressor::new(&corrupt_path, &test_dir);
+        assert!(matches!(
+            compressor.compress_to_jpg(),
+            Err(CompressError::Decode(_))
+        ));
+
+        let compressor = Compressor::new(&test_images[0], &test_dir);
+        compressor.compress_to_jpg().unwrap();
+        match compressor.compress_to_jpg() {
+            Err(CompressError::AlreadyExists(path)) => {
+                assert_eq!(path, test_dir.join("img_stripe.jpg"))
+            }
+            r => panic!("Unexpected result: {:?}", r),
+        }
+        cleanup(test_dir);
+    }
+
+    #[test]
+    fn tiff_bmp_input_test() {
+        let (test_dir, _) = setup("tiff_bmp_input_test_dir");
+        let img = image::DynamicImage::ImageRgb8(image::RgbImage::from_fn(64, 64, |x, y| {
+            image::Rgb([x as u8 * 4, y as u8 * 4, 128])
+        }));
+        let tiff_path = test_dir.join("tiff_input.tif");
+        let bmp_path = test_dir.join("bmp_input.bmp");
+        img.save(&tiff_path).unwrap();
+        img.save(&bmp_path).unwrap();
+        for path in [&tiff_path, &bmp_path] {
+            let compressed = Compressor::new(path, &test_dir).compress_to_jpg().unwrap();
+            assert_eq!(
+                image::guess_format(&fs::read(compressed).unwrap()).unwrap(),
+                ImageFormat::Jpeg
+            );
+        }
+
+        // Mark the strips of the TIFF image as compressed with CCITT Group 3, which the decoder does not implement.
+        let mut data = fs::read(&tiff_path).unwrap();
+        assert_eq!(&data[..4], b"II*\0");
+        let ifd = u32::from_le_bytes(data[4..8].try_into().unwrap()) as usize;
+        let entries = u16::from_le_bytes(data[ifd..ifd + 2].try_into().unwrap()) as usize;
+        let compression = (0..entries)
+            .map(|i| ifd + 2 + i * 12)
+            .find(|&entry| u16::from_le_bytes(data[entry..entry + 2].try_into().unwrap()) == 259)
+            .unwrap();
+        data[compression + 8..compression + 10].copy_from_slice(&2u16.to_le_bytes());
+        let unsupported_path = test_dir.join("unsupported.tif");
+        fs::write(&unsupported_path, data).unwrap();
+
+        let mut compressor = Compressor::new(&unsupported_path, &test_dir);
+        compressor.set_unreadable_policy(UnreadablePolicy::Fail);
+        match compressor.compress_to_jpg() {
+            Err(
+                e @ CompressError::UnsupportedInput {
+                    format: Some(ImageFormat::Tiff),
+                    ..
+                },
+            ) => {
+                let message = e.to_string();
+                assert!(message.contains("Tiff"), "{}", message);
+                assert!(message.contains("unsupported.tif"), "{}", message);
+            }
+            r => panic!("Unexpected result: {:?}", r),
+        }
+        cleanup(test_dir);
+    }
+
+    #[test]
+    fn compress_to_jpg_test() {
+        let (test_dir, mut test_images) = setup("compress_to_jpg_test");
+
+        let dest_dir = PathBuf::from("compress_to_jpg_dest_dir");
+        fs::create_dir_all(&dest_dir).unwrap();
+
+        for test_image in &test_images {
+            let mut compressor = Compressor::new(test_image, &dest_dir);
+            compressor.set_factor(Factor::new(80., 1.0));
+            compressor.compress_to_jpg().unwrap();
+        }
+        test_images = test_images
+            .iter()
+            .map(|image| dest_dir.join(image.file_name().unwrap()))
+            .collect();
+        for new_image in &test_images {
+            let mut new_test_image = new_image.clone();
+            new_test_image.set_extension("jpg");
+            assert!(new_test_image.is_file());
+        }
+        cleanup(test_dir);
+        cleanup(dest_dir);
+    }
+
+    #[test]
+    fn source_dir_untouched_test() {
+        let (test_dir, test_images) = setup("source_dir_untouched_test");
+        let dest_dir = PathBuf::from("source_dir_untouched_dest_dir");
+        cleanup(&dest_dir);
+        fs::create_dir_all(&dest_dir).unwrap();
+
+        let compressor = Compressor::new(&test_images[0], &dest_dir);
+        compressor.compress_to_jpg().unwrap();
+        let mut source_files = fs::read_dir(&test_dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect::<Vec<_>>();
+        source_files.sort();
+        let mut expected = test_images.clone();
+        expected.sort();
+        assert_eq!(source_files, expected);
+        cleanup(test_dir);
+        cleanup(dest_dir);
+    }
+
+    #[test]
+    fn compress_to_jpg_matches_memory_test() {
+        let (test_dir, test_images) = setup("compress_to_jpg_matches_memory_test");
+        let dest_dir = PathBuf::from("compress_to_jpg_matches_memory_dest_dir");
+        cleanup(&dest_dir);
+        fs::create_dir_all(&dest_dir).unwrap();
+
+        let compressor = Compressor::new(&test_images[0], &dest_dir);
+        let compressed = compressor.compress_to_jpg().unwrap();
+        let source_data = fs::read(&test_images[0]).unwrap();
+        assert_eq!(
+            fs::read(compressed).unwrap(),
+            compressor
+                .compress_from_memory(&source_data, ImageFormat::Png)
+                .unwrap()
+        );
+        cleanup(test_dir);
+        cleanup(dest_dir);
+    }
+
+    #[test]
+    fn compressor_builder_test() {
+        let (test_dir, test_images) = setup("compressor_builder_test");
+        let dest_dir = PathBuf::from("compressor_builder_dest_dir");
+        cleanup(&dest_dir);
+        fs::create_dir_all(&dest_dir).unwrap();
+
+        let compressor = Compressor::builder()
+            .source(&test_images[0])
+            .dest(&dest_dir)
+            .factor(Factor::new(70., 0.5))
+            .overwrite(true)
+            .build()
+            .unwrap();
+        let report = compressor.compress_to_jpg_reported().unwrap();
+        assert_eq!(report.path, dest_dir.join("img_stripe.jpg"));
+        assert_eq!(report.dimensions, (128, 128));
+        compressor.compress_to_jpg().unwrap();
+
+        let missing = Compressor::<&Path, &Path>::builder()
+            .dest(&dest_dir)
+            .build();
+        assert!(matches!(missing, Err(BuildError::MissingSource)));
+        let missing = Compressor::<&Path, &Path>::builder()
+            .source(&test_images[0])
+            .build();
+        assert!(matches!(missing, Err(BuildError::MissingDest)));
+        cleanup(test_dir);
+        cleanup(dest_dir);
+    }
+
+    #[test]
+    fn factor_try_new_test() {
+        assert_eq!(Factor::try_new(100., 1.), Ok(Factor::new(100., 1.)));
+        assert_eq!(Factor::try_new(0., 0.5), Err(FactorError::Quality(0.)));
+        assert_eq!(
+            Factor::try_new(100.5, 0.5),
+            Err(FactorError::Quality(100.5))
+        );
+        assert_eq!(Factor::try_new(80., 0.), Err(FactorError::SizeRatio(0.)));
+        assert_eq!(Factor::try_new(80., 1.5), Err(FactorError::SizeRatio(1.5)));
+        assert!(matches!(
+            Factor::try_new(f32::NAN, 0.5),
+            Err(FactorError::Quality(_))
+        ));
+    }
+
+    #[test]
+    fn compress_tiny_ratio_test() {
+        let mut compressor =
+            Compressor::new("compress_tiny_ratio_source", "compress_tiny_ratio_dest");
+        compressor.set_factor(Factor::new(80., 0.1));
+        let img =
+            image::DynamicImage::ImageRgb8(ImageBuffer::from_pixel(3, 3, image::Rgb([0, 0, 0])));
+        let decoded = image::load_from_memory(&compressor.compress_image(&img).unwrap()).unwrap();
+        assert_eq!((decoded.width(), decoded.height()), (1, 1));
+
+        let wide =
+            image::DynamicImage::ImageRgb8(ImageBuffer::from_pixel(300, 3, image::Rgb([0, 0, 0])));
+        let decoded = image::load_from_memory(&compressor.compress_image(&wide).unwrap()).unwrap();
+        assert_eq!((decoded.width(), decoded.height()), (30, 1));
+
+        let empty = image::DynamicImage::new_rgb8(0, 3);
+        assert!(matches!(
+            compressor.compress_image(&empty),
+            Err(CompressError::Encode(_))
+        ));
+    }
+
+    #[test]
+    fn compress_mismatched_pixels_test() {
+        let compressor = Compressor::new("compress_mismatched_source", "compress_mismatched_dest");
+        let metadata = ImageMetadata::default();
+        let result = compressor.compress(ResizedPixels::Rgb(vec![0; 10]), 4, 4, 80., &metadata);
+        assert!(matches!(result, Err(CompressError::Encode(_))));
+        let result = compressor.compress(
+            ResizedPixels::Rgba(vec![0; 4 * 4 * 3]),
+            4,
+            4,
+            80.,
+            &metadata,
+        );
+        assert!(matches!(result, Err(CompressError::Encode(_))));
+        assert!(compressor
+            .compress(
+                ResizedPixels::Rgba(vec![0; 4 * 4 * 4]),
+                4,
+                4,
+                80.,
+                &metadata
+            )
+            .is_ok());
+    }
+
+    #[test]
+    fn compress_from_memory_test() {
+        let img = image::DynamicImage::ImageRgb8(ImageBuffer::from_fn(128, 128, |x, y| {
+            image::Rgb([x as u8, y as u8, 128u8])
+        }));
+        let mut png_data = Vec::new();
+        img.write_to(&mut io::Cursor::new(&mut png_data), ImageFormat::Png)
+            .unwrap();
+
+        let compressor = Compressor::new(
+            "compress_from_memory_test_source",
+            "compress_from_memory_test_dest",
+        );
+        let jpg_data = compressor
+            .compress_from_memory(&png_data, ImageFormat::Png)
+            .unwrap();
+        assert_eq!(image::guess_format(&jpg_data).unwrap(), ImageFormat::Jpeg);
+        let decoded = image::load_from_memory(&jpg_data).unwrap();
+        assert_eq!(
+            decoded.width(),
+            (128. * Factor::default().size_ratio()) as u32
+        );
+        assert!(!PathBuf::from("compress_from_memory_test_dest").exists());
+    }
+
+    #[test]
+    fn compress_to_webp_test() {
+        let (test_dir, test_images) = setup("compress_to_webp_test");
+
+        let dest_dir = PathBuf::from("compress_to_webp_dest_dir");
+        fs::create_dir_all(&dest_dir).unwrap();
+
+        let mut compressor = Compressor::new(&test_images[0], &dest_dir);
+        compressor.set_factor(Factor::new(75., 0.5));
+        let webp_path = compressor.compress_to_webp().unwrap();
+        assert_eq!(webp_path, dest_dir.join("img_stripe.webp"));
+        let decoded = image::open(&webp_path).unwrap();
+        assert_eq!((decoded.width(), decoded.height()), (128, 128));
+        assert!(compressor.compress_to_webp().is_err());
+        cleanup(test_dir);
+        cleanup(dest_dir);
+    }
+
+    /// Encoder that records the size of the pixels it gets and returns them as they are.
+    struct RecordingEncoder {
+        received: Arc<Mutex<Option<(usize, u32, u32)>>>,
+    }
+
+    impl OutputEncoder for RecordingEncoder {
+        fn encode(
+            &self,
+            pixels: ResizedPixels,
+            width: u32,
+            height: u32,
+            _: Factor,
+        ) -> Result<Vec<u8>, CompressError> {
+            *self.received.lock().unwrap() = Some((pixels.len(), width, height));
+            Ok(pixels.into_rgb())
+        }
+
+        fn extension(&self) -> &str {
+            "raw"
+        }
+    }
+
+    #[test]
+    fn custom_encoder_test() {
+        let (test_dir, test_images) = setup("custom_encoder_test");
+        let dest_dir = PathBuf::from("custom_encoder_dest_dir");
+        cleanup(&dest_dir);
+        fs::create_dir_all(&dest_dir).unwrap();
+
+        let received = Arc::new(Mutex::new(None));
+        let mut compressor = Compressor::new(&test_images[0], &dest_dir);
+        compressor.set_factor(Factor::new(80., 0.5));
+        compressor.set_encoder(Box::new(RecordingEncoder {
+            received: Arc::clone(&received),
+        }));
+        let encoded = compressor.compress_with_encoder().unwrap();
+        assert_eq!(encoded, dest_dir.join("img_stripe.raw"));
+        assert_eq!(*received.lock().unwrap(), Some((128 * 128 * 3, 128, 128)));
+        assert_eq!(fs::metadata(&encoded).unwrap().len(), 128 * 128 * 3);
+        cleanup(test_dir);
+        cleanup(dest_dir);
+    }
+
+    #[test]
+    fn compress_to_png_test() {
+        let test_dir = PathBuf::from("compress_to_png_test");
+        cleanup(&test_dir);
+        fs::create_dir_all(&test_dir).unwrap();
+        let rgba_path = test_dir.join("img_rgba.png");
+        ImageBuffer::from_fn(64, 64, |x, _| image::Rgba([255u8, 0, 0, (x * 4) as u8]))
+            .save(&rgba_path)
+            .unwrap();
+
+        let dest_dir = PathBuf::from("compress_to_png_dest_dir");
+        fs::create_dir_all(&dest_dir).unwrap();
+
+        let mut compressor = Compressor::new(&rgba_path, &dest_dir);
+        compressor.set_factor(Factor::new(80., 0.5));
+        compressor.set_png_compression(CompressionType::Best);
+        let png_path = compressor.compress_to_png().unwrap();
+        let decoded = image::open(&png_path).unwrap();
+        assert!(decoded.color().has_alpha());
+        assert_eq!((decoded.width(), decoded.height()), (32, 32));
+        let alphas = decoded
+            .to_rgba8()
+            .pixels()
+            .map(|p| p[3])
+            .collect::<Vec<_>>();
+        assert!(alphas.iter().any(|a| *a < 128));
+        assert!(alphas.iter().any(|a| *a > 128));
+        cleanup(test_dir);
+        cleanup(dest_dir);
+    }
+
+    #[test]
+    fn compress_16bit_to_png_test() {
+        let test_dir = PathBuf::from("compress_16bit_to_png_test");
+        cleanup(&test_dir);
+        fs::create_dir_all(&test_dir).unwrap();
+        let source = test_dir.join("img_16bit.png");
+        ImageBuffer::from_fn(64, 64, |x, y| {
+            image::Rgb([(x * 1000) as u16, (y * 1000) as u16, 1])
+        })
+        .save(&source)
+        .unwrap();
+
+        let dest_dir = PathBuf::from("compress_16bit_to_png_dest_dir");
+        fs::create_dir_all(&dest_dir).unwrap();
+        let mut compressor = Compressor::new(&source, &dest_dir);
+        compressor.set_factor(Factor::new(80., 1.));
+        let decoded = image::open(compressor.compress_to_png().unwrap()).unwrap();
+        assert_eq!(decoded.color(), image::ColorType::Rgb16);
+        assert_eq!(decoded.into_rgb16().get_pixel(3, 5).0, [3000, 5000, 1]);
+
+        let decoded = image::open(compressor.compress_to_jpg().unwrap()).unwrap();
+        assert_eq!(decoded.color(), image::ColorType::Rgb8);
+        cleanup(test_dir);
+        cleanup(dest_dir);
+    }
+
+    #[test]
+    fn resize_opaque_test() {
+        let compressor = Compressor::new("resize_opaque_test_source", "resize_opaque_test_dest");
+        let img = image::DynamicImage::ImageRgb8(ImageBuffer::from_pixel(
+            16,
+            16,
+            image::Rgb([10u8, 20, 30]),
+        ));
+        let (pixels, width, height) = compressor.resize(img, 0.5).unwrap();
+        assert_eq!((width, height), (8, 8));
+        match pixels {
+            ResizedPixels::Rgb(p) => assert_eq!(p.len(), 8 * 8 * 3),
+            _ => panic!("Opaque image must be resized to RGB"),
+        }
+    }
+
+    #[test]
+    fn resize_transparent_test() {
+        let compressor = Compressor::new(
+            "resize_transparent_test_source",
+            "resize_transparent_test_dest",
+        );
+        let img = image::DynamicImage::ImageRgba8(ImageBuffer::from_pixel(
+            16,
+            16,
+            image::Rgba([0u8, 0, 0, 0]),
+        ));
+        let (pixels, _, _) = compressor.resize(img, 0.5).unwrap();
+        match &pixels {
+            ResizedPixels::Rgba(p) => assert_eq!(p.len(), 8 * 8 * 4),
+            _ => panic!("Transparent image must be resized to RGBA"),
+        }
+        assert!(pixels.into_rgb().iter().all(|c| *c == 255));
+    }
+
+    #[test]
+    fn resize_max_dimensions_test() {
+        let mut compressor = Compressor::new(
+            "resize_max_dimensions_test_source",
+            "resize_max_dimensions_test_dest",
+        );
+        compressor.set_max_dimensions(100, 100);
+        let landscape = image::DynamicImage::new_rgb8(400, 200);
+        let portrait = image::DynamicImage::new_rgb8(200, 400);
+        let small = image::DynamicImage::new_rgb8(50, 20);
+        let (_, width, height) = compressor.resize(landscape, 0.8).unwrap();
+        assert_eq!((width, height), (100, 50));
+        let (_, width, height) = compressor.resize(portrait, 0.8).unwrap();
+        assert_eq!((width, height), (50, 100));
+        let (_, width, height) = compressor.resize(small, 0.8).unwrap();
+        assert_eq!((width, height), (50, 20));
+    }
+
+    #[test]
+    fn crop_aspect_test() {
+        let mut compressor = Compressor::new("crop_aspect_test_source", "crop_aspect_test_dest");
+        compressor.set_crop_aspect(16, 9);
+        let square = image::DynamicImage::new_rgb8(256, 256);
+        let (_, width, height) = compressor.resize(square.clone(), 1.0).unwrap();
+        assert_eq!((width, height), (256, 144));
+        let (_, width, height) = compressor.resize(square, 0.5).unwrap();
+        assert_eq!((width, height), (128, 72));
+
+        compressor.set_crop_aspect(1, 1);
+        let wide = image::DynamicImage::new_rgb8(400, 200);
+        let (_, width, height) = compressor.resize(wide, 1.0).unwrap();
+        assert_eq!((width, height), (200, 200));
+    }
+
+    #[test]
+    fn min_dimensions_test() {
+        let mut compressor =
+            Compressor::new("min_dimensions_test_source", "min_dimensions_test_dest");
+        compressor.set_min_dimensions(64, 64);
+        let small = image::DynamicImage::new_rgb8(100, 80);
+        let (_, width, height) = compressor.resize(small.clone(), 0.1).unwrap();
+        assert_eq!((width, height), (80, 64));
+        let (_, width, height) = compressor.resize(small, 0.9).unwrap();
+        assert_eq!((width, height), (90, 72));
+
+        let tiny = image::DynamicImage::new_rgb8(48, 200);
+        let (_, width, height) = compressor.resize(tiny, 0.1).unwrap();
+        assert_eq!((width, height), (48, 200));
+
+        let odd = image::DynamicImage::new_rgb8(333, 777);
+        let (_, width, height) = compressor.resize(odd, 0.01).unwrap();
+        assert_eq!(width, 64);
+        assert!(height >= 64);
+
+        // The minimum also applies to the maximum dimensions, and wins over them.
+        compressor.set_max_dimensions(100, 100);
+        let wide = image::DynamicImage::new_rgb8(1000, 200);
+        let (_, width, height) = compressor.resize(wide, 1.).unwrap();
+        assert_eq!((width, height), (320, 64));
+        let photo = image::DynamicImage::new_rgb8(400, 300);
+        let (_, width, height) = compressor.resize(photo, 1.).unwrap();
+        assert_eq!((width, height), (100, 75));
+    }
+
+    #[test]
+    fn dpi_test() {
+        let (test_dir, test_images) = setup("dpi_test");
+        // Unit, horizontal density, and vertical density in the JFIF header.
+        let density = |data: &[u8]| {
+            assert_eq!(&data[6..11], b"JFIF\0");
+            (
+                data[13],
+                u16::from_be_bytes([data[14], data[15]]),
+                u16::from_be_bytes([data[16], data[17]]),
+            )
+        };
+
+        let mut compressor = Compressor::new(&test_images[0], &test_dir);
+        let mut buffer = Vec::new();
+        compressor.compress_to_writer(&mut buffer).unwrap();
+        assert_eq!(density(&buffer), (1, 72, 72));
+
+        compressor.set_dpi(300, 200);
+        let compressed = compressor.compress_to_jpg().unwrap();
+        assert_eq!(density(&fs::read(&compressed).unwrap()), (1, 300, 200));
+
+        // The density of the source is kept when the DPI is not set.
+        let dest_dir = test_dir.join("dest");
+        fs::create_dir(&dest_dir).unwrap();
+        let compressor = Compressor::new(&compressed, &dest_dir);
+        let recompressed = compressor.compress_to_jpg().unwrap();
+        assert_eq!(density(&fs::read(recompressed).unwrap()), (1, 300, 200));
+
+        let png = test_dir.join("dense.png");
+        let mut data = Vec::new();
+        image::DynamicImage::new_rgb8(16, 16)
+            .write_to(&mut io::Cursor::new(&mut data), ImageFormat::Png)
+            .unwrap();
+        // Insert a pHYs chunk of 5906 pixels per meter, which is 150 DPI, after the IHDR chunk.
+        let mut phys = b"pHYs".to_vec();
+        phys.extend(5906u32.to_be_bytes());
+        phys.extend(5906u32.to_be_bytes());
+        phys.push(1);
+        let mut chunk = 9u32.to_be_bytes().to_vec();
+        chunk.extend(&phys);
+        chunk.extend([0; 4]);
+        data.splice(33..33, chunk);
+        fs::write(&png, data).unwrap();
+        assert_eq!(
+            read_density(&mut io::Cursor::new(fs::read(&png).unwrap()), None),
+            Some((150, 150))
+        );
+        cleanup(test_dir);
+    }
+
+    #[test]
+    fn watermark_test() {
+        let (test_dir, _) = setup("watermark_test");
+        let source = test_dir.join("black.png");
+        image::RgbImage::new(200, 100).save(&source).unwrap();
+        let mark = test_dir.join("mark.png");
+        image::RgbaImage::from_pixel(40, 20, image::Rgba([255, 0, 0, 255]))
+            .save(&mark)
+            .unwrap();
+
+        let pixel = |compressor: &Compressor<&PathBuf, &PathBuf>, x, y| {
+            let mut buffer = Vec::new();
+            compressor.compress_to_writer(&mut buffer).unwrap();
+            let img = image::load_from_memory(&buffer).unwrap().into_rgb8();
+            assert_eq!(img.dimensions(), (160, 80));
+            img.get_pixel(x, y).0
+        };
+        let mut compressor = Compressor::new(&source, &test_dir);
+        assert!(pixel(&compressor, 150, 70)[0] < 16);
+
+        compressor
+            .set_watermark(&mark, WatermarkPosition::BottomRight, 0.5)
+            .unwrap();
+        // The watermark is 40x20 in the bottom right corner of the 160x80 image with a margin of 3 pixels.
+        let red = pixel(&compressor, 150, 70);
+        assert!((100..160).contains(&red[0]), "{:?}", red);
+        assert!(pixel(&compressor, 10, 10)[0] < 16);
+
+        compressor
+            .set_watermark(&mark, WatermarkPosition::Center, 1.)
+            .unwrap();
+        assert!(pixel(&compressor, 80, 40)[0] > 200);
+        assert!(pixel(&compressor, 150, 70)[0] < 16);
+
+        compressor
+            .set_watermark(&mark, WatermarkPosition::Tiled, 1.)
+            .unwrap();
+        assert!(pixel(&compressor, 10, 10)[0] > 200);
+        assert!(pixel(&compressor, 70, 40)[0] > 200);
+
+        assert!(compressor
+            .set_watermark(test_dir.join("missing.png"), WatermarkPosition::Center, 1.)
+            .is_err());
+
+        // The height of a tall watermark on a wide image does not overflow.
+        let tall_mark = test_dir.join("tall_mark.png");
+        image::RgbaImage::from_pixel(1, 70_000, image::Rgba([255, 0, 0, 255]))
+            .save(&tall_mark)
+            .unwrap();
+        compressor
+            .set_watermark(&tall_mark, WatermarkPosition::Center, 1.)
+            .unwrap();
+        let marked = compressor.draw_watermark(image::DynamicImage::new_rgb8(280_000, 2));
+        assert_eq!((marked.width(), marked.height()), (280_000, 2));
+        assert!(marked.to_rgb8().get_pixel(140_000, 1)[0] > 200);
+        cleanup(test_dir);
+    }
+
+    #[test]
+    fn filter_type_test() {
+        let (test_dir, test_images) = setup("filter_type_test");
+        let data = fs::read(&test_images[0]).unwrap();
+
+        let mut compressor = Compressor::new(&test_images[0], &test_dir);
+        compressor.set_factor(Factor::new(80., 0.3));
+        compressor.set_filter_type(FilterType::Nearest);
+        let nearest = compressor
+            .compress_from_memory(&data, ImageFormat::Png)
+            .unwrap();
+        compressor.set_filter_type(FilterType::Lanczos3);
+        let lanczos = compressor
+            .compress_from_memory(&data, ImageFormat::Png)
+            .unwrap();
+        assert_ne!(nearest, lanczos);
+        cleanup(test_dir);
+    }
+
+    #[test]
+    fn gif_mode_test() {
+        let (test_dir, _) = setup("gif_mode_test");
+        let source = test_dir.join("animated.gif");
+        {
+            let mut encoder = GifEncoder::new(File::create(&source).unwrap());
+            let frames = (0..3u8).map(|i| {
+                let buffer = image::RgbaImage::from_pixel(64, 48, image::Rgba([i * 80, 0, 0, 255]));
+                Frame::from_parts(buffer, 0, 0, image::Delay::from_numer_denom_ms(100, 1))
+            });
+            encoder.encode_frames(frames).unwrap();
+        }
+        let dest_dir = test_dir.join("dest");
+        fs::create_dir(&dest_dir).unwrap();
+
+        let compressor = Compressor::new(&source, &dest_dir);
+        let first_frame = compressor.compress_to_jpg().unwrap();
+        assert_eq!(first_frame, dest_dir.join("animated.jpg"));
+
+        let mut compressor = Compressor::new(&source, &dest_dir);
+        compressor.set_gif_mode(GifMode::Animated);
+        let report = compressor.compress_to_jpg_reported().unwrap();
+        assert_eq!(report.path, dest_dir.join("animated.gif"));
+        assert_eq!(report.original_dimensions, (64, 48));
+        let frames = decode_gif_frames(BufReader::new(File::open(&report.path).unwrap())).unwrap();
+        assert_eq!(frames.len(), 3);
+        assert_eq!(frames[0].buffer().dimensions(), report.dimensions);
+        assert!(report.dimensions.0 < 64);
+
+        // The output name template, the output extension and the watermark apply to every frame.
+        let mark = test_dir.join("mark.png");
+        image::RgbaImage::from_pixel(8, 8, image::Rgba([0, 255, 0, 255]))
+            .save(&mark)
+            .unwrap();
+        compressor.set_output_name_template("{stem}_{width}.jpg".to_string());
+        compressor.set_output_extension("GIF".to_string());
+        compressor
+            .set_watermark(&mark, WatermarkPosition::Center, 1.)
+            .unwrap();
+        let report = compressor.compress_to_jpg_reported().unwrap();
+        let expected = format!("animated_{}.GIF", report.dimensions.0);
+        assert_eq!(report.path, dest_dir.join(expected));
+        let frames = decode_gif_frames(BufReader::new(File::open(&report.path).unwrap())).unwrap();
+        assert_eq!(frames.len(), 3);
+        let (width, height) = report.dimensions;
+        for frame in &frames {
+            let center = frame.buffer().get_pixel(width / 2, height / 2).0;
+            assert!(center[1] > 200 && center[0] < 64, "{:?}", center);
+        }
+        assert!(matches!(
+            compressor.compress_to_jpg_reported(),
+            Err(CompressError::AlreadyExists(_))
+        ));
+        cleanup(test_dir);
+    }
+
+    #[test]
+    fn filter_for_size_test() {
+        let mut compressor =
+            Compressor::new("filter_for_size_test_source", "filter_for_size_test_dest");
+        let avatar = image::DynamicImage::new_rgb8(64, 64);
+        let photo = image::DynamicImage::new_rgb8(512, 512);
+        assert_eq!(compressor.filter_for(&avatar), FilterType::Triangle);
+        assert_eq!(compressor.filter_for(&photo), FilterType::Triangle);
+
+        compressor.set_filter_for_size(FilterType::Nearest, FilterType::Lanczos3, 256 * 256);
+        assert_eq!(compressor.filter_for(&avatar), FilterType::Nearest);
+        assert_eq!(compressor.filter_for(&photo), FilterType::Lanczos3);
+    }
+
+    /// Create a jpg image with an EXIF segment containing the given orientation.
+    fn write_oriented_jpg<T: AsRef<Path>>(path: T, width: u32, height: u32, orientation: u16) {
+        let mut tiff = b"II*\0\x08\0\0\0\x01\0".to_vec();
+        tiff.extend_from_slice(&[0x12, 0x01, 0x03, 0x00, 0x01, 0x00, 0x00, 0x00]);
+        tiff.extend_from_slice(&orientation.to_le_bytes());
+        tiff.extend_from_slice(&[0, 0, 0, 0, 0, 0]);
+        write_exif_jpg(path, width, height, &tiff);
+    }
+
+    /// Write a jpg image with the raw TIFF data as its EXIF block.
+    fn write_exif_jpg<T: AsRef<Path>>(path: T, width: u32, height: u32, tiff: &[u8]) {
+        let mut jpg = Vec::new();
+        image::DynamicImage::new_rgb8(width, height)
+            .write_to(&mut io::Cursor::new(&mut jpg), ImageFormat::Jpeg)
+            .unwrap();
+
+        let mut segment = vec![0xFF, 0xE1];
+        segment.extend_from_slice(&((tiff.len() + 8) as u16).to_be_bytes());
+        segment.extend_from_slice(b"Exif\0\0");
+        segment.extend_from_slice(tiff);
+
+        let mut data = jpg[..2].to_vec();
+        data.extend_from_slice(&segment);
+        data.extend_from_slice(&jpg[2..]);
+        fs::write(path, data).unwrap();
+    }
+
+    #[test]
+    fn auto_orient_test() {
+        let test_dir = PathBuf::from("auto_orient_test");
+        cleanup(&test_dir);
+        fs::create_dir_all(&test_dir).unwrap();
+        let source = test_dir.join("img_oriented.jpg");
+        write_oriented_jpg(&source, 200, 100, 6);
+
+        let dest_dir = PathBuf::from("auto_orient_dest_dir");
+        fs::create_dir_all(&dest_dir).unwrap();
+
+        let mut compressor = Compressor::new(&source, &dest_dir);
+        compressor.set_factor(Factor::new(80., 1.0));
+        let oriented = compressor.compress_to_jpg().unwrap();
+        assert_eq!(image::image_dimensions(&oriented).unwrap(), (100, 200));
+        fs::remove_file(&oriented).unwrap();
+
+        compressor.set_auto_orient(false);
+        let unoriented = compressor.compress_to_jpg().unwrap();
+        assert_eq!(image::image_dimensions(unoriented).unwrap(), (200, 100));
+        cleanup(test_dir);
+        cleanup(dest_dir);
+    }
+
+    #[test]
+    fn preserve_metadata_test() {
+        let test_dir = PathBuf::from("preserve_metadata_test");
+        cleanup(&test_dir);
+        fs::create_dir_all(&test_dir).unwrap();
+        let source = test_dir.join("img_exif.jpg");
+        // IFD0 with the DateTime and the Orientation tags.
+        let mut tiff = b"II*\0\x08\0\0\0\x02\0".to_vec();
+        tiff.extend_from_slice(&[
+            0x12, 0x01, 0x03, 0x00, 0x01, 0x00, 0x00, 0x00, 0x06, 0x00, 0x00, 0x00,
+        ]);
+        tiff.extend_from_slice(&[
+            0x32, 0x01, 0x02, 0x00, 0x14, 0x00, 0x00, 0x00, 0x26, 0x00, 0x00, 0x00,
+        ]);
+        tiff.extend_from_slice(&[0, 0, 0, 0]);
+        tiff.extend_from_slice(b"2020:01:02 03:04:05\0");
+        write_exif_jpg(&source, 200, 100, &tiff);
+
+        let dest_dir = PathBuf::from("preserve_metadata_dest_dir");
+        fs::create_dir_all(&dest_dir).unwrap();
+        let mut compressor = Compressor::new(&source, &dest_dir);
+        compressor.set_factor(Factor::new(80., 1.0));
+        let stripped = compressor.compress_to_jpg().unwrap();
+        assert!(read_exif(&mut BufReader::new(File::open(&stripped).unwrap())).is_none());
+        fs::remove_file(&stripped).unwrap();
+
+        compressor.set_preserve_metadata(true);
+        let preserved = compressor.compress_to_jpg().unwrap();
+        assert_eq!(image::image_dimensions(&preserved).unwrap(), (100, 200));
+        let exif = read_exif(&mut BufReader::new(File::open(&preserved).unwrap())).unwrap();
+        let date = exif
+            .get_field(exif::Tag::DateTime, exif::In::PRIMARY)
+            .unwrap()
+            .display_value()
+            .to_string();
+        assert_eq!(date, "2020-01-02 03:04:05");
+        assert_eq!(read_orientation(Some(&exif)), 1);
+        cleanup(test_dir);
+        cleanup(dest_dir);
+    }
+
+    #[test]
+    fn strip_all_metadata_test() {
+        let test_dir = PathBuf::from("strip_all_metadata_test");
+        cleanup(&test_dir);
+        fs::create_dir_all(&test_dir).unwrap();
+        let source = test_dir.join("img_gps.jpg");
+        // IFD0 with a pointer to the GPS IFD, which has the GPSLatitudeRef tag.
+        let mut tiff = b"II*\0\x08\0\0\0\x01\0".to_vec();
+        tiff.extend_from_slice(&[
+            0x25, 0x88, 0x04, 0x00, 0x01, 0x00, 0x00, 0x00, 0x1A, 0x00, 0x00, 0x00,
+        ]);
+        tiff.extend_from_slice(&[0, 0, 0, 0]);
+        tiff.extend_from_slice(b"\x01\0");
+        tiff.extend_from_slice(&[
+            0x01, 0x00, 0x02, 0x00, 0x02, 0x00, 0x00, 0x00, b'N', 0x00, 0x00, 0x00,
+        ]);
+        tiff.extend_from_slice(&[0, 0, 0, 0]);
+        write_exif_jpg(&source, 64, 64, &tiff);
+        assert!(crate::metadata::contains_metadata(&source).unwrap());
+
+        let dest_dir = PathBuf::from("strip_all_metadata_dest_dir");
+        fs::create_dir_all(&dest_dir).unwrap();
+        let mut compressor = Compressor::new(&source, &dest_dir);
+        compressor.set_preserve_metadata(true);
+        let preserved = compressor.compress_to_jpg().unwrap();
+        assert!(crate::metadata::contains_metadata(&preserved).unwrap());
+        fs::remove_file(&preserved).unwrap();
+
+        compressor.set_strip_all_metadata(true);
+        let stripped = compressor.compress_to_jpg().unwrap();
+        assert!(!crate::metadata::contains_metadata(stripped).unwrap());
+        cleanup(test_dir);
+        cleanup(dest_dir);
+    }
+
+    #[test]
+    fn preserve_icc_test() {
+        let test_dir = PathBuf::from("preserve_icc_test");
+        cleanup(&test_dir);
+        fs::create_dir_all(&test_dir).unwrap();
+        let source = test_dir.join("img_icc.jpg");
+        let icc_profile = b"preserve_icc_test fake profile data".to_vec();
+        let mut comp = Compress::new(ColorSpace::JCS_RGB);
+        comp.set_size(16, 16);
+        let mut comp = comp.start_compress(Vec::new()).unwrap();
+        for marker in icc_markers(&icc_profile) {
+            comp.write_marker(Marker::APP(2), &marker);
+        }
+        comp.write_scanlines(&[128u8; 16 * 16 * 3]).unwrap();
+        fs::write(&source, comp.finish().unwrap()).unwrap();
+
+        let dest_dir = PathBuf::from("preserve_icc_dest_dir");
+        fs::create_dir_all(&dest_dir).unwrap();
+
+        let contains_profile = |data: &[u8]| {
+            data.windows(icc_profile.len())
+                .any(|window| window == icc_profile.as_slice())
+        };
+        let mut compressor = Compressor::new(&source, &dest_dir);
+        let preserved = compressor.compress_to_jpg().unwrap();
+        let preserved_data = fs::read(&preserved).unwrap();
+        assert!(contains_profile(&preserved_data));
+        assert!(preserved_data.windows(12).any(|w| w == b"ICC_PROFILE\0"));
+        fs::remove_file(&preserved).unwrap();
+
+        compressor.set_preserve_icc(false);
+        let stripped = compressor.compress_to_jpg().unwrap();
+        assert!(!contains_profile(&fs::read(stripped).unwrap()));
+        cleanup(test_dir);
+        cleanup(dest_dir);
+    }
+
+    #[test]
+    fn subsampling_test() {
+        let (test_dir, test_images) = setup("subsampling_test");
+        let data = fs::read(&test_images[0]).unwrap();
+
+        let mut compressor = Compressor::new(&test_images[0], &test_dir);
+        let sub420 = compressor
+            .compress_from_memory(&data, ImageFormat::Png)
+            .unwrap();
+        compressor.set_subsampling(Subsampling::Sub444);
+        let sub444 = compressor
+            .compress_from_memory(&data, ImageFormat::Png)
+            .unwrap();
+        assert_ne!(sub420, sub444);
+        assert!(image::load_from_memory(&sub444).is_ok());
+        cleanup(test_dir);
+    }
+
+    #[test]
+    fn smoothing_test() {
+        let (test_dir, test_images) = setup("smoothing_test");
+        let data = fs::read(&test_images[1]).unwrap();
+
+        let mut compressor = Compressor::new(&test_images[1], &test_dir);
+        let sharp = compressor
+            .compress_from_memory(&data, ImageFormat::Gif)
+            .unwrap();
+        compressor.set_smoothing(50);
+        let smoothed = compressor
+            .compress_from_memory(&data, ImageFormat::Gif)
+            .unwrap();
+        assert_ne!(sharp, smoothed);
+        assert!(image::load_from_memory(&smoothed).is_ok());
+        cleanup(test_dir);
+    }
+
+    #[test]
+    fn trellis_test() {
+        let (test_dir, test_images) = setup("trellis_test");
+        let data = fs::read(&test_images[1]).unwrap();
+        let has_marker = |data: &[u8], marker: u8| data.windows(2).any(|w| w == [0xFF, marker]);
+        let quant_tables = |data: &[u8]| {
+            let mut tables = Vec::new();
+            let mut i = 2;
+            while data[i] == 0xFF && data[i + 1] != 0xDA {
+                let len = u16::from_be_bytes([data[i + 2], data[i + 3]]) as usize;
+                if data[i + 1] == 0xDB {
+                    tables.extend_from_slice(&data[i + 4..i + 2 + len]);
+                }
+                i += 2 + len;
+            }
+            tables
+        };
+
+        let mut compressor = Compressor::new(&test_images[1], &test_dir);
+        let trellis = compressor
+            .compress_from_memory(&data, ImageFormat::Gif)
+            .unwrap();
+        compressor.set_trellis(false);
+        let plain = compressor
+            .compress_from_memory(&data, ImageFormat::Gif)
+            .unwrap();
+        assert_ne!(trellis, plain);
+        // Only the trellis quantization is turned off, so the quantization tables are the same.
+        assert_eq!(quant_tables(&plain), quant_tables(&trellis));
+        assert!(
+            plain.len() < trellis.len() * 5 / 4,
+            "{} bytes without trellis, {} bytes with it",
+            plain.len(),
+            trellis.len()
+        );
+        assert!(has_marker(&plain, 0xC2));
+        assert!(image::load_from_memory(&plain).is_ok());
+        cleanup(test_dir);
+    }
+
+    #[test]
+    fn min_quality_test() {
+        let (test_dir, test_images) = setup("min_quality_test");
+        let dest_dir = PathBuf::from("min_quality_dest_dir");
+        cleanup(&dest_dir);
+        fs::create_dir_all(&dest_dir).unwrap();
+
+        let mut compressor = Compressor::new(&test_images[0], &dest_dir);
+        compressor.set_cal_func(|_, _, _| Factor::new(5., 1.));
+        compressor.set_output_name_template("{stem}_q{quality}.jpg".to_string());
+        compressor.set_min_quality(40.);
+        let img = image::DynamicImage::new_rgb8(8, 8);
+        assert_eq!(compressor.effective_factor(&img, 0).quality(), 40.);
+        assert_eq!(
+            compressor.compress_to_jpg().unwrap(),
+            dest_dir.join("img_stripe_q40.jpg")
+        );
+
+        compressor.set_min_quality(2.);
+        assert_eq!(compressor.effective_factor(&img, 0).quality(), 5.);
+
+        // A flat image looks the same at any quality, so the search would go down to `min` without the minimum quality.
+        compressor.set_min_quality(60.);
+        compressor.set_quality_range(5., 90.).unwrap();
+        let (quality, _) = compressor
+            .compress_in_quality_range(
+                ResizedPixels::from_image(img, false),
+                8,
+                8,
+                60.,
+                &ImageMetadata::default(),
+            )
+            .unwrap();
+        assert!((60. ..=90.).contains(&quality), "{}", quality);
+        cleanup(test_dir);
+        cleanup(dest_dir);
+    }
+
+    #[test]
+    fn quality_range_test() {
+        let smooth = image::DynamicImage::ImageRgb8(image::RgbImage::from_fn(256, 256, |x, y| {
+            image::Rgb([x as u8, y as u8, (x + y) as u8 / 2])
+        }));
+        let detailed =
+            image::DynamicImage::ImageRgb8(image::RgbImage::from_fn(256, 256, |x, y| {
+                let v = (x.wrapping_mul(7919) ^ y.wrapping_mul(104729)) as u8;
+                image::Rgb([v, v.wrapping_mul(3), v.wrapping_add(x as u8)])
+            }));
+
+        let mut compressor = Compressor::new("unused", "unused");
+        compressor.set_quality_range(95., 30.).unwrap();
+        assert_eq!(compressor.quality_range, Some((30., 95.)));
+        let metadata = ImageMetadata::default();
+        for img in [smooth, detailed] {
+            let pixels = ResizedPixels::from_image(img.clone(), false);
+            let highest = compressor
+                .compress(pixels.clone(), 256, 256, 95., &metadata)
+                .unwrap();
+            let midpoint = compressor
+                .compress(pixels.clone(), 256, 256, 62.5, &metadata)
+                .unwrap();
+            let (quality, ranged) = compressor
+                .compress_in_quality_range(pixels, 256, 256, 80., &metadata)
+                .unwrap();
+
+            // A quality below `max` is chosen, and the file is never larger than with the midpoint quality.
+            assert!((30. ..95.).contains(&quality), "{}", quality);
+            assert!(ranged.len() < highest.len());
+            assert!(
+                ranged.len() <= midpoint.len(),
+                "{} bytes with the range, {} bytes at the midpoint",
+                ranged.len(),
+                midpoint.len()
+            );
+            if quality != 62.5 {
+                let reference = img.into_luma8();
+                let ssim_of = |data: &[u8]| {
+                    ssim(
+                        &reference,
+                        &image::load_from_memory(data).unwrap().into_luma8(),
+                    )
+                };
+                assert!(ssim_of(&ranged) >= ssim_of(&highest) - QUALITY_RANGE_SSIM_TOLERANCE);
+            }
+        }
+
+        assert_eq!(
+            compressor.set_quality_range(0., 50.),
+            Err(FactorError::Quality(0.))
+        );
+        assert_eq!(compressor.quality_range, Some((30., 95.)));
+    }
+
+    #[test]
+    fn baseline_test() {
+        let (test_dir, test_images) = setup("baseline_test");
+        let data = fs::read(&test_images[1]).unwrap();
+        let has_marker = |data: &[u8], marker: u8| data.windows(2).any(|w| w == [0xFF, marker]);
+
+        let mut compressor = Compressor::new(&test_images[1], &test_dir);
+        let progressive = compressor
+            .compress_from_memory(&data, ImageFormat::Gif)
+            .unwrap();
+        assert!(has_marker(&progressive, 0xC2));
+        compressor.set_progressive(false);
+        let baseline = compressor
+            .compress_from_memory(&data, ImageFormat::Gif)
+            .unwrap();
+        assert!(!has_marker(&baseline, 0xC2));
+        let decoded = image::load_from_memory_with_format(&baseline, ImageFormat::Jpeg).unwrap();
+        assert_eq!(
+            decoded.width(),
+            (256. * Factor::default().size_ratio()) as u32
+        );
+        cleanup(test_dir);
+    }
+
+    #[test]
+    fn grayscale_test() {
+        let (test_dir, test_images) = setup("grayscale_test");
+        let data = fs::read(&test_images[1]).unwrap();
+
+        let mut compressor = Compressor::new(&test_images[1], &test_dir);
+        let color = compressor
+            .compress_from_memory(&data, ImageFormat::Gif)
+            .unwrap();
+        compressor.set_grayscale(true);
+        let grayscale = compressor
+            .compress_from_memory(&data, ImageFormat::Gif)
+            .unwrap();
+        assert!(grayscale.len() < color.len());
+        let decoded = image::load_from_memory_with_format(&grayscale, ImageFormat::Jpeg).unwrap();
+        assert_eq!(decoded.color(), image::ColorType::L8);
+        cleanup(test_dir);
+    }
+
+    #[test]
+    fn compress_to_jpg_with_cal_func_test() {
+        let (test_dir, test_images) = setup("compress_to_jpg_with_cal_func_test");
+
+        let default_dest_dir = PathBuf::from("compress_to_jpg_with_cal_func_default_dest_dir");
+        let cal_func_dest_dir = PathBuf::from("compress_to_jpg_with_cal_func_dest_dir");
+        fs::create_dir_all(&default_dest_dir).unwrap();
+        fs::create_dir_all(&cal_func_dest_dir).unwrap();
+
+        let mut compressor = Compressor::new(&test_images[1], &default_dest_dir);
+        compressor.set_factor(Factor::new(90., 1.0));
+        let default_file = compressor.compress_to_jpg().unwrap();
+
+        let mut compressor = Compressor::new(&test_images[1], &cal_func_dest_dir);
+        compressor.set_factor(Factor::new(90., 1.0));
+        compressor.set_cal_func(|width, height, file_size| {
+            assert!(file_size > 0);
+            if width * height > 100 * 100 {
+                Factor::new(10., 1.0)
+            } else {
+                Factor::new(90., 1.0)
+            }
+        });
+        let cal_func_file = compressor.compress_to_jpg().unwrap();
+
+        let default_size = fs::metadata(default_file).unwrap().len();
+        let cal_func_size = fs::metadata(cal_func_file).unwrap().len();
+        assert!(cal_func_size < default_size);
+        cleanup(test_dir);
+        cleanup(default_dest_dir);
+        cleanup(cal_func_dest_dir);
+    }
+
+    #[test]
+    fn compress_to_target_size_test() {
+        let (test_dir, test_images) = setup("compress_to_target_size_test");
+
+        let dest_dir = PathBuf::from("compress_to_target_size_dest_dir");
+        fs::create_dir_all(&dest_dir).unwrap();
+
+        let mut compressor = Compressor::new(&test_images[1], &dest_dir);
+        compressor.set_factor(Factor::new(80., 1.0));
+        assert!(compressor.compress_to_target_size(100).is_err());
+        let target_file = compressor.compress_to_target_size(40_000).unwrap();
+        assert!(fs::metadata(target_file).unwrap().len() <= 40_000);
+        cleanup(test_dir);
+        cleanup(dest_dir);
+    }
+
+    #[test]
+    fn keep_if_larger_test() {
+        let test_dir = PathBuf::from("keep_if_larger_test");
+        cleanup(&test_dir);
+        fs::create_dir_all(&test_dir).unwrap();
+        let icon_path = test_dir.join("icon.png");
+        ImageBuffer::from_pixel(8, 8, image::Rgb([255u8, 0, 0]))
+            .save(&icon_path)
+            .unwrap();
+        let icon_data = fs::read(&icon_path).unwrap();
+
+        let dest_dir = PathBuf::from("keep_if_larger_dest_dir");
+        cleanup(&dest_dir);
+        fs::create_dir_all(&dest_dir).unwrap();
+        let mut compressor = Compressor::new(&icon_path, &dest_dir);
+        compressor.set_factor(Factor::new(80., 1.0));
+        let report = compressor.compress_to_jpg_reported().unwrap();
+        assert!(!report.kept_original);
+        assert!(report.compressed_size > icon_data.len() as u64);
+        fs::remove_file(&report.path).unwrap();
+
+        compressor.set_keep_if_larger(true);
+        let report = compressor.compress_to_jpg_reported().unwrap();
+        assert!(report.kept_original);
+        assert_eq!(report.path, dest_dir.join("icon.png"));
+        assert_eq!(report.compressed_size, report.original_size);
+        assert_eq!(report.dimensions, (8, 8));
+        assert_eq!(fs::read(&report.path).unwrap(), icon_data);
+        assert!(!dest_dir.join("icon.jpg").exists());
+
+        // The kept source in memory is the data read in advance, even if the source file is gone.
+        #[cfg(feature = "folder")]
+        {
+            fs::remove_file(&icon_path).unwrap();
+            compressor.set_source_data(icon_data.clone());
+            let (report, data) = compressor
+                .compress_to_memory_reported(OutputFormat::Jpeg)
+                .unwrap();
+            assert!(report.kept_original);
+            assert_eq!(data, icon_data);
+        }
+        cleanup(test_dir);
+        cleanup(dest_dir);
+    }
+
+    #[test]
+    fn skip_if_smaller_than_test() {
+        let test_dir = PathBuf::from("skip_if_smaller_than_test");
+        cleanup(&test_dir);
+        fs::create_dir_all(&test_dir).unwrap();
+        let thumb_path = test_dir.join("thumb.jpeg");
+        let icon_path = test_dir.join("icon.png");
+        let thumb =
+            ImageBuffer::from_fn(16, 16, |x, y| image::Rgb([x as u8 * 16, y as u8 * 16, 0]));
+        thumb.save(&thumb_path).unwrap();
+        thumb.save(&icon_path).unwrap();
+        let thumb_data = fs::read(&thumb_path).unwrap();
+
+        let dest_dir = PathBuf::from("skip_if_smaller_than_dest_dir");
+        cleanup(&dest_dir);
+        fs::create_dir_all(&dest_dir).unwrap();
+        let mut compressor = Compressor::new(&thumb_path, &dest_dir);
+        compressor.set_skip_if_smaller_than(thumb_data.len() as u64 + 1);
+        let report = compressor.compress_to_jpg_reported().unwrap();
+        assert!(report.skipped);
+        assert_eq!(report.path, dest_dir.join("thumb.jpeg"));
+        assert_eq!(report.dimensions, (16, 16));
+        assert_eq!(fs::read(&report.path).unwrap(), thumb_data);
+        assert!(!dest_dir.join("thumb.jpg").exists());
+
+        compressor.set_skip_if_smaller_than(thumb_data.len() as u64);
+        let report = compressor.compress_to_jpg_reported().unwrap();
+        assert!(!report.skipped);
+        assert_eq!(report.path, dest_dir.join("thumb.jpg"));
+
+        let mut compressor = Compressor::new(&icon_path, &dest_dir);
+        compressor.set_skip_if_smaller_than(u64::MAX);
+        let report = compressor.compress_to_jpg_reported().unwrap();
+        assert!(!report.skipped);
+        assert_eq!(report.path, dest_dir.join("icon.jpg"));
+        cleanup(test_dir);
+        cleanup(dest_dir);
+    }
+
+    #[test]
+    fn compress_to_jpg_reported_test() {
+        let test_dir = PathBuf::from("compress_to_jpg_reported_test");
+        cleanup(&test_dir);
+        fs::create_dir_all(&test_dir).unwrap();
+        let gradient_path = test_dir.join("img_gradient.jpg");
+        ImageBuffer::from_fn(200, 100, |x, y| image::Rgb([x as u8, y as u8, 128u8]))
+            .save(&gradient_path)
+            .unwrap();
+
+        let dest_dir = PathBuf::from("compress_to_jpg_reported_dest_dir");
+        fs::create_dir_all(&dest_dir).unwrap();
+
+        let mut compressor = Compressor::new(&gradient_path, &dest_dir);
+        compressor.set_factor(Factor::new(60., 0.5));
+        let report = compressor.compress_to_jpg_reported().unwrap();
+        assert_eq!(report.path, dest_dir.join("img_gradient.jpg"));
+        assert_eq!(
+            report.original_size,
+            fs::metadata(&gradient_path).unwrap().len()
+        );
+        assert_eq!(
+            report.compressed_size,
+            fs::metadata(&report.path).unwrap().len()
+        );
+        assert!(report.compressed_size < report.original_size);
+        assert!(report.compression_ratio() < 1.);
+        assert_eq!(report.original_dimensions, (200, 100));
+        assert_eq!(report.dimensions, (100, 50));
+        cleanup(test_dir);
+        cleanup(dest_dir);
+    }
+
+    #[test]
+    fn report_timing_test() {
+        let (test_dir, test_images) = setup("report_timing_test");
+
+        let dest_dir = PathBuf::from("report_timing_dest_dir");
+        fs::create_dir_all(&dest_dir).unwrap();
+        let report = Compressor::new(&test_images[1], &dest_dir)
+            .compress_to_jpg_reported()
+            .unwrap();
+        assert!(report.decode_time > Duration::ZERO);
+        assert!(report.resize_time > Duration::ZERO);
+        assert!(report.encode_time > Duration::ZERO);
+        cleanup(test_dir);
+        cleanup(dest_dir);
+    }
+
+    #[test]
+    fn overwrite_test() {
+        let (test_dir, test_images) = setup("overwrite_test");
+
+        let dest_dir = PathBuf::from("overwrite_dest_dir");
+        fs::create_dir_all(&dest_dir).unwrap();
+        let existing = dest_dir.join("img_stripe.jpg");
+        fs::write(&existing, "existing").unwrap();
+
+        let mut compressor = Compressor::new(&test_images[0], &dest_dir);
+        let error = compressor.compress_to_jpg().unwrap_err();
+        assert!(matches!(error, CompressError::AlreadyExists(ref path) if *path == existing));
+        assert_eq!(fs::read(&existing).unwrap(), b"existing");
+
+        compressor.set_overwrite(true);
+        assert_eq!(compressor.compress_to_jpg().unwrap(), existing);
+        assert_eq!(
+            image::guess_format(&fs::read(&existing).unwrap()).unwrap(),
+            ImageFormat::Jpeg
+        );
+        cleanup(test_dir);
+        cleanup(dest_dir);
+    }
+
+    #[test]
+    fn output_name_template_test() {
+        let (test_dir, test_images) = setup("output_name_template_test");
+
+        let dest_dir = PathBuf::from("output_name_template_dest_dir");
+        fs::create_dir_all(&dest_dir).unwrap();
+        let mut compressor = Compressor::new(&test_images[0], &dest_dir);
+        compressor.set_factor(Factor::new(70., 0.5));
+        compressor.set_output_name_template("{stem}_{width}x{height}_q{quality}.jpg".to_string());
+        let compressed = compressor.compress_to_jpg().unwrap();
+        assert_eq!(compressed, dest_dir.join("img_stripe_128x128_q70.jpg"));
+        assert!(compressed.is_file());
+
+        compressor.set_output_name_template("{stem}_compressed.jpg".to_string());
+        let compressed = compressor.compress_to_jpg().unwrap();
+        assert_eq!(compressed, dest_dir.join("img_stripe_compressed.jpg"));
+        cleanup(test_dir);
+        cleanup(dest_dir);
+    }
+
+    #[test]
+    fn delete_source_in_same_dir_test() {
+        let (test_dir, test_images) = setup("delete_source_in_same_dir_test");
+
+        // A failed write of a new file does not delete the source.
+        let mut compressor = Compressor::new(&test_images[0], &test_dir);
+        compressor.set_delete_source(true);
+        fs::create_dir(test_dir.join("img_stripe.jpg")).unwrap();
+        assert!(compressor.compress_to_jpg().is_err());
+        assert!(test_images[0].is_file());
+        fs::remove_dir(test_dir.join("img_stripe.jpg")).unwrap();
+
+        // A failed write onto the source itself keeps the source as it is.
+        let source = compressor.compress_to_jpg().unwrap();
+        assert!(!test_images[0].exists());
+        let original = fs::read(&source).unwrap();
+        let mut compressor = Compressor::new(&source, &test_dir);
+        compressor.set_delete_source(true);
+        compressor.set_overwrite(true);
+        compressor.set_temp_dir(test_dir.join("missing_temp_dir"));
+        assert!(compressor.compress_to_jpg().is_err());
+        assert_eq!(fs::read(&source).unwrap(), original);
+
+        // A successful write replaces the source and does not delete the new image.
+        let temp_file = test_dir.join(".img_stripe.jpg.tmp");
+        compressor.set_temp_dir(std::env::temp_dir());
+        assert_eq!(compressor.compress_to_jpg().unwrap(), source);
+        assert_eq!(
+            image::guess_format(&fs::read(&source).unwrap()).unwrap(),
+            ImageFormat::Jpeg
+        );
+        assert!(!temp_file.exists());
+        cleanup(test_dir);
+    }
+
+    #[test]
+    fn atomic_write_test() {
+        let (test_dir, test_images) = setup("atomic_write_test");
+
+        let dest_dir = PathBuf::from("atomic_write_dest_dir");
+        fs::create_dir_all(&dest_dir).unwrap();
+        let target_file = dest_dir.join("img_stripe.jpg");
+        let temp_dir = dest_dir.join("temp");
+        let mut compressor = Compressor::new(&test_images[0], &dest_dir);
+        compressor.set_temp_dir(temp_dir.clone());
+
+        // The write fails before the image is renamed to the target file.
+        fs::write(&temp_dir, []).unwrap();
+        assert!(compressor.compress_to_jpg().is_err());
+        assert!(!target_file.exists());
+        fs::remove_file(&temp_dir).unwrap();
+
+        // A partial file left by a killed process does not get in the way of the complete image.
+        fs::create_dir(&temp_dir).unwrap();
+        let stale_file = temp_dir.join(".img_stripe.jpg.tmp");
+        fs::write(&stale_file, [0xFF, 0xD8, 0xFF]).unwrap();
+        assert_eq!(compressor.compress_to_jpg().unwrap(), target_file);
+        assert!(image::open(&target_file).is_ok());
+        assert_eq!(fs::read_dir(&temp_dir).unwrap().count(), 1);
+
+        // The files at the next names of the temporary file are not written through or truncated.
+        let planted = (0..20)
+            .map(|_| temp_file_path(&temp_dir, &target_file))
+            .collect::<Vec<_>>();
+        for path in &planted {
+            fs::write(path, b"planted").unwrap();
+        }
+        let temp_file = compressor
+            .write_temp_file(&temp_dir, &target_file, &[0xFF, 0xD8])
+            .unwrap();
+        assert!(!planted.contains(&temp_file));
+        assert_eq!(fs::read(&temp_file).unwrap(), [0xFF, 0xD8]);
+        for path in &planted {
+            assert_eq!(fs::read(path).unwrap(), b"planted");
+        }
+        fs::remove_dir_all(&temp_dir).unwrap();
+
+        // A rename that fails for a reason other than another filesystem does not write a second copy.
+        fs::create_dir(&temp_dir).unwrap();
+        fs::remove_file(&target_file).unwrap();
+        fs::create_dir(&target_file).unwrap();
+        assert!(compressor.compress_to_jpg().is_err());
+        assert_eq!(fs::read_dir(&temp_dir).unwrap().count(), 0);
+        assert_eq!(fs::read_dir(&dest_dir).unwrap().count(), 2);
+        cleanup(test_dir);
+        cleanup(dest_dir);
+    }
+
+    #[test]
+    fn temp_dir_test() {
+        let (test_dir, test_images) = setup("temp_dir_test");
+
+        let dest_dir = PathBuf::from("temp_dir_dest_dir");
+        let temp_dir = PathBuf::from("temp_dir_temp_dir");
+        fs::create_dir_all(&dest_dir).unwrap();
+        fs::create_dir_all(&temp_dir).unwrap();
+        let mut compressor = Compressor::new(&test_images[0], &dest_dir);
+        compressor.set_temp_dir(temp_dir.join("missing"));
+        assert!(compressor.compress_to_jpg().is_err());
+
+        compressor.set_temp_dir(temp_dir.clone());
+        let compressed = compressor.compress_to_jpg().unwrap();
+        assert!(image::open(&compressed).is_ok());
+        assert_eq!(fs::read_dir(&temp_dir).unwrap().count(), 0);
+        assert_eq!(fs::read_dir(&dest_dir).unwrap().count(), 1);
+        cleanup(test_dir);
+        cleanup(dest_dir);
+        cleanup(temp_dir);
+    }
+
+    #[test]
+    fn output_extension_test() {
+        let (test_dir, test_images) = setup("output_extension_test");
+
+        let dest_dir = PathBuf::from("output_extension_dest_dir");
+        fs::create_dir_all(&dest_dir).unwrap();
+        let mut compressor = Compressor::new(&test_images[0], &dest_dir);
+        compressor.set_output_extension("JPG".to_string());
+        let compressed = compressor.compress_to_jpg().unwrap();
+        assert_eq!(compressed, dest_dir.join("img_stripe.JPG"));
+        assert!(compressed.is_file());
+
+        compressor.set_output_extension("jpeg".to_string());
+        compressor.set_output_name_template("{stem}_small.jpg".to_string());
+        let compressed = compressor.compress_to_jpg().unwrap();
+        assert_eq!(compressed, dest_dir.join("img_stripe_small.jpeg"));
+
+        compressor.set_output_extension("png".to_string());
+        let error = compressor.compress_to_jpg().unwrap_err();
+        assert!(matches!(error, CompressError::UnsupportedExtension(ref e) if e == "png"));
+        assert_eq!(
+            compressor.compress_to_png().unwrap(),
+            dest_dir.join("img_stripe.png")
+        );
+        cleanup(test_dir);
+        cleanup(dest_dir);
+    }
+
+    #[test]
+    fn compress_with_thumbnail_test() {
+        let test_dir = PathBuf::from("compress_with_thumbnail_test");
+        cleanup(&test_dir);
+        fs::create_dir_all(&test_dir).unwrap();
+        let source = test_dir.join("wide.png");
+        ImageBuffer::from_fn(400, 200, |x, _| image::Rgb([x as u8, 0, 0]))
+            .save(&source)
+            .unwrap();
+
+        let dest_dir = PathBuf::from("compress_with_thumbnail_dest_dir");
+        fs::create_dir_all(&dest_dir).unwrap();
+        let compressor = Compressor::new(&source, &dest_dir);
+        let (compressed, thumbnail) = compressor.compress_with_thumbnail(100, false).unwrap();
+        assert_eq!(compressed, dest_dir.join("wide.jpg"));
+        assert_eq!(thumbnail, dest_dir.join("wide_thumb.jpg"));
+        assert!(compressed.is_file());
+        assert_eq!(image::image_dimensions(&thumbnail).unwrap(), (100, 50));
+
+        let square_dest_dir = dest_dir.join("square");
+        fs::create_dir_all(&square_dest_dir).unwrap();
+        let compressor = Compressor::new(&source, &square_dest_dir);
+        let (_, thumbnail) = compressor.compress_with_thumbnail(100, true).unwrap();
+        assert_eq!(image::image_dimensions(thumbnail).unwrap(), (100, 100));
+
+        // The name of the image is known only after it is compressed, so the thumbnail must not be left alone.
+        let named_dest_dir = dest_dir.join("named");
+        fs::create_dir_all(&named_dest_dir).unwrap();
+        fs::write(named_dest_dir.join("wide_400.jpg"), "existing").unwrap();
+        let mut compressor = Compressor::new(&source, &named_dest_dir);
+        compressor.set_factor(Factor::new(80., 1.));
+        compressor.set_output_name_template("{stem}_{width}.jpg".to_string());
+        assert!(matches!(
+            compressor.compress_with_thumbnail(100, false),
+            Err(CompressError::AlreadyExists(_))
+        ));
+        assert!(!named_dest_dir.join("wide_thumb.jpg").exists());
+        cleanup(test_dir);
+        cleanup(dest_dir);
+    }
+
+    #[test]
+    fn preserve_timestamps_test() {
+        let (test_dir, test_images) = setup("preserve_timestamps_test");
+        let modified =
+            std::time::SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(1_000_000_000);
+        File::options()
+            .write(true)
+            .open(&test_images[0])
+            .unwrap()
+            .set_modified(modified)
+            .unwrap();
+
+        let dest_dir = PathBuf::from("preserve_timestamps_dest_dir");
+        fs::create_dir_all(&dest_dir).unwrap();
+        let mut compressor = Compressor::new(&test_images[0], &dest_dir);
+        compressor.set_preserve_timestamps(true);
+        let compressed = compressor.compress_to_jpg().unwrap();
+        let compressed_modified = fs::metadata(compressed).unwrap().modified().unwrap();
+        let difference = match compressed_modified.duration_since(modified) {
+            Ok(d) => d,
+            Err(e) => e.duration(),
+        };
+        assert!(difference.as_secs() < 1);
         cleanup(test_dir);
         cleanup(dest_dir);
     }
@@ -10,44 +10,217 @@
 //! get_file_list(&root);
 //! ```
 
-use std::io;
+use ignore::WalkBuilder;
+#[cfg(feature = "folder")]
+use rayon::prelude::*;
+use std::collections::HashSet;
 use std::path::{Path, PathBuf};
+#[cfg(feature = "folder")]
+use std::sync::Mutex;
+use std::{fs, io};
 
 /// Find all files in the root directory in a recursive way.
 /// The hidden files started with `.` will be not included in result.
+///
+/// Symbolic links are followed, but a directory that is already visited is not crawled again,
+/// so a link to an ancestor directory does not cause an infinite loop.
 pub fn get_file_list<O: AsRef<Path>>(root: O) -> io::Result<Vec<PathBuf>> {
+    crawl(root.as_ref(), None, true)
+}
+
+/// Find all files in the root directory in a recursive way, like [`get_file_list`],
+/// but symbolic links are skipped if `follow_symlinks` is false.
+/// # Examples
+/// ```
+/// use image_compressor::crawler::get_file_list_with_symlinks;
+/// let files_without_links = get_file_list_with_symlinks("root", false);
+/// ```
+pub fn get_file_list_with_symlinks<O: AsRef<Path>>(
+    root: O,
+    follow_symlinks: bool,
+) -> io::Result<Vec<PathBuf>> {
+    crawl(root.as_ref(), None, follow_symlinks)
+}
+
+/// Find all files in the root directory in a recursive way, like [`get_file_list`],
+/// but do not descend into the directories deeper than `max_depth`.
+///
+/// The depth is relative to the root. Depth 0 means only the files directly in the root directory,
+/// and depth 1 adds the files in its subdirectories.
+/// # Examples
+/// ```
+/// use image_compressor::crawler::get_file_list_with_depth;
+/// let top_level_files = get_file_list_with_depth("root", 0);
+/// ```
+pub fn get_file_list_with_depth<O: AsRef<Path>>(
+    root: O,
+    max_depth: usize,
+) -> io::Result<Vec<PathBuf>> {
+    crawl(root.as_ref(), Some(max_depth), true)
+}
+
+/// Find all files in the root directory in a recursive way, like [`get_file_list`],
+/// but the subdirectories are read concurrently in the rayon thread pool.
+///
+/// It returns the same files as [`get_file_list`] in an unspecified order,
+/// and is faster for huge directory trees on network filesystems where each `read_dir` is slow.
+/// If a directory can be reached through several symbolic links, any one of the paths may be listed.
+/// Available with the `folder` feature.
+/// # Examples
+/// ```
+/// use image_compressor::crawler::get_file_list_parallel;
+/// let files = get_file_list_parallel("root");
+/// ```
+#[cfg(feature = "folder")]
+pub fn get_file_list_parallel<O: AsRef<Path>>(root: O) -> io::Result<Vec<PathBuf>> {
+    let root = root.as_ref();
+    let visited_dirs = Mutex::new(HashSet::from([root.canonicalize()?]));
+    crawl_parallel(root, &visited_dirs)
+}
+
+/// Crawl the directory, reading its subdirectories concurrently.
+///
+/// The real paths of the visited directories are shared between the threads to avoid visiting a directory twice.
+#[cfg(feature = "folder")]
+fn crawl_parallel(dir: &Path, visited_dirs: &Mutex<HashSet<PathBuf>>) -> io::Result<Vec<PathBuf>> {
+    let entries = dir
+        .read_dir()?
+        .map(|entry| entry.map(|e| e.path()))
+        .collect::<io::Result<Vec<_>>>()?;
+    entries
+        .into_par_iter()
+        .map(|path| {
+            if path.is_dir() {
+                if visited_dirs.lock().unwrap().insert(path.canonicalize()?) {
+                    return crawl_parallel(&path, visited_dirs);
+                }
+                Ok(Vec::new())
+            } else if is_hidden(&path) {
+                Ok(Vec::new())
+            } else {
+                Ok(vec![path])
+            }
+        })
+        .try_reduce(Vec::new, |mut files, other| {
+            files.extend(other);
+            Ok(files)
+        })
+}
+
+/// Crawl the root directory, descending into the directories up to `max_depth` if it is given.
+///
+/// The real paths of the visited directories are tracked to avoid visiting a directory twice.
+fn crawl(root: &Path, max_depth: Option<usize>, follow_symlinks: bool) -> io::Result<Vec<PathBuf>> {
     let mut image_list: Vec<PathBuf> = Vec::new();
-    let mut file_list: Vec<PathBuf> = root
-        .as_ref()
+    let mut visited_dirs = HashSet::new();
+    visited_dirs.insert(root.canonicalize()?);
+    let mut file_list: Vec<(PathBuf, usize)> = root
         .read_dir()?
-        .map(|entry| entry.unwrap().path())
+        .map(|entry| (entry.unwrap().path(), 0))
         .collect();
     let mut i = 0;
     loop {
         if i >= file_list.len() {
             break;
         }
-        if file_list[i].is_dir() {
-            for component in file_list[i].read_dir()? {
-                file_list.push(component.unwrap().path());
+        let (path, depth) = file_list[i].clone();
+        i += 1;
+        if !follow_symlinks && fs::symlink_metadata(&path)?.file_type().is_symlink() {
+            continue;
+        }
+        if path.is_dir() {
+            let below_max_depth = match max_depth {
+                Some(max_depth) => depth < max_depth,
+                None => true,
+            };
+            if below_max_depth && visited_dirs.insert(path.canonicalize()?) {
+                for component in path.read_dir()? {
+                    file_list.push((component.unwrap().path(), depth + 1));
+                }
             }
-        } else if file_list[i]
-            .file_name()
-            .unwrap()
-            .to_str()
-            .unwrap()
-            .chars()
-            .collect::<Vec<_>>()[0]
-            != '.'
-        {
-            image_list.push(file_list[i].to_path_buf());
+        } else if !is_hidden(&path) {
+            image_list.push(path);
         }
-        i += 1;
     }
 
     Ok(image_list)
 }
 
+/// Check whether the file name starts with `.`.
+/// Non-UTF-8 names are checked lossily, and a path without a file name is not hidden.
+fn is_hidden(path: &Path) -> bool {
+    match path.file_name() {
+        Some(name) => name.to_string_lossy().starts_with('.'),
+        None => false,
+    }
+}
+
+/// Find all files in the root directory in a recursive way, like [`get_file_list`],
+/// but only the files whose extension is in `extensions` are included in result.
+/// The extensions are compared case-insensitively and are given without the leading `.`.
+/// # Examples
+/// ```
+/// use image_compressor::crawler::get_file_list_filtered;
+/// let images = get_file_list_filtered("root", &["jpg", "png", "webp"]);
+/// ```
+pub fn get_file_list_filtered<O: AsRef<Path>>(
+    root: O,
+    extensions: &[&str],
+) -> io::Result<Vec<PathBuf>> {
+    let file_list = get_file_list(root)?;
+    Ok(filter_by_extensions(file_list, extensions))
+}
+
+/// Keep only the files whose extension is in `extensions`, compared case-insensitively.
+pub(crate) fn filter_by_extensions(file_list: Vec<PathBuf>, extensions: &[&str]) -> Vec<PathBuf> {
+    file_list
+        .into_iter()
+        .filter(|file| match file.extension().and_then(|e| e.to_str()) {
+            Some(ext) => extensions.iter().any(|e| e.eq_ignore_ascii_case(ext)),
+            None => false,
+        })
+        .collect()
+}
+
+/// Find all files in the root directory in a recursive way, like [`get_file_list`],
+/// but the files matched by the ignore files named `ignore_file_name` are excluded.
+///
+/// An ignore file has the glob patterns of `.gitignore`, and is read in every directory,
+/// so it applies to the files in its directory and the subdirectories.
+/// A directory that matches a pattern, such as `raw/`, is not crawled at all.
+/// Symbolic links are followed, but the links that make a loop are skipped.
+/// # Examples
+/// ```
+/// use image_compressor::crawler::get_file_list_with_ignore;
+/// let files = get_file_list_with_ignore("root", ".compressignore");
+/// ```
+pub fn get_file_list_with_ignore<O: AsRef<Path>>(
+    root: O,
+    ignore_file_name: &str,
+) -> io::Result<Vec<PathBuf>> {
+    let walker = WalkBuilder::new(root)
+        .standard_filters(false)
+        .add_custom_ignore_filename(ignore_file_name)
+        .follow_links(true)
+        .build();
+    let mut file_list = Vec::new();
+    for entry in walker {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(e) => match e.into_io_error() {
+                Some(e) => return Err(e),
+                // Loops of symbolic links and malformed patterns are skipped.
+                None => continue,
+            },
+        };
+        let path = entry.into_path();
+        if !path.is_dir() && !is_hidden(&path) {
+            file_list.push(path);
+        }
+    }
+    Ok(file_list)
+}
+
 #[cfg(test)]
 pub mod tests {
 
@@ -57,7 +230,7 @@ pub mod tests {
     use std::path::{Path, PathBuf};
     use std::{fs, io};
 
-    const CRAWLER_TEST_FILES: &'static [&str] = &[
+    const CRAWLER_TEST_FILES: &[&str] = &[
         "file1.txt",
         "file2.txt",
         "file3.txt",
@@ -67,9 +240,8 @@ pub mod tests {
 
     /// Create dummy test files.
     fn write_test_file<T: AsRef<Path>>(path: T) -> io::Result<()> {
-        match &path.as_ref().parent() {
-            Some(p) => fs::create_dir_all(&p).unwrap(),
-            None => (),
+        if let Some(p) = path.as_ref().parent() {
+            fs::create_dir_all(p).unwrap();
         }
         write!(
             File::create(&path)?,
@@ -122,4 +294,126 @@ pub mod tests {
         assert_eq!(test_vec, expected_vec);
         cleanup(test_dir);
     }
+
+    #[test]
+    #[cfg(feature = "folder")]
+    fn get_file_list_parallel_test() {
+        let (test_dir, _) = setup("get_file_list_parallel_test_dir");
+        write_test_file(test_dir.join("dir1").join(".hidden")).unwrap();
+        write_test_file(test_dir.join("dir5").join("file6.txt")).unwrap();
+        let mut expected_vec = get_file_list(&test_dir).unwrap();
+        expected_vec.sort();
+        let mut test_vec = get_file_list_parallel(&test_dir).unwrap();
+        test_vec.sort();
+        assert_eq!(test_vec, expected_vec);
+        cleanup(test_dir);
+    }
+
+    #[test]
+    fn get_file_list_with_depth_test() {
+        let (test_dir, mut expected_vec) = setup("get_file_list_with_depth_test_dir");
+        expected_vec.sort();
+        let mut test_vec = get_file_list_with_depth(&test_dir, 0).unwrap();
+        assert_eq!(test_vec, vec![test_dir.join(CRAWLER_TEST_FILES[0])]);
+
+        test_vec = get_file_list_with_depth(&test_dir, 1).unwrap();
+        test_vec.sort();
+        assert_eq!(test_vec.len(), 2);
+
+        test_vec = get_file_list_with_depth(&test_dir, 3).unwrap();
+        test_vec.sort();
+        assert_eq!(test_vec.len(), 4);
+        assert!(test_vec.iter().all(|file| expected_vec.contains(file)));
+
+        test_vec = get_file_list_with_depth(&test_dir, 4).unwrap();
+        test_vec.sort();
+        assert_eq!(test_vec, expected_vec);
+        cleanup(test_dir);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn get_file_list_symlink_loop_test() {
+        let (test_dir, mut expected_vec) = setup("get_file_list_symlink_loop_test_dir");
+        let link_dir = test_dir.join("dir1").join("dir2");
+        std::os::unix::fs::symlink(fs::canonicalize(&test_dir).unwrap(), link_dir.join("loop"))
+            .unwrap();
+        expected_vec.sort();
+
+        let mut test_vec = get_file_list(&test_dir).unwrap();
+        test_vec.sort();
+        assert_eq!(test_vec, expected_vec);
+
+        test_vec = get_file_list_with_symlinks(&test_dir, false).unwrap();
+        test_vec.sort();
+        assert_eq!(test_vec, expected_vec);
+        cleanup(test_dir);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn get_file_list_non_utf8_test() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+
+        let (test_dir, mut expected_vec) = setup("get_file_list_non_utf8_test_dir");
+        let non_utf8_file = test_dir.join(OsStr::from_bytes(b"caf\xe9.txt"));
+        write!(File::create(&non_utf8_file).unwrap(), "latin-1").unwrap();
+        let hidden_file = test_dir.join(OsStr::from_bytes(b".hidd\xe9n"));
+        write!(File::create(&hidden_file).unwrap(), "hidden").unwrap();
+        expected_vec.push(non_utf8_file);
+        expected_vec.sort();
+
+        let mut test_vec = get_file_list(&test_dir).unwrap();
+        test_vec.sort();
+        assert_eq!(test_vec, expected_vec);
+        cleanup(test_dir);
+    }
+
+    #[test]
+    fn get_file_list_filtered_test() {
+        let (test_dir, _) = setup("get_file_list_filtered_test_dir");
+        let mut expected_vec = vec![
+            test_dir.join("image1.jpg"),
+            test_dir.join("dir1").join("image2.PNG"),
+            test_dir.join("dir1").join("dir2").join("image3.webp"),
+        ];
+        for file in &expected_vec {
+            write_test_file(file).unwrap();
+        }
+        write_test_file(test_dir.join("dir1").join("video.mp4")).unwrap();
+        write_test_file(test_dir.join("no_extension")).unwrap();
+
+        let mut test_vec = get_file_list_filtered(&test_dir, &["jpg", "png", "webp"]).unwrap();
+        test_vec.sort();
+        expected_vec.sort();
+        assert_eq!(test_vec, expected_vec);
+        cleanup(test_dir);
+    }
+
+    #[test]
+    fn get_file_list_with_ignore_test() {
+        let (test_dir, _) = setup("get_file_list_with_ignore_test_dir");
+        write!(
+            File::create(test_dir.join(".compressignore")).unwrap(),
+            "dir2/\n*.raw\n"
+        )
+        .unwrap();
+        write_test_file(test_dir.join("photo.raw")).unwrap();
+        write_test_file(test_dir.join("dir1").join("photo.jpg")).unwrap();
+
+        let mut test_vec = get_file_list_with_ignore(&test_dir, ".compressignore").unwrap();
+        test_vec.sort();
+        let mut expected_vec = vec![
+            test_dir.join(CRAWLER_TEST_FILES[0]),
+            test_dir.join("dir1").join(CRAWLER_TEST_FILES[1]),
+            test_dir.join("dir1").join("photo.jpg"),
+        ];
+        expected_vec.sort();
+        assert_eq!(test_vec, expected_vec);
+        assert!(!test_vec
+            .iter()
+            .any(|file| file.starts_with(test_dir.join("dir1").join("dir2"))));
+        cleanup(test_dir);
+    }
 }
@@ -2,7 +2,7 @@
 //!
 
 use std::error::Error;
-use std::fs::{read_dir, remove_dir_all};
+use std::fs::{read_dir, remove_dir, remove_dir_all};
 use std::io;
 use std::path::Path;
 
@@ -10,26 +10,31 @@ use std::path::Path;
 ///
 /// If the directory is not empty, this function doesn't delete that particular directory and its parents also.
 ///
+/// If `remove_hidden` is true, hidden files whose names start with `.`, such as `.DS_Store`, are not counted,
+/// so a directory with only hidden files is treated as empty and deleted together with the hidden files.
+/// If it is false, a hidden file keeps its directory like any other file.
+///
 /// # Error
 /// - When directory is not empty.
 /// - When the child directory is not empty.
-pub fn delete_recursive<O: AsRef<Path>>(dir: O) -> Result<(), Box<dyn Error>> {
+/// - When the directory cannot be removed, e.g. for lack of permission.
+pub fn delete_recursive<O: AsRef<Path>>(dir: O, remove_hidden: bool) -> Result<(), Box<dyn Error>> {
     if dir.as_ref().is_dir() {
         let mut does_file_exist = false;
         for content in read_dir(&dir)? {
             let content = content?;
             let content_path = content.path();
             if content_path.is_dir() {
-                match delete_recursive(&content_path) {
+                match delete_recursive(&content_path, remove_hidden) {
                     Ok(_) => (),
                     Err(_) => does_file_exist = true,
                 }
-            } else if &content_path.file_name().unwrap().to_str().unwrap()[..1] != "." {
+            } else if !remove_hidden || !content.file_name().to_string_lossy().starts_with('.') {
                 does_file_exist = true;
             }
         }
         if !does_file_exist {
-            remove_dir_all(dir).unwrap();
+            remove_dir_all(dir)?;
             Ok(())
         } else {
             Err(Box::new(io::Error::new(
@@ -45,6 +50,31 @@ pub fn delete_recursive<O: AsRef<Path>>(dir: O) -> Result<(), Box<dyn Error>> {
     }
 }
 
+/// Remove the empty directories under the root directory from the bottom up, and return how many are removed.
+///
+/// A directory that becomes empty after its empty subdirectories are removed is removed too,
+/// but the root directory itself is kept. Unlike [`delete_recursive`],
+/// the directories with any file, including hidden files, are quietly left as they are.
+/// Symbolic links to directories are not followed.
+///
+/// # Error
+/// - When a directory cannot be read or removed.
+pub fn prune_empty_dirs<O: AsRef<Path>>(root: O) -> io::Result<usize> {
+    let mut removed = 0;
+    for entry in read_dir(&root)? {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            let path = entry.path();
+            removed += prune_empty_dirs(&path)?;
+            if read_dir(&path)?.next().is_none() {
+                remove_dir(&path)?;
+                removed += 1;
+            }
+        }
+    }
+    Ok(removed)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -53,7 +83,7 @@ mod tests {
     use std::io::Write;
     use std::path::PathBuf;
 
-    const TEST_FILES: &'static [&str] = &[
+    const TEST_FILES: &[&str] = &[
         "file1.txt",
         "file2.txt",
         "file3.txt",
@@ -88,9 +118,8 @@ mod tests {
 
     /// Create dummy test files.
     fn write_test_file<T: AsRef<Path>>(path: T) -> io::Result<()> {
-        match &path.as_ref().parent() {
-            Some(p) => fs::create_dir_all(&p).unwrap(),
-            None => (),
+        if let Some(p) = path.as_ref().parent() {
+            fs::create_dir_all(p).unwrap();
         }
         write!(
             File::create(&path)?,
@@ -112,10 +141,62 @@ mod tests {
         if test_dir.is_dir() {
             remove_dir_all(&test_dir).unwrap();
         }
-        assert!(delete_recursive(&test_dir).is_err());
+        assert!(delete_recursive(&test_dir, true).is_err());
         for test_file in test_files {
             assert!(!test_file.is_file());
         }
         cleanup(test_dir);
     }
+
+    #[test]
+    fn delete_recursive_hidden_only_test() {
+        let test_dir = PathBuf::from("delete_recursive_hidden_only_test_dir");
+        cleanup(&test_dir);
+        let hidden_file = test_dir.join("dir1").join(".DS_Store");
+        write_test_file(&hidden_file).unwrap();
+
+        assert!(delete_recursive(&test_dir, false).is_err());
+        assert!(hidden_file.is_file());
+
+        assert!(delete_recursive(&test_dir, true).is_ok());
+        assert!(!test_dir.exists());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn delete_recursive_permission_denied_test() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let test_dir = PathBuf::from("delete_recursive_permission_denied_test_dir");
+        cleanup(&test_dir);
+        let locked_dir = test_dir.join("locked");
+        fs::create_dir_all(locked_dir.join("empty")).unwrap();
+        fs::set_permissions(&locked_dir, fs::Permissions::from_mode(0o555)).unwrap();
+
+        // The permission is not checked for a privileged user such as root.
+        if File::create(locked_dir.join("probe")).is_err() {
+            assert!(delete_recursive(&locked_dir, true).is_err());
+            assert!(locked_dir.join("empty").is_dir());
+        }
+        fs::set_permissions(&locked_dir, fs::Permissions::from_mode(0o755)).unwrap();
+        cleanup(test_dir);
+    }
+
+    #[test]
+    fn prune_empty_dirs_test() {
+        let (test_dir, test_files) = setup("prune_empty_dirs_test_dir");
+        fs::create_dir_all(test_dir.join("empty1").join("empty2")).unwrap();
+        fs::create_dir_all(test_dir.join("dir1").join("empty3")).unwrap();
+        write_test_file(test_dir.join("hidden").join(".DS_Store")).unwrap();
+
+        assert_eq!(prune_empty_dirs(&test_dir).unwrap(), 3);
+        assert!(!test_dir.join("empty1").exists());
+        assert!(!test_dir.join("dir1").join("empty3").exists());
+        assert!(test_dir.join("hidden").join(".DS_Store").is_file());
+        for test_file in test_files {
+            assert!(test_file.is_file());
+        }
+        assert_eq!(prune_empty_dirs(&test_dir).unwrap(), 0);
+        cleanup(test_dir);
+    }
 }
@@ -0,0 +1,452 @@
+//! Encoders that write the resized pixels in an output format.
+//!
+//! [`Compressor::compress_with_encoder`](crate::compressor::Compressor::compress_with_encoder)
+//! encodes the image with the [`OutputEncoder`] set by
+//! [`Compressor::set_encoder`](crate::compressor::Compressor::set_encoder),
+//! so a format that this crate does not support can be added by implementing the trait.
+//! # Examples
+//! ```no_run
+//! use image_compressor::compressor::{Compressor, ResizedPixels};
+//! use image_compressor::encoder::OutputEncoder;
+//! use image_compressor::{CompressError, Factor};
+//!
+//! struct RawEncoder;
+//!
+//! impl OutputEncoder for RawEncoder {
+//!     fn encode(&self, pixels: ResizedPixels, _: u32, _: u32, _: Factor) -> Result<Vec<u8>, CompressError> {
+//!         Ok(pixels.into_rgb())
+//!     }
+//!
+//!     fn extension(&self) -> &str {
+//!         "raw"
+//!     }
+//! }
+//!
+//! let mut compressor = Compressor::new("source.png", "dest");
+//! compressor.set_encoder(Box::new(RawEncoder));
+//! compressor.compress_with_encoder().unwrap();
+//! ```
+
+use crate::compressor::{CompressionType, ResizedPixels, Subsampling};
+use crate::error::CompressError;
+use crate::Factor;
+use image::codecs::png::{FilterType as PngFilterType, PngEncoder as ImagePngEncoder};
+use image::{ExtendedColorType, ImageEncoder};
+use mozjpeg::{qtable, ColorSpace, Compress, Marker, ScanMode};
+
+/// The identifier at the start of an APP1 segment for EXIF data.
+const EXIF_HEADER: &[u8] = b"Exif\0\0";
+
+/// Encoder of the resized pixels to the bytes of an image file.
+pub trait OutputEncoder: Send + Sync {
+    /// Encode the pixels of an image of `width` and `height` with the quality of the `factor`.
+    ///
+    /// The size ratio of the `factor` is already applied to the pixels.
+    /// The quality of the `factor` is on the unified scale, which can be converted with
+    /// [`native_quality`](OutputEncoder::native_quality).
+    fn encode(
+        &self,
+        pixels: ResizedPixels,
+        width: u32,
+        height: u32,
+        factor: Factor,
+    ) -> Result<Vec<u8>, CompressError>;
+
+    /// Extension of the new image file, without the leading `.`.
+    fn extension(&self) -> &str;
+
+    /// Convert the unified quality from 0 to 100 to the native quality scale of the codec.
+    ///
+    /// The unified scale is the jpg quality of mozjpeg, so that the same quality
+    /// gives roughly the same visual quality in every format.
+    /// The default returns the quality as it is.
+    fn native_quality(&self, quality: f32) -> f32 {
+        quality
+    }
+
+    /// Check whether the encoder keeps 16 bits per channel.
+    ///
+    /// If it is true, the pixels of a 16-bit image are given as [`ResizedPixels::Rgb16`] or [`ResizedPixels::Rgba16`].
+    /// Otherwise they are converted to 8 bits per channel. The default is false.
+    fn supports_16bit(&self) -> bool {
+        false
+    }
+
+    /// Check whether the encoder produces files with the extension, without the leading `.`.
+    ///
+    /// The default accepts the [`extension`](OutputEncoder::extension) in any case.
+    fn produces_extension(&self, extension: &str) -> bool {
+        extension.eq_ignore_ascii_case(self.extension())
+    }
+}
+
+/// Unified qualities and the webp qualities of about the same SSIM, in increasing order.
+const WEBP_QUALITY_POINTS: &[(f32, f32)] =
+    &[(0., 0.), (50., 35.), (80., 65.), (90., 78.), (100., 100.)];
+
+/// Interpolate the value of `x` linearly between the points, which are sorted by x.
+fn interpolate(points: &[(f32, f32)], x: f32) -> f32 {
+    let x = x.clamp(points[0].0, points[points.len() - 1].0);
+    for pair in points.windows(2) {
+        let ((x0, y0), (x1, y1)) = (pair[0], pair[1]);
+        if x <= x1 {
+            return y0 + (y1 - y0) * (x - x0) / (x1 - x0);
+        }
+    }
+    points[points.len() - 1].1
+}
+
+/// Split the ICC profile into the data of APP2 markers of a jpg image.
+///
+/// The profile is split into chunks that fit in a marker,
+/// and the sequence numbers of the chunks start from 1 as the ICC specification requires.
+pub(crate) fn icc_markers(icc_profile: &[u8]) -> Vec<Vec<u8>> {
+    const MAX_DATA_BYTES_IN_MARKER: usize = 65533 - 14;
+
+    let chunks = icc_profile.chunks(MAX_DATA_BYTES_IN_MARKER);
+    let num_chunks = chunks.len();
+    chunks
+        .enumerate()
+        .map(|(i, chunk)| {
+            let mut data = b"ICC_PROFILE\0".to_vec();
+            data.extend([(i + 1) as u8, num_chunks as u8]);
+            data.extend_from_slice(chunk);
+            data
+        })
+        .collect()
+}
+
+/// Set the density in dots per inch of the JFIF header that mozjpeg writes at the start of the jpg image.
+///
+/// The mozjpeg crate cannot set the density before the compression, so the header is changed in place.
+/// Nothing is changed if the image does not start with a JFIF header.
+fn set_jfif_density(jpg: &mut [u8], (x, y): (u16, u16)) {
+    if jpg.len() < 18 || !jpg.starts_with(&[0xFF, 0xD8, 0xFF, 0xE0]) || &jpg[6..11] != b"JFIF\0" {
+        return;
+    }
+    jpg[13] = 1;
+    jpg[14..16].copy_from_slice(&x.to_be_bytes());
+    jpg[16..18].copy_from_slice(&y.to_be_bytes());
+}
+
+/// Encoder of jpg images with mozjpeg.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct JpegEncoder {
+    subsampling: Subsampling,
+    progressive: bool,
+    grayscale: bool,
+    smoothing: u8,
+    trellis: bool,
+    density: (u16, u16),
+}
+
+impl Default for JpegEncoder {
+    fn default() -> Self {
+        JpegEncoder {
+            subsampling: Subsampling::default(),
+            progressive: true,
+            grayscale: false,
+            smoothing: 0,
+            trellis: true,
+            density: (72, 72),
+        }
+    }
+}
+
+impl JpegEncoder {
+    /// Create a new `JpegEncoder` that makes progressive color images with 4:2:0 subsampling.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set the chroma subsampling of the jpg image. The default is [`Subsampling::Sub420`].
+    pub fn set_subsampling(&mut self, subsampling: Subsampling) {
+        self.subsampling = subsampling;
+    }
+
+    /// Set whether to make a progressive jpg image. The default is true.
+    pub fn set_progressive(&mut self, progressive: bool) {
+        self.progressive = progressive;
+    }
+
+    /// Set whether to make a grayscale jpg image. The default is false.
+    pub fn set_grayscale(&mut self, grayscale: bool) {
+        self.grayscale = grayscale;
+    }
+
+    /// Set the smoothing factor of mozjpeg from 0 to 100. Values above 100 are treated as 100.
+    /// The default is 0, which means no smoothing.
+    pub fn set_smoothing(&mut self, smoothing: u8) {
+        self.smoothing = smoothing.min(100);
+    }
+
+    /// Set whether to use the trellis quantization of mozjpeg. The default is true.
+    pub fn set_trellis(&mut self, trellis: bool) {
+        self.trellis = trellis;
+    }
+
+    /// Set the density in dots per inch written to the JFIF header. The default is 72 DPI.
+    pub fn set_density(&mut self, x: u16, y: u16) {
+        self.density = (x, y);
+    }
+
+    /// Encode the pixels to jpg format with the quality, writing the EXIF data and the ICC profile if they are given.
+    ///
+    /// Since jpg has no alpha channel, RGBA pixels are flattened over a white background.
+    ///
+    /// # Error
+    /// - When the length of the pixels is not `width * height * channels`,
+    ///   instead of writing corrupted scanlines.
+    pub(crate) fn encode_with_metadata(
+        &self,
+        pixels: ResizedPixels,
+        width: usize,
+        height: usize,
+        quality: f32,
+        exif: Option<&[u8]>,
+        icc_profile: Option<&[u8]>,
+    ) -> Result<Vec<u8>, CompressError> {
+        let expected_len = width * height * pixels.channels();
+        if pixels.len() != expected_len {
+            return Err(CompressError::Encode(format!(
+                "The size of the pixels does not match the image size: expected {} bytes for {}x{}, got {} bytes",
+                expected_len,
+                width,
+                height,
+                pixels.len()
+            )));
+        }
+        let (color_space, img_vec) = if self.grayscale {
+            let rgb_img = image::RgbImage::from_raw(width as u32, height as u32, pixels.into_rgb())
+                .ok_or_else(|| {
+                    CompressError::Encode(
+                        "The size of the pixels does not match the image size".to_string(),
+                    )
+                })?;
+            (
+                ColorSpace::JCS_GRAYSCALE,
+                image::DynamicImage::ImageRgb8(rgb_img)
+                    .into_luma8()
+                    .into_vec(),
+            )
+        } else {
+            (ColorSpace::JCS_RGB, pixels.into_rgb())
+        };
+        let channels = if self.grayscale { 1 } else { 3 };
+
+        let mut comp = Compress::new(color_space);
+        if !self.trellis {
+            // The mozjpeg crate cannot turn off the trellis quantization alone,
+            // so the libjpeg defaults, which do not use it, are set,
+            // and the other size optimizations of mozjpeg are turned back on below.
+            comp.set_fastest_defaults();
+        }
+        if self.progressive {
+            comp.set_scan_optimization_mode(ScanMode::Auto);
+        } else {
+            comp.set_scan_optimization_mode(ScanMode::AllComponentsTogether);
+        }
+        comp.set_quality(quality);
+        if !self.trellis {
+            // The quantization tables of the mozjpeg defaults, scaled from the integer quality like `set_quality`.
+            let table_quality = (quality as i32).clamp(1, 100) as f32;
+            let qtable = qtable::NRobidoux.scaled(table_quality, table_quality);
+            comp.set_luma_qtable(&qtable);
+            comp.set_chroma_qtable(&qtable);
+            comp.set_optimize_coding(true);
+        }
+        comp.set_smoothing_factor(self.smoothing);
+        if !self.grayscale {
+            let pixel_sizes = self.subsampling.pixel_sizes();
+            comp.set_chroma_sampling_pixel_sizes(pixel_sizes, pixel_sizes);
+        }
+
+        comp.set_size(width, height);
+
+        comp.set_optimize_scans(self.progressive);
+        if self.progressive && !self.trellis {
+            // The libjpeg defaults are baseline, so the progression is added after the other settings.
+            comp.set_progressive_mode();
+        }
+        let mut comp = comp.start_compress(Vec::new())?;
+        if let Some(exif) = exif {
+            // The length of a marker segment cannot exceed 65535 bytes including the length itself.
+            if exif.len() + EXIF_HEADER.len() <= 65533 {
+                comp.write_marker(Marker::APP(1), &[EXIF_HEADER, exif].concat());
+            }
+        }
+        if let Some(icc_profile) = icc_profile.filter(|p| !p.is_empty()) {
+            for marker in icc_markers(icc_profile) {
+                comp.write_marker(Marker::APP(2), &marker);
+            }
+        }
+
+        let stride = width * channels;
+        for scanline in img_vec.chunks_exact(stride) {
+            comp.write_scanlines(scanline)?;
+        }
+        let mut compressed = comp.finish()?;
+        set_jfif_density(&mut compressed, self.density);
+        Ok(compressed)
+    }
+}
+
+impl OutputEncoder for JpegEncoder {
+    fn encode(
+        &self,
+        pixels: ResizedPixels,
+        width: u32,
+        height: u32,
+        factor: Factor,
+    ) -> Result<Vec<u8>, CompressError> {
+        self.encode_with_metadata(
+            pixels,
+            width as usize,
+            height as usize,
+            factor.quality(),
+            None,
+            None,
+        )
+    }
+
+    fn extension(&self) -> &str {
+        "jpg"
+    }
+
+    fn produces_extension(&self, extension: &str) -> bool {
+        extension.eq_ignore_ascii_case("jpg") || extension.eq_ignore_ascii_case("jpeg")
+    }
+}
+
+/// Encoder of lossless png images with adaptive filtering.
+///
+/// The quality of the `Factor` is not used, and the alpha channel is kept if the image has one.
+/// A 16-bit image is kept with 16 bits per channel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct PngEncoder {
+    compression: CompressionType,
+}
+
+impl PngEncoder {
+    /// Create a new `PngEncoder` with the compression level.
+    pub fn new(compression: CompressionType) -> Self {
+        PngEncoder { compression }
+    }
+}
+
+impl OutputEncoder for PngEncoder {
+    fn encode(
+        &self,
+        pixels: ResizedPixels,
+        width: u32,
+        height: u32,
+        _: Factor,
+    ) -> Result<Vec<u8>, CompressError> {
+        // The 16-bit values are given to the png encoder as bytes in the native endian.
+        let to_bytes =
+            |pixels: Vec<u16>| -> Vec<u8> { pixels.iter().flat_map(|v| v.to_ne_bytes()).collect() };
+        let (pixels, color_type) = match pixels {
+            ResizedPixels::Rgb(pixels) => (pixels, ExtendedColorType::Rgb8),
+            ResizedPixels::Rgba(pixels) => (pixels, ExtendedColorType::Rgba8),
+            ResizedPixels::Rgb16(pixels) => (to_bytes(pixels), ExtendedColorType::Rgb16),
+            ResizedPixels::Rgba16(pixels) => (to_bytes(pixels), ExtendedColorType::Rgba16),
+        };
+        let mut encoded = Vec::new();
+        ImagePngEncoder::new_with_quality(&mut encoded, self.compression, PngFilterType::Adaptive)
+            .write_image(&pixels, width, height, color_type)
+            .map_err(|e| CompressError::Encode(e.to_string()))?;
+        Ok(encoded)
+    }
+
+    fn extension(&self) -> &str {
+        "png"
+    }
+
+    fn supports_16bit(&self) -> bool {
+        true
+    }
+}
+
+/// Encoder of lossy webp images with the quality of the `Factor`.
+///
+/// libwebp keeps more details than mozjpeg at the same quality number,
+/// so the unified quality is lowered to the webp quality of about the same SSIM, e.g. 80 to 65.
+/// Earlier versions passed the quality to libwebp as it is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct WebpEncoder;
+
+impl OutputEncoder for WebpEncoder {
+    fn encode(
+        &self,
+        pixels: ResizedPixels,
+        width: u32,
+        height: u32,
+        factor: Factor,
+    ) -> Result<Vec<u8>, CompressError> {
+        let pixels = pixels.into_8bit();
+        let encoded = match &pixels {
+            ResizedPixels::Rgb(pixels) => webp::Encoder::from_rgb(pixels, width, height),
+            ResizedPixels::Rgba(pixels) => webp::Encoder::from_rgba(pixels, width, height),
+            ResizedPixels::Rgb16(_) | ResizedPixels::Rgba16(_) => {
+                unreachable!("the pixels are converted to 8 bits")
+            }
+        }
+        .encode(self.native_quality(factor.quality()));
+        Ok(encoded.to_vec())
+    }
+
+    fn extension(&self) -> &str {
+        "webp"
+    }
+
+    fn native_quality(&self, quality: f32) -> f32 {
+        interpolate(WEBP_QUALITY_POINTS, quality)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ssim::ssim;
+
+    #[test]
+    fn webp_native_quality_test() {
+        assert_eq!(WebpEncoder.native_quality(0.), 0.);
+        assert_eq!(WebpEncoder.native_quality(80.), 65.);
+        assert_eq!(WebpEncoder.native_quality(85.), 71.5);
+        assert_eq!(WebpEncoder.native_quality(100.), 100.);
+        assert_eq!(JpegEncoder::new().native_quality(80.), 80.);
+    }
+
+    #[test]
+    fn unified_quality_test() {
+        let image = image::RgbImage::from_fn(256, 256, |x, y| {
+            let (fx, fy) = (x as f32 / 256., y as f32 / 256.);
+            let noise = ((x * 7919 + y * 104729) % 37) as f32;
+            image::Rgb([
+                (((fx * 12.).sin() * 0.5 + 0.5) * 200. + noise) as u8,
+                (((fy * 9.).cos() * 0.5 + 0.5) * 200. + noise) as u8,
+                ((((fx + fy) * 20.).sin() * 0.5 + 0.5) * 220.) as u8,
+            ])
+        });
+        let reference = image::DynamicImage::ImageRgb8(image.clone()).into_luma8();
+        let encoded_ssim = |encoder: &dyn OutputEncoder| {
+            let pixels = ResizedPixels::Rgb(image.clone().into_vec());
+            let encoded = encoder
+                .encode(pixels, 256, 256, Factor::new(80., 1.))
+                .unwrap();
+            ssim(
+                &reference,
+                &image::load_from_memory(&encoded).unwrap().into_luma8(),
+            )
+        };
+        let jpg_ssim = encoded_ssim(&JpegEncoder::new());
+        let webp_ssim = encoded_ssim(&WebpEncoder);
+        assert!(
+            (jpg_ssim - webp_ssim).abs() < 0.02,
+            "jpg: {}, webp: {}",
+            jpg_ssim,
+            webp_ssim
+        );
+    }
+}
@@ -0,0 +1,209 @@
+//! Error type of the compression.
+//!
+//! Every compress function returns a [`CompressError`],
+//! so the callers can handle each cause of the failure differently.
+//! # Examples
+//! ```no_run
+//! use image_compressor::compressor::Compressor;
+//! use image_compressor::CompressError;
+//!
+//! let compressor = Compressor::new("source.png", "dest");
+//! match compressor.compress_to_jpg() {
+//!     Ok(path) => println!("Compressed: {}", path.display()),
+//!     Err(CompressError::AlreadyExists(path)) => println!("Skipped: {}", path.display()),
+//!     Err(e) => println!("Cannot compress the image: {}", e),
+//! }
+//! ```
+
+use image::{ImageError, ImageFormat};
+use std::error::Error;
+use std::path::PathBuf;
+use std::{fmt, io};
+
+/// Error occurred while compressing images.
+#[derive(Debug)]
+pub enum CompressError {
+    /// Reading or writing a file failed.
+    Io(io::Error),
+
+    /// The source file cannot be decoded as an image.
+    Decode(ImageError),
+
+    /// The image cannot be encoded to the new format.
+    Encode(String),
+
+    /// The file of the new image already exists in the destination directory
+    /// and the overwrite flag is false.
+    AlreadyExists(PathBuf),
+
+    /// The source file is not an image that can be decoded.
+    /// `format` is the format detected by the content, `None` if it is not recognized as an image,
+    /// and `reason` tells why the image cannot be decoded.
+    UnsupportedInput {
+        path: PathBuf,
+        format: Option<ImageFormat>,
+        reason: String,
+    },
+
+    /// The source file cannot be opened as an image and is skipped
+    /// with [`UnreadablePolicy::Skip`](crate::compressor::UnreadablePolicy::Skip).
+    Skipped(PathBuf),
+
+    /// The image cannot be compressed under `max_bytes` even with the lowest quality.
+    /// `smallest` is the file size in bytes with the lowest quality.
+    TargetSizeUnreachable {
+        path: PathBuf,
+        max_bytes: u64,
+        smallest: u64,
+    },
+
+    /// The output extension is not one that the encoder produces.
+    UnsupportedExtension(String),
+
+    /// The folder compression is aborted by a failed file with the error message.
+    Aborted(String),
+
+    /// The folder compression is cancelled after `processed` of `total` files.
+    Cancelled { processed: usize, total: usize },
+
+    /// `panicked` of the `workers` threads of the folder compression panicked.
+    /// `message` is the panic message of the first one.
+    WorkerPanicked {
+        panicked: usize,
+        workers: usize,
+        message: String,
+    },
+}
+
+impl fmt::Display for CompressError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CompressError::Io(e) => write!(f, "{}", e),
+            CompressError::Decode(e) => write!(f, "Cannot open file as image: {}", e),
+            CompressError::Encode(e) => write!(f, "{}", e),
+            CompressError::AlreadyExists(path) => write!(
+                f,
+                "A file with the same name exists: {}",
+                path.file_name().unwrap_or_default().to_string_lossy()
+            ),
+            CompressError::UnsupportedInput {
+                path, format: None, ..
+            } => write!(f, "Unrecognized image format: {}", path.display()),
+            CompressError::UnsupportedInput {
+                path,
+                format: Some(format),
+                reason,
+            } => write!(
+                f,
+                "Unsupported {:?} image: {}: {}",
+                format,
+                path.display(),
+                reason
+            ),
+            CompressError::Skipped(path) => write!(
+                f,
+                "Skipped the file that cannot be opened as an image: {}",
+                path.display()
+            ),
+            CompressError::TargetSizeUnreachable {
+                path,
+                max_bytes,
+                smallest,
+            } => write!(
+                f,
+                "Cannot compress file {} under {} bytes: {} bytes at the lowest quality",
+                path.file_name().unwrap_or_default().to_string_lossy(),
+                max_bytes,
+                smallest
+            ),
+            CompressError::UnsupportedExtension(extension) => write!(
+                f,
+                "The encoder does not produce files with the extension: {}",
+                extension
+            ),
+            CompressError::Aborted(e) => write!(f, "Compression is aborted: {}", e),
+            CompressError::Cancelled { processed, total } => write!(
+                f,
+                "Compression is cancelled: {} of {} files are processed",
+                processed, total
+            ),
+            CompressError::WorkerPanicked {
+                panicked,
+                workers,
+                message,
+            } => write!(
+                f,
+                "Compression is aborted: {} of {} worker threads panicked: {}",
+                panicked, workers, message
+            ),
+        }
+    }
+}
+
+impl Error for CompressError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            CompressError::Io(e) => Some(e),
+            CompressError::Decode(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for CompressError {
+    fn from(e: io::Error) -> Self {
+        CompressError::Io(e)
+    }
+}
+
+impl From<ImageError> for CompressError {
+    fn from(e: ImageError) -> Self {
+        CompressError::Decode(e)
+    }
+}
+
+/// Error of a [`Factor`](crate::Factor) value out of range.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FactorError {
+    /// The quality is not greater than 0 and at most 100.
+    Quality(f32),
+
+    /// The size ratio is not greater than 0 and at most 1.
+    SizeRatio(f32),
+}
+
+impl fmt::Display for FactorError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FactorError::Quality(quality) => {
+                write!(f, "Quality must be in (0, 100], got {}", quality)
+            }
+            FactorError::SizeRatio(size_ratio) => {
+                write!(f, "Size ratio must be in (0, 1], got {}", size_ratio)
+            }
+        }
+    }
+}
+
+impl Error for FactorError {}
+
+/// Error of a builder with a required option that is not set.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BuildError {
+    /// The source is not set.
+    MissingSource,
+
+    /// The destination is not set.
+    MissingDest,
+}
+
+impl fmt::Display for BuildError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BuildError::MissingSource => write!(f, "The source is not set"),
+            BuildError::MissingDest => write!(f, "The destination is not set"),
+        }
+    }
+}
+
+impl Error for BuildError {}
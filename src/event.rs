@@ -0,0 +1,113 @@
+//! Events sent while compressing a folder.
+//!
+//! Set a [`Sender`](std::sync::mpsc::Sender) of [`CompressionEvent`] with
+//! [`FolderCompressor::set_event_sender`](super::FolderCompressor::set_event_sender)
+//! to receive the progress of the compression without parsing text messages.
+
+use std::fmt;
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// Progress of a folder compression.
+#[derive(Debug, Clone, PartialEq)]
+pub enum CompressionEvent {
+    /// The compression is started with `total` files to compress.
+    Started { total: usize },
+
+    /// A file is compressed.
+    /// `path` is the path of the new compressed file, which is not written in a dry run,
+    /// `original` and `compressed` are the file sizes in bytes before and after the compression.
+    FileDone {
+        path: PathBuf,
+        original: u64,
+        compressed: u64,
+    },
+
+    /// A file cannot be compressed.
+    /// `path` is the path of the source file.
+    FileFailed { path: PathBuf, error: String },
+
+    /// A file is skipped because its output already exists in the destination directory,
+    /// because it cannot be compressed with [`FileErrorPolicy::Skip`](super::FileErrorPolicy::Skip),
+    /// or because it is a small jpg image copied as it is
+    /// with [`FolderCompressor::set_skip_if_smaller_than`](super::FolderCompressor::set_skip_if_smaller_than).
+    /// `path` is the path of the source file.
+    FileSkipped { path: PathBuf, reason: String },
+
+    /// `completed` of `total` files are processed. It is sent after each file,
+    /// so a determinate progress bar can be drawn from it.
+    /// `eta` is the remaining time estimated from the average time per file so far.
+    /// It is only sent to the event sender, not as a message to the `Sender<String>`.
+    Progress {
+        completed: usize,
+        total: usize,
+        eta: Duration,
+    },
+
+    /// The total size of the compressed images reached the budget set by
+    /// [`FolderCompressor::set_max_total_output`](super::FolderCompressor::set_max_total_output),
+    /// so no more files are compressed.
+    /// `written` is the total size in bytes and `max_bytes` is the budget.
+    BudgetReached { written: u64, max_bytes: u64 },
+
+    /// The source directories are deleted after the compression.
+    SourceDeleted,
+
+    /// The source directories cannot be deleted after the compression.
+    SourceDeleteFailed { error: String },
+
+    /// The total file sizes of a dry run, sent before [`CompressionEvent::Finished`].
+    /// `original` and `compressed` are the sums of the file sizes in bytes of the compressed files.
+    DryRunSummary { original: u64, compressed: u64 },
+
+    /// Every file is processed. This is the last event of a compression.
+    Finished,
+}
+
+impl fmt::Display for CompressionEvent {
+    /// Format the event as the message sent to the `Sender<String>`.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CompressionEvent::Started { total } => write!(f, "Total file count: {}", total),
+            CompressionEvent::FileDone { path, .. } => write!(
+                f,
+                "Compress complete! File: {}",
+                path.file_name().unwrap_or_default().to_string_lossy()
+            ),
+            CompressionEvent::FileFailed { error, .. } => write!(f, "{}", error),
+            CompressionEvent::FileSkipped { reason, .. } => write!(f, "Skipped: {}", reason),
+            CompressionEvent::Progress {
+                completed,
+                total,
+                eta,
+            } => write!(
+                f,
+                "Progress: {}/{} ({:.0}%), ETA {}s",
+                completed,
+                total,
+                *completed as f64 / *total as f64 * 100.,
+                eta.as_secs()
+            ),
+            CompressionEvent::BudgetReached { written, max_bytes } => write!(
+                f,
+                "Output budget reached: {} of {} bytes are written",
+                written, max_bytes
+            ),
+            CompressionEvent::SourceDeleted => write!(f, "Delete source directories complete!"),
+            CompressionEvent::SourceDeleteFailed { error } => {
+                write!(f, "Cannot delete source directories: {}", error)
+            }
+            CompressionEvent::DryRunSummary {
+                original,
+                compressed,
+            } => write!(
+                f,
+                "Dry run: {} bytes -> {} bytes, saves {} bytes",
+                original,
+                compressed,
+                original.saturating_sub(*compressed)
+            ),
+            CompressionEvent::Finished => write!(f, "Compress complete!"),
+        }
+    }
+}
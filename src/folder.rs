@@ -0,0 +1,2593 @@
+//! Compress all images in a folder with multiple threads.
+//!
+//! Available with the `folder` feature, which is enabled by default.
+
+use crate::compressor::{Compressor, OutputFormat, UnreadablePolicy};
+use crate::crawler::{filter_by_extensions, get_file_list, get_file_list_with_ignore};
+use crate::dir::delete_recursive;
+use crate::{
+    CalFunc, CompressError, CompressionEvent, CompressionReport, Factor, FileResult, FilterType,
+    FolderSummary,
+};
+use crossbeam_queue::SegQueue;
+use log::{debug, error, info, warn};
+use rayon::prelude::*;
+use std::any::Any;
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::Write;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::{self, Sender};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use std::{fs, io};
+use zip::write::SimpleFileOptions;
+use zip::{CompressionMethod, ZipWriter};
+
+fn send_message<T: ToString>(sender: &Sender<T>, message: T) {
+    match sender.send(message) {
+        Ok(_) => (),
+        Err(e) => warn!("Message passing error: {}", e),
+    }
+}
+
+/// Compress all images in the source directory into the destination directory with one call.
+///
+/// It is a shortcut of [`FolderCompressor`] with the default settings except the `factor` and the number of `threads`.
+/// If `threads` is 0, the rayon thread pool sized to the machine is used, see [`FolderCompressor::set_thread_count`].
+/// Use [`FolderCompressor`] for the other settings.
+/// # Examples
+/// ```
+/// use image_compressor::{compress_all, Factor};
+///
+/// # std::fs::create_dir_all("compress_all_source").unwrap();
+/// # image::RgbImage::new(64, 64).save("compress_all_source/black.png").unwrap();
+/// let summary = compress_all("compress_all_source", "compress_all_dest", Factor::new(80., 0.8), 4).unwrap();
+/// assert_eq!(summary.succeeded, 1);
+/// # std::fs::remove_dir_all("compress_all_source").unwrap();
+/// # std::fs::remove_dir_all("compress_all_dest").unwrap();
+/// ```
+pub fn compress_all<O: AsRef<Path>, D: AsRef<Path>>(
+    source: O,
+    dest: D,
+    factor: Factor,
+    threads: u32,
+) -> Result<FolderSummary, CompressError> {
+    let mut comp = FolderCompressor::new(source, dest);
+    comp.set_factor(factor);
+    comp.set_thread_count(threads);
+    comp.compress()
+}
+
+/// What [`FolderCompressor`] does when a file cannot be compressed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum FileErrorPolicy {
+    /// Skip the file, sending a [`CompressionEvent::FileSkipped`] event.
+    Skip,
+
+    /// Copy the file to the destination directory as it is.
+    #[default]
+    Copy,
+
+    /// Stop compressing the remaining files and make [`FolderCompressor::compress`] return an `Err`.
+    Abort,
+}
+
+/// Order in which [`FolderCompressor`] compresses the files.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Schedule {
+    /// Compress the files in the order they are found in the source directory.
+    #[default]
+    AsDiscovered,
+
+    /// Compress the largest files first, so that the threads do not wait for a few huge files at the end.
+    LargestFirst,
+}
+
+/// Sort the files in the order of the schedule.
+fn schedule_files(mut files: Vec<PathBuf>, schedule: Schedule) -> Vec<PathBuf> {
+    if schedule == Schedule::LargestFirst {
+        files.sort_by_cached_key(|file| {
+            std::cmp::Reverse(fs::metadata(file).map(|m| m.len()).unwrap_or(0))
+        });
+    }
+    files
+}
+
+/// State shared by the threads of a folder compression.
+#[derive(Default)]
+struct JobState {
+    /// The first error of a file that aborts the compression.
+    abort_error: Mutex<Option<String>>,
+
+    /// Results of the processed files.
+    results: Mutex<Vec<FileResult>>,
+
+    /// File stems of the new images for each source file, used when the output is flattened.
+    flat_stems: HashMap<PathBuf, String>,
+
+    /// Budget of the memory for the decoded images, if it is limited.
+    memory_budget: Option<Arc<MemoryBudget>>,
+
+    /// Zip archive that the compressed images are written into, if it is set.
+    archive: Option<Mutex<ZipWriter<File>>>,
+
+    /// The number of the files to compress.
+    total: usize,
+
+    /// The time when the files start to be processed, used to estimate the remaining time.
+    started: Option<Instant>,
+
+    /// Total size in bytes of the compressed images.
+    output_bytes: AtomicU64,
+
+    /// Whether the total size of the compressed images reached the budget.
+    budget_reached: AtomicBool,
+}
+
+impl JobState {
+    /// Estimate the remaining time from the average time per file of the `completed` files.
+    fn eta(&self, completed: usize) -> Duration {
+        let elapsed = self.started.map(|s| s.elapsed()).unwrap_or_default();
+        let remaining = self.total.saturating_sub(completed);
+        elapsed.mul_f64(remaining as f64 / completed.max(1) as f64)
+    }
+}
+
+/// Shared budget of the memory for the images being compressed at the same time.
+struct MemoryBudget {
+    max_bytes: u64,
+    inflight_bytes: Mutex<u64>,
+    released: Condvar,
+}
+
+impl MemoryBudget {
+    fn new(max_bytes: u64) -> Self {
+        MemoryBudget {
+            max_bytes,
+            inflight_bytes: Mutex::new(0),
+            released: Condvar::new(),
+        }
+    }
+
+    /// Reserve `bytes` in the budget, blocking until there is room.
+    ///
+    /// An image larger than the whole budget is allowed when no other image is in flight,
+    /// so that it is compressed alone instead of blocking forever.
+    fn reserve(self: &Arc<Self>, bytes: u64) -> MemoryReservation {
+        let mut inflight_bytes = self.inflight_bytes.lock().unwrap();
+        while *inflight_bytes > 0 && *inflight_bytes + bytes > self.max_bytes {
+            inflight_bytes = self.released.wait(inflight_bytes).unwrap();
+        }
+        *inflight_bytes += bytes;
+        MemoryReservation {
+            budget: Arc::clone(self),
+            bytes,
+        }
+    }
+}
+
+/// Reserved memory in the budget, released when dropped.
+///
+/// It can be sent to another thread with the data it is reserved for, e.g. by the reader of the prefetch.
+struct MemoryReservation {
+    budget: Arc<MemoryBudget>,
+    bytes: u64,
+}
+
+impl Drop for MemoryReservation {
+    fn drop(&mut self) {
+        *self.budget.inflight_bytes.lock().unwrap() -= self.bytes;
+        self.budget.released.notify_all();
+    }
+}
+
+/// Iterator of the reports of [`FolderCompressor::compress_iter`], which cancels the compression when dropped.
+struct CompressIter {
+    receiver: mpsc::IntoIter<Result<CompressionReport, CompressError>>,
+    dropped: Arc<AtomicBool>,
+}
+
+impl Iterator for CompressIter {
+    type Item = Result<CompressionReport, CompressError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.receiver.next()
+    }
+}
+
+impl Drop for CompressIter {
+    fn drop(&mut self) {
+        self.dropped.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Estimate the memory to decode the image as `width * height * 4` bytes, the size of the RGBA pixels.
+/// The image header is read to get the size, and 0 is returned if the file is not an image.
+fn estimate_decoded_size(file: &Path) -> u64 {
+    match image::image_dimensions(file) {
+        Ok((width, height)) => width as u64 * height as u64 * 4,
+        Err(_) => 0,
+    }
+}
+
+/// Make the name of the entry in a zip archive from the relative path, separated by `/`.
+fn archive_entry_name(path: &Path) -> String {
+    path.components()
+        .map(|c| c.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// Give each file a file stem that is unique in the list.
+///
+/// The files are sorted first, so the same file gets the same stem in every run.
+/// The first file keeps its stem, and the others get a counter appended like `photo_1`.
+fn unique_file_stems(files: &[PathBuf]) -> HashMap<PathBuf, String> {
+    let mut sorted_files = files.to_vec();
+    sorted_files.sort();
+    let mut used_stems = HashSet::new();
+    let mut stems = HashMap::new();
+    for file in sorted_files {
+        let stem = file
+            .file_stem()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .to_string();
+        let mut unique_stem = stem.clone();
+        let mut counter = 1;
+        while !used_stems.insert(unique_stem.clone()) {
+            unique_stem = format!("{}_{}", stem, counter);
+            counter += 1;
+        }
+        stems.insert(file, unique_stem);
+    }
+    stems
+}
+
+/// Check whether the error is caused by an output file that already exists.
+fn is_already_exists(e: &CompressError) -> bool {
+    matches!(e, CompressError::AlreadyExists(_))
+}
+
+/// Check whether the file is a complete image, not a truncated leftover of an interrupted write.
+fn is_complete_image(path: &Path) -> bool {
+    let Ok(data) = fs::read(path) else {
+        return false;
+    };
+    // A jpg image must end with the end of image marker, which the decoder does not require.
+    if data.starts_with(&[0xFF, 0xD8]) && !data.ends_with(&[0xFF, 0xD9]) {
+        return false;
+    }
+    image::load_from_memory(&data).is_ok()
+}
+
+/// Make the report of the output that is already made by a previous run.
+fn resumed_report(file: &Path, output: PathBuf) -> CompressionReport {
+    CompressionReport {
+        original_size: fs::metadata(file).map(|m| m.len()).unwrap_or(0),
+        compressed_size: fs::metadata(&output).map(|m| m.len()).unwrap_or(0),
+        original_dimensions: image::image_dimensions(file).unwrap_or_default(),
+        dimensions: image::image_dimensions(&output).unwrap_or_default(),
+        kept_original: false,
+        skipped: false,
+        decode_time: Duration::ZERO,
+        resize_time: Duration::ZERO,
+        encode_time: Duration::ZERO,
+        path: output,
+    }
+}
+
+/// Shared function called with the report of each compressed file.
+type OnFileFunc = Arc<dyn Fn(&CompressionReport) + Send + Sync>;
+
+/// Compressor struct for a directory.
+#[derive(Clone)]
+pub struct FolderCompressor {
+    factor: Factor,
+    cal_func: Option<CalFunc>,
+    min_quality: Option<f32>,
+    on_file: Option<OnFileFunc>,
+    filter_type: FilterType,
+    filter_for_size: Option<(FilterType, FilterType, u64)>,
+    source_path: PathBuf,
+    dest_path: PathBuf,
+    thread_count: u32,
+    prefetch: bool,
+    delete_source: bool,
+    overwrite: bool,
+    resume: bool,
+    skip_if_smaller_than: Option<u64>,
+    preserve_timestamps: bool,
+    flatten: bool,
+    dry_run: bool,
+    max_inflight_bytes: Option<u64>,
+    max_total_output: Option<u64>,
+    schedule: Schedule,
+    output_archive: Option<PathBuf>,
+    file_error_policy: FileErrorPolicy,
+    sender: Option<Sender<String>>,
+    event_sender: Option<Sender<CompressionEvent>>,
+    json_sender: Option<Sender<String>>,
+    cancel_token: Option<Arc<AtomicBool>>,
+    iter_dropped: Option<Arc<AtomicBool>>,
+    extensions: Option<Vec<String>>,
+    format_map: HashMap<String, OutputFormat>,
+    ignore_file: Option<String>,
+}
+
+impl FolderCompressor {
+    /// Create a new `FolderCompressor` instance.
+    /// Just needs source directory path and destination directory path.
+    /// The source can also be a single image file, which is compressed into the destination directory.
+    /// If you do not set the quality calculation function,
+    /// it will use the default calculation function which sets the quality only by the file size.
+    /// Likewise, if you do not set the number of threads, only one thread is used by default.\
+    /// # Examples
+    /// ```
+    /// use image_compressor::FolderCompressor;
+    /// use std::path::Path;
+    ///
+    /// let source = Path::new("source");
+    /// let dest = Path::new("dest");
+    ///
+    /// let comp = FolderCompressor::new(source, dest);
+    /// ```
+    pub fn new<O: AsRef<Path>, D: AsRef<Path>>(source_path: O, dest_path: D) -> Self {
+        FolderCompressor {
+            factor: Factor::default(),
+            cal_func: None,
+            min_quality: None,
+            on_file: None,
+            filter_type: FilterType::Triangle,
+            filter_for_size: None,
+            source_path: source_path.as_ref().to_path_buf(),
+            dest_path: dest_path.as_ref().to_path_buf(),
+            thread_count: 1,
+            prefetch: false,
+            delete_source: false,
+            overwrite: false,
+            resume: false,
+            skip_if_smaller_than: None,
+            preserve_timestamps: false,
+            flatten: false,
+            dry_run: false,
+            max_inflight_bytes: None,
+            max_total_output: None,
+            schedule: Schedule::default(),
+            output_archive: None,
+            file_error_policy: FileErrorPolicy::default(),
+            sender: None,
+            event_sender: None,
+            json_sender: None,
+            cancel_token: None,
+            iter_dropped: None,
+            extensions: None,
+            format_map: HashMap::new(),
+            ignore_file: None,
+        }
+    }
+
+    /// Set Factor using to compress images.
+    pub fn set_factor(&mut self, factor: Factor) {
+        self.factor = factor;
+    }
+
+    /// Set a calculator function that returns a `Factor` for each image.
+    ///
+    /// The function gets the width, the height, and the file size in bytes of the source image,
+    /// so the factor can differ by file. e.g. larger files can be compressed more.
+    /// When the function is set, the `Factor` set by [`set_factor`](FolderCompressor::set_factor) is ignored.
+    /// # Examples
+    /// ```
+    /// use image_compressor::FolderCompressor;
+    /// use image_compressor::Factor;
+    ///
+    /// let mut comp = FolderCompressor::new("source", "dest");
+    /// comp.set_cal_func(|width, height, file_size| {
+    ///     if file_size > 1024 * 1024 || width * height > 1920 * 1080 {
+    ///         Factor::new(60., 0.6)
+    ///     } else {
+    ///         Factor::new(80., 0.8)
+    ///     }
+    /// });
+    /// ```
+    pub fn set_cal_func<F>(&mut self, cal_func: F)
+    where
+        F: Fn(u32, u32, u64) -> Factor + Send + Sync + 'static,
+    {
+        self.cal_func = Some(Arc::new(cal_func));
+    }
+
+    /// Set the lowest quality that the images are compressed with.
+    /// The value is clamped to 0 to 100. See [`Compressor::set_min_quality`].
+    pub fn set_min_quality(&mut self, min_quality: f32) {
+        self.min_quality = Some(min_quality.clamp(0., 100.));
+    }
+
+    /// Set the function called with the report of each compressed file.
+    ///
+    /// It is called from the worker threads after each file is compressed,
+    /// so it should be thread-safe, e.g. accumulate the statistics with atomics or a `Mutex`.
+    /// Failed and skipped files are not reported to it.
+    /// # Examples
+    /// ```
+    /// use image_compressor::FolderCompressor;
+    /// use std::sync::atomic::{AtomicU64, Ordering};
+    /// use std::sync::Arc;
+    ///
+    /// let saved_bytes = Arc::new(AtomicU64::new(0));
+    /// let counter = Arc::clone(&saved_bytes);
+    /// let mut comp = FolderCompressor::new("source", "dest");
+    /// comp.set_on_file(move |report| {
+    ///     let saved = report.original_size.saturating_sub(report.compressed_size);
+    ///     counter.fetch_add(saved, Ordering::Relaxed);
+    /// });
+    /// ```
+    pub fn set_on_file<F>(&mut self, on_file: F)
+    where
+        F: Fn(&CompressionReport) + Send + Sync + 'static,
+    {
+        self.on_file = Some(Arc::new(on_file));
+    }
+
+    /// Set the filter used to resize images. The default is [`FilterType::Triangle`].
+    pub fn set_filter_type(&mut self, filter_type: FilterType) {
+        self.filter_type = filter_type;
+    }
+
+    /// Choose the filter used to resize each image by its number of pixels, instead of the filter type.
+    ///
+    /// Images with fewer than `threshold_px` pixels are resized with the fast `small_filter`,
+    /// and the others with the quality `large_filter`.
+    /// It speeds up batches of many tiny images, such as avatars, mixed with a few large ones.
+    pub fn set_filter_for_size(
+        &mut self,
+        small_filter: FilterType,
+        large_filter: FilterType,
+        threshold_px: u64,
+    ) {
+        self.filter_for_size = Some((small_filter, large_filter, threshold_px));
+    }
+
+    /// Set whether to delete source files.
+    ///
+    /// After the compression, the source directories left empty are deleted too.
+    /// Hidden files such as `.DS_Store` are not compressed and do not keep their directories.
+    pub fn set_delete_source(&mut self, to_delete: bool) {
+        self.delete_source = to_delete;
+    }
+
+    /// Set whether to replace the files that already exist in the destination directory.
+    ///
+    /// The default is false, and the files whose output already exists are skipped
+    /// with a [`CompressionEvent::FileSkipped`] event.
+    pub fn set_overwrite(&mut self, overwrite: bool) {
+        self.overwrite = overwrite;
+    }
+
+    /// Set whether to resume a previous run that is interrupted. The default is false.
+    ///
+    /// A file whose output already exists as a complete image is skipped as a success,
+    /// with a [`CompressionEvent::FileSkipped`] event and the report of the existing output.
+    /// An output that cannot be read as a complete image, such as a truncated leftover, is compressed again.
+    /// So the same job can run every night and only compresses the files that are not done yet.
+    pub fn set_resume(&mut self, resume: bool) {
+        self.resume = resume;
+    }
+
+    /// Set the file size in bytes under which a jpg source file is copied as it is without the compression.
+    /// The default is no threshold.
+    ///
+    /// Such a file is counted as a success, with a [`CompressionEvent::FileSkipped`] event
+    /// and the report whose [`skipped`](CompressionReport::skipped) is true.
+    /// See [`Compressor::set_skip_if_smaller_than`].
+    pub fn set_skip_if_smaller_than(&mut self, bytes: u64) {
+        self.skip_if_smaller_than = Some(bytes);
+    }
+
+    /// Set whether the compressed images keep the modified and accessed times of the source files.
+    /// The default is false.
+    pub fn set_preserve_timestamps(&mut self, preserve_timestamps: bool) {
+        self.preserve_timestamps = preserve_timestamps;
+    }
+
+    /// Set whether to write every compressed file directly into the destination directory.
+    /// The default is false, which mirrors the directory structure of the source directory.
+    ///
+    /// When flattened, files with the same name in different directories would collide.
+    /// Such files are given distinct names by appending a counter to the file stem, e.g. `photo_1.jpg`.
+    /// The files are numbered in the order of their source paths, so the names are the same in every run.
+    pub fn set_flatten(&mut self, flatten: bool) {
+        self.flatten = flatten;
+    }
+
+    /// Set whether to mirror the directory structure of the source directory in the destination directory.
+    /// The default is true. It is the opposite of [`set_flatten`](FolderCompressor::set_flatten).
+    ///
+    /// A file whose path is not relative to the source directory, e.g. a symbolic link resolved outside of it,
+    /// is written directly into the destination directory even if the tree is preserved.
+    pub fn set_preserve_tree(&mut self, preserve_tree: bool) {
+        self.flatten = !preserve_tree;
+    }
+
+    /// Set whether to only measure the compression without writing or deleting any file. The default is false.
+    ///
+    /// In a dry run, every image is decoded, resized, and compressed in memory,
+    /// and [`CompressionEvent::FileDone`] events report the would-be sizes of the files.
+    /// The total sizes are sent with a [`CompressionEvent::DryRunSummary`] event at the end.
+    /// Neither the compressed files nor the copies of the failed files are written,
+    /// and the source files are not deleted even if [`set_delete_source`](FolderCompressor::set_delete_source) is set.
+    pub fn set_dry_run(&mut self, dry_run: bool) {
+        self.dry_run = dry_run;
+    }
+
+    /// Limit the memory used by the images being compressed at the same time.
+    ///
+    /// Before a thread decodes a file, it reserves the estimated memory of the decoded image in the budget,
+    /// and waits until the other threads release enough memory.
+    /// It bounds the peak memory regardless of the number of threads.
+    /// The memory is estimated as `width * height * 4` bytes from the image header,
+    /// plus the size of the file if it is read ahead by [`set_prefetch`](FolderCompressor::set_prefetch).
+    /// An image larger than the whole budget is compressed alone.
+    pub fn set_max_inflight_bytes(&mut self, max_inflight_bytes: u64) {
+        self.max_inflight_bytes = Some(max_inflight_bytes);
+    }
+
+    /// Limit the total size in bytes of the compressed images of the compression.
+    ///
+    /// The threads add the size of each compressed image to the total,
+    /// and stop taking new files once it reaches the budget,
+    /// sending a [`CompressionEvent::BudgetReached`] event. The files being compressed are finished.
+    /// The remaining files are not processed, and [`compress`](FolderCompressor::compress) still returns `Ok`.
+    pub fn set_max_total_output(&mut self, max_total_output: u64) {
+        self.max_total_output = Some(max_total_output);
+    }
+
+    /// Set the order in which the files are compressed. The default is [`Schedule::AsDiscovered`].
+    ///
+    /// [`Schedule::LargestFirst`] usually shortens the total time for files of mixed sizes.
+    pub fn set_schedule(&mut self, schedule: Schedule) {
+        self.schedule = schedule;
+    }
+
+    /// Write every compressed file into a single zip archive at the path, instead of the destination directory.
+    ///
+    /// The relative paths of the files in the source directory are kept as the names of the entries,
+    /// and the images are stored without further compression since jpg images are already compressed.
+    /// The threads compress the files in memory and write them into the archive one at a time.
+    /// The destination directory is not used, and the archive is not written in a dry run.
+    /// # Examples
+    /// ```
+    /// use image_compressor::FolderCompressor;
+    ///
+    /// let mut comp = FolderCompressor::new("source", "dest");
+    /// comp.set_output_archive("compressed.zip");
+    /// ```
+    pub fn set_output_archive<P: AsRef<Path>>(&mut self, path: P) {
+        self.output_archive = Some(path.as_ref().to_path_buf());
+    }
+
+    /// Set what to do when a file cannot be compressed. The default is [`FileErrorPolicy::Copy`].
+    ///
+    /// With [`FileErrorPolicy::Abort`], a single corrupt file fails the whole compression.
+    pub fn set_file_error_policy(&mut self, policy: FileErrorPolicy) {
+        self.file_error_policy = policy;
+    }
+
+    /// Set Sender for message passing.
+    /// If you set a sender, the method sends messages whether compressing is complete.
+    pub fn set_sender(&mut self, sender: Sender<String>) {
+        self.sender = Some(sender);
+    }
+
+    /// Set Sender for typed [`CompressionEvent`]s.
+    /// It can be used with the `Sender<String>` set by [`set_sender`](FolderCompressor::set_sender) at the same time.
+    /// # Examples
+    /// ```
+    /// use std::sync::mpsc;
+    /// use image_compressor::{CompressionEvent, FolderCompressor};
+    ///
+    /// let (tx, rx) = mpsc::channel();
+    /// let mut comp = FolderCompressor::new("source_dir", "dest_dir");
+    /// comp.set_event_sender(tx);
+    /// let _ = comp.compress();
+    ///
+    /// for event in rx.try_iter() {
+    ///     if let CompressionEvent::FileDone { original, compressed, .. } = event {
+    ///         println!("{} bytes -> {} bytes", original, compressed);
+    ///     }
+    /// }
+    /// ```
+    pub fn set_event_sender(&mut self, sender: Sender<CompressionEvent>) {
+        self.event_sender = Some(sender);
+    }
+
+    /// Set Sender for a JSON record of each processed file, for log collectors.
+    ///
+    /// A record is a single line like
+    /// `{"path":"source/photo.png","status":"done","orig_bytes":1024,"new_bytes":512,"ms":12}`.
+    /// `path` is the source file, `status` is one of `done`, `failed`, and `skipped`,
+    /// and `new_bytes` is `null` unless the file is compressed.
+    /// It can be used with the other senders at the same time.
+    pub fn set_json_sender(&mut self, sender: Sender<String>) {
+        self.json_sender = Some(sender);
+    }
+
+    /// Set a flag to cancel the compression from another thread.
+    ///
+    /// When the flag is set to true, each thread stops before compressing its next file,
+    /// and [`compress`](FolderCompressor::compress) returns an `Err` with the number of processed files.
+    /// The source directory is not deleted after a cancelled compression.
+    /// # Examples
+    /// ```
+    /// use std::sync::atomic::{AtomicBool, Ordering};
+    /// use std::sync::Arc;
+    /// use image_compressor::FolderCompressor;
+    ///
+    /// let cancel_token = Arc::new(AtomicBool::new(false));
+    /// let mut comp = FolderCompressor::new("source_dir", "dest_dir");
+    /// comp.set_cancel_token(Arc::clone(&cancel_token));
+    ///
+    /// // e.g. when the cancel button is clicked
+    /// cancel_token.store(true, Ordering::Relaxed);
+    /// ```
+    pub fn set_cancel_token(&mut self, cancel_token: Arc<AtomicBool>) {
+        self.cancel_token = Some(cancel_token);
+    }
+
+    /// Set the extensions of the files to compress, compared case-insensitively.
+    ///
+    /// By default, every non-hidden file in the source directory is processed,
+    /// so other files like `.txt` or `.mp4` are copied to the destination directory.
+    /// When the extensions are set, the other files are ignored.
+    /// # Examples
+    /// ```
+    /// use image_compressor::FolderCompressor;
+    ///
+    /// let mut comp = FolderCompressor::new("source", "dest");
+    /// comp.set_extensions(&["jpg", "png", "webp"]);
+    /// ```
+    pub fn set_extensions(&mut self, extensions: &[&str]) {
+        self.extensions = Some(extensions.iter().map(|e| e.to_string()).collect());
+    }
+
+    /// Set the output format of the files by their extensions, such as `png` to [`OutputFormat::Webp`].
+    ///
+    /// The extensions are compared case-insensitively.
+    /// The files with an extension that is not in the map are compressed to jpg format as before.
+    /// # Examples
+    /// ```
+    /// use image_compressor::{FolderCompressor, OutputFormat};
+    /// use std::collections::HashMap;
+    ///
+    /// let mut comp = FolderCompressor::new("source", "dest");
+    /// comp.set_format_map(HashMap::from([("png".to_string(), OutputFormat::Webp)]));
+    /// ```
+    pub fn set_format_map(&mut self, format_map: HashMap<String, OutputFormat>) {
+        self.format_map = format_map
+            .into_iter()
+            .map(|(extension, format)| (extension.to_ascii_lowercase(), format))
+            .collect();
+    }
+
+    /// The output format of the file, picked by its extension from the format map.
+    fn output_format(&self, file: &Path) -> OutputFormat {
+        file.extension()
+            .and_then(|e| e.to_str())
+            .and_then(|e| self.format_map.get(&e.to_ascii_lowercase()))
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// Set the name of the ignore files, such as `.compressignore`, whose files are not processed.
+    ///
+    /// The ignore files have the glob patterns of `.gitignore` and are read in every directory of the source directory.
+    /// See [`get_file_list_with_ignore`](crate::crawler::get_file_list_with_ignore).
+    /// # Examples
+    /// ```
+    /// use image_compressor::FolderCompressor;
+    ///
+    /// let mut comp = FolderCompressor::new("source", "dest");
+    /// comp.set_ignore_file(".compressignore");
+    /// ```
+    pub fn set_ignore_file(&mut self, ignore_file_name: &str) {
+        self.ignore_file = Some(ignore_file_name.to_string());
+    }
+
+    /// Setter for the number of threads used to compress images.
+    ///
+    /// 0 means to scale to the machine automatically: images are compressed in the default thread pool of rayon,
+    /// which has as many threads as [`std::thread::available_parallelism`] unless `RAYON_NUM_THREADS` is set,
+    /// and balances the load better when the file sizes differ a lot.
+    /// Otherwise the threads are named `img-compress-0`, `img-compress-1`, and so on,
+    /// which show up in profilers and panic messages.
+    /// # Examples
+    /// ```
+    /// use image_compressor::FolderCompressor;
+    /// use image_compressor::Factor;
+    /// use std::path::Path;
+    ///
+    /// let source = Path::new("source");
+    /// let dest = Path::new("dest");
+    ///
+    /// let mut comp = FolderCompressor::new(source, dest);
+    /// comp.set_thread_count(4);
+    /// ```
+    pub fn set_thread_count(&mut self, thread_count: u32) {
+        self.thread_count = thread_count;
+    }
+
+    /// Set whether a dedicated thread reads the files into memory ahead of the compressing threads.
+    /// The default is false.
+    ///
+    /// The compressing threads decode the images from the bytes in memory,
+    /// so reading the disk overlaps with the encoding.
+    /// It improves the throughput on spinning disks and network mounts.
+    /// At most twice as many files as the threads are read ahead.
+    /// With [`set_max_inflight_bytes`](FolderCompressor::set_max_inflight_bytes),
+    /// the files read ahead also count against the budget, so fewer files may be read ahead.
+    pub fn set_prefetch(&mut self, prefetch: bool) {
+        self.prefetch = prefetch;
+    }
+
+    /// Folder compress function.
+    ///
+    /// The function will compress all images, using multithreading, in a given source folder and will wait until everything is done.
+    /// If user set a [`Sender`] for [`FolderCompressor`] before, the method sends messages whether compressing is complete.
+    ///
+    /// Returns a [`FolderSummary`] with the totals and the result of each file,
+    /// so failed files and their reasons can be checked without the messages.
+    ///
+    /// # Warning
+    /// Since this function consume its `self`, the `FolderCompressor` instance (which is self) is no longer available after calling this function.
+    /// Use [`compress_dir`](FolderCompressor::compress_dir) to compress several directories with the same settings.
+    /// ```
+    /// use std::path::PathBuf;
+    /// use std::sync::mpsc;
+    /// use image_compressor::FolderCompressor;
+    ///
+    /// let source = PathBuf::from("source_dir");
+    /// let dest = PathBuf::from("dest_dir");
+    /// let (tx, tr) = mpsc::channel();
+    ///
+    /// let mut comp = FolderCompressor::new(source, dest);
+    /// comp.set_sender(tx);
+    /// comp.set_thread_count(4);
+    ///
+    /// match comp.compress(){
+    ///     Ok(_) => {},
+    ///     Err(e) => println!("Cannot compress the folder: {}", e),
+    /// }
+    /// ```
+    pub fn compress(self) -> Result<FolderSummary, CompressError> {
+        let start = Instant::now();
+        let to_comp_file_list = self.scan()?;
+        self.compress_list(to_comp_file_list, start)
+    }
+
+    /// Compress exactly the given files, without crawling the source directory.
+    ///
+    /// Works like [`compress`](FolderCompressor::compress) with the same threads and settings,
+    /// so the file list can come from elsewhere, such as a database query.
+    /// The new images mirror the paths of the files relative to the source directory,
+    /// and the files outside of it are written directly into the destination directory.
+    /// The extension filter and the ignore file are not applied to the list.
+    /// # Examples
+    /// ```no_run
+    /// use std::path::PathBuf;
+    /// use image_compressor::FolderCompressor;
+    ///
+    /// let files = vec![PathBuf::from("photos/a.png"), PathBuf::from("photos/2024/b.png")];
+    /// let comp = FolderCompressor::new("photos", "dest");
+    /// let summary = comp.compress_files(files).unwrap();
+    /// ```
+    pub fn compress_files(self, files: Vec<PathBuf>) -> Result<FolderSummary, CompressError> {
+        self.compress_list(files, Instant::now())
+    }
+
+    /// Compress the folder like [`compress`](FolderCompressor::compress) in a background thread,
+    /// and return an iterator of the reports of the compressed files.
+    ///
+    /// Each report is yielded as soon as its file is compressed, in the order the files complete,
+    /// which is not the order of the source directory with more than one thread.
+    /// The reports are the same ones given to the function set by [`set_on_file`](FolderCompressor::set_on_file),
+    /// which is still called. The files that are not compressed are not yielded,
+    /// so use [`set_event_sender`](FolderCompressor::set_event_sender) to receive them.
+    ///
+    /// The iterator ends when every file is processed.
+    /// If the compression itself fails, e.g. it is cancelled or aborted, its error is the last item,
+    /// so a stopped run can be told apart from a finished one.
+    ///
+    /// Dropping the iterator cancels the compression like the cancel token,
+    /// e.g. after `take(n)`, so the files being compressed are finished and the rest are not compressed.
+    /// The cancel token set by [`set_cancel_token`](FolderCompressor::set_cancel_token) is not changed.
+    /// # Examples
+    /// ```no_run
+    /// use image_compressor::FolderCompressor;
+    ///
+    /// let comp = FolderCompressor::new("source_dir", "dest_dir");
+    /// for result in comp.compress_iter() {
+    ///     match result {
+    ///         Ok(report) => println!("{}: {} bytes", report.path.display(), report.compressed_size),
+    ///         Err(e) => println!("Cannot compress the folder: {}", e),
+    ///     }
+    /// }
+    /// ```
+    pub fn compress_iter(
+        mut self,
+    ) -> impl Iterator<Item = Result<CompressionReport, CompressError>> {
+        let (tx, rx) = mpsc::channel();
+        let dropped = Arc::new(AtomicBool::new(false));
+        self.iter_dropped = Some(Arc::clone(&dropped));
+        let report_tx = tx.clone();
+        let on_file = self.on_file.take();
+        self.set_on_file(move |report| {
+            if let Some(on_file) = &on_file {
+                on_file(report);
+            }
+            // The receiver is dropped when the iterator is dropped.
+            let _ = report_tx.send(Ok(report.clone()));
+        });
+        // The senders are dropped when the compression returns, which ends the iterator.
+        spawn_worker("img-compress-iter".to_string(), move || {
+            if let Err(e) = self.compress() {
+                let _ = tx.send(Err(e));
+            }
+        });
+        CompressIter {
+            receiver: rx.into_iter(),
+            dropped,
+        }
+    }
+
+    /// Compress the files of the list, which is the body of [`compress`](FolderCompressor::compress).
+    /// The elapsed time of the summary is measured from `start`.
+    fn compress_list(
+        self,
+        to_comp_file_list: Vec<PathBuf>,
+        start: Instant,
+    ) -> Result<FolderSummary, CompressError> {
+        let total = to_comp_file_list.len();
+        self.send_event(CompressionEvent::Started { total });
+
+        let mut state = JobState::default();
+        if self.flatten {
+            state.flat_stems = unique_file_stems(&to_comp_file_list);
+        }
+        state.memory_budget = self
+            .max_inflight_bytes
+            .map(|max_bytes| Arc::new(MemoryBudget::new(max_bytes)));
+        if let (Some(archive_path), false) = (&self.output_archive, self.dry_run) {
+            if let Some(parent) = archive_path.parent().filter(|p| !p.as_os_str().is_empty()) {
+                fs::create_dir_all(parent)?;
+            }
+            state.archive = Some(Mutex::new(ZipWriter::new(File::create(archive_path)?)));
+        }
+        let to_comp_file_list = schedule_files(to_comp_file_list, self.schedule);
+        state.total = total;
+        state.started = Some(Instant::now());
+        let folder = Arc::new(self);
+        let state = Arc::new(state);
+        let joined = if folder.prefetch {
+            process_prefetched(to_comp_file_list, &folder, &state)
+        } else if folder.thread_count == 0 {
+            catch_rayon_panic(|| process_with_rayon(to_comp_file_list, &folder, &state))
+        } else {
+            let queue = Arc::new(SegQueue::new());
+            for i in to_comp_file_list {
+                queue.push(i);
+            }
+            let mut handles = Vec::new();
+            for i in 0..folder.thread_count {
+                let arc_folder = Arc::clone(&folder);
+                let arc_queue = Arc::clone(&queue);
+                let arc_state = Arc::clone(&state);
+                let handle = spawn_worker(format!("img-compress-{}", i), move || {
+                    process(&arc_queue, &arc_folder, &arc_state);
+                });
+                handles.push(handle);
+            }
+
+            join_workers(handles)
+        };
+
+        // A panicked thread drops its references while unwinding.
+        let mut state = Arc::into_inner(state).expect("every thread is joined");
+        joined?;
+        if let Some(archive) = state.archive.take() {
+            archive
+                .into_inner()
+                .unwrap()
+                .finish()
+                .map_err(|e| CompressError::Io(io::Error::other(e)))?;
+        }
+
+        if let Some(e) = state.abort_error.lock().unwrap().take() {
+            return Err(CompressError::Aborted(e));
+        }
+        if folder.is_cancelled() {
+            let processed = state.results.lock().unwrap().len();
+            return Err(CompressError::Cancelled { processed, total });
+        }
+
+        let results = std::mem::take(&mut *state.results.lock().unwrap());
+        let summary = FolderSummary::new(results, start.elapsed());
+        if folder.dry_run {
+            folder.send_event(CompressionEvent::DryRunSummary {
+                original: summary.original_bytes,
+                compressed: summary.compressed_bytes,
+            });
+        } else if folder.delete_source && folder.source_path.is_dir() {
+            match delete_recursive(&folder.source_path, true) {
+                Ok(_) => folder.send_event(CompressionEvent::SourceDeleted),
+                Err(e) => folder.send_event(CompressionEvent::SourceDeleteFailed {
+                    error: e.to_string(),
+                }),
+            };
+        }
+        folder.send_event(CompressionEvent::Finished);
+        Ok(summary)
+    }
+
+    /// Return the files that [`compress`](FolderCompressor::compress) would process, without compressing them.
+    ///
+    /// The same extension, ignore file, and hidden file rules as the compression are used,
+    /// so the length of the list equals the total that the compression will report.
+    /// This is useful to size a progress bar before the work begins.
+    /// # Examples
+    /// ```no_run
+    /// use image_compressor::FolderCompressor;
+    ///
+    /// let comp = FolderCompressor::new("source", "dest");
+    /// let total = comp.scan().unwrap().len();
+    /// println!("{} files to compress", total);
+    /// ```
+    pub fn scan(&self) -> io::Result<Vec<PathBuf>> {
+        let file_list = if self.source_path.is_file() {
+            vec![self.source_path.clone()]
+        } else {
+            match &self.ignore_file {
+                Some(ignore_file) => get_file_list_with_ignore(&self.source_path, ignore_file)?,
+                None => get_file_list(&self.source_path)?,
+            }
+        };
+        Ok(match &self.extensions {
+            Some(extensions) => {
+                let extensions = extensions.iter().map(|e| e.as_str()).collect::<Vec<_>>();
+                filter_by_extensions(file_list, &extensions)
+            }
+            None => file_list,
+        })
+    }
+
+    /// Compress the images in the source directory into the destination directory with the settings of this instance.
+    ///
+    /// Works like [`compress`](FolderCompressor::compress), but the source and destination directories given here are used,
+    /// and `self` is not consumed, so the same settings can drive multiple directories.
+    /// # Examples
+    /// ```no_run
+    /// use image_compressor::FolderCompressor;
+    ///
+    /// let mut comp = FolderCompressor::new("unused", "unused");
+    /// comp.set_thread_count(4);
+    /// for (source, dest) in [("album1", "out/album1"), ("album2", "out/album2")] {
+    ///     comp.compress_dir(source, dest).unwrap();
+    /// }
+    /// ```
+    pub fn compress_dir<O: AsRef<Path>, D: AsRef<Path>>(
+        &self,
+        source_path: O,
+        dest_path: D,
+    ) -> Result<FolderSummary, CompressError> {
+        let mut folder = self.clone();
+        folder.source_path = source_path.as_ref().to_path_buf();
+        folder.dest_path = dest_path.as_ref().to_path_buf();
+        folder.compress()
+    }
+
+    /// The directory that the subdirectories of the destination are made relative to.
+    ///
+    /// It is the parent directory of the source when the source is a single file.
+    fn source_root(&self) -> &Path {
+        if self.source_path.is_file() {
+            self.source_path.parent().unwrap_or(Path::new(""))
+        } else {
+            &self.source_path
+        }
+    }
+
+    /// Check whether the compression is cancelled by the cancel token,
+    /// or by dropping the iterator of [`compress_iter`](FolderCompressor::compress_iter).
+    fn is_cancelled(&self) -> bool {
+        [&self.cancel_token, &self.iter_dropped]
+            .into_iter()
+            .flatten()
+            .any(|token| token.load(Ordering::Relaxed))
+    }
+
+    /// Folder compress function like [`compress`](FolderCompressor::compress) without blocking the async executor.
+    ///
+    /// The whole compression runs in the blocking thread pool of tokio with `spawn_blocking`,
+    /// and the future resolves when every file is processed. Available with the `async` feature.
+    #[cfg(feature = "async")]
+    pub async fn compress_async(self) -> Result<FolderSummary, CompressError> {
+        tokio::task::spawn_blocking(move || self.compress())
+            .await
+            .map_err(|e| CompressError::Io(io::Error::other(e)))?
+    }
+
+    /// Send the event to the senders.
+    ///
+    /// The event is formatted as a message for the `Sender<String>`,
+    /// except for the progress, which is only sent to the event sender
+    /// so that the messages stay the same as before the progress was added.
+    /// Every event is also logged with the `log` crate, so the application controls the verbosity with its logger.
+    /// Failed files are logged as errors and skipped files as warnings.
+    fn send_event(&self, event: CompressionEvent) {
+        match &event {
+            CompressionEvent::FileFailed { path, .. } => error!("{}: {}", path.display(), event),
+            CompressionEvent::FileSkipped { path, .. } => warn!("{}: {}", path.display(), event),
+            CompressionEvent::SourceDeleteFailed { .. } => error!("{}", event),
+            CompressionEvent::Progress { .. } => debug!("{}", event),
+            _ => info!("{}", event),
+        }
+        if let Some(s) = self
+            .sender
+            .as_ref()
+            .filter(|_| !matches!(event, CompressionEvent::Progress { .. }))
+        {
+            send_message(s, event.to_string());
+        }
+        if let Some(s) = &self.event_sender {
+            send_message(s, event);
+        }
+    }
+
+    /// Compress a file into the directory that has the same relative path in the destination directory.
+    ///
+    /// If the output stem is given, the new image is named with it instead of the file stem of the source.
+    /// If the archive is given, the new image is written into it with the relative path instead.
+    /// If the data of the file is given, the image is decoded from it instead of reading the file.
+    fn compress_file(
+        &self,
+        file: &Path,
+        data: Option<Vec<u8>>,
+        output_stem: Option<&str>,
+        archive: Option<&Mutex<ZipWriter<File>>>,
+    ) -> Result<CompressionReport, CompressError> {
+        let file_name = file.file_name().unwrap_or_default().to_string_lossy();
+        let parent = match file.parent() {
+            // Fall back to the destination directory itself rather than losing the file.
+            Some(p) => p.strip_prefix(self.source_root()).unwrap_or(Path::new("")),
+            None => {
+                return Err(io::Error::other(format!(
+                    "Cannot find the parent directory of file {}",
+                    file_name
+                ))
+                .into())
+            }
+        };
+        let dest_root = match archive {
+            Some(_) => PathBuf::new(),
+            None => self.dest_path.to_path_buf(),
+        };
+        let new_dest_dir = if self.flatten {
+            dest_root
+        } else {
+            dest_root.join(parent)
+        };
+        if archive.is_none()
+            && !self.dry_run
+            && !new_dest_dir.is_dir()
+            && fs::create_dir_all(&new_dest_dir).is_err()
+        {
+            return Err(io::Error::other(format!(
+                "Cannot create the parent directory of file {}",
+                file_name
+            ))
+            .into());
+        }
+        let mut compressor = Compressor::new(file, &new_dest_dir);
+        compressor.set_factor(self.factor);
+        if let Some(cal_func) = &self.cal_func {
+            let cal_func = Arc::clone(cal_func);
+            compressor
+                .set_cal_func(move |width, height, file_size| cal_func(width, height, file_size));
+        }
+        if let Some(min_quality) = self.min_quality {
+            compressor.set_min_quality(min_quality);
+        }
+        compressor.set_filter_type(self.filter_type);
+        if let Some((small_filter, large_filter, threshold_px)) = self.filter_for_size {
+            compressor.set_filter_for_size(small_filter, large_filter, threshold_px);
+        }
+        compressor.set_delete_source(self.delete_source);
+        compressor.set_overwrite(self.overwrite);
+        if let Some(bytes) = self.skip_if_smaller_than {
+            compressor.set_skip_if_smaller_than(bytes);
+        }
+        compressor.set_preserve_timestamps(self.preserve_timestamps);
+        compressor.set_unreadable_policy(UnreadablePolicy::Fail);
+        compressor.set_dry_run(self.dry_run);
+        if let Some(data) = data {
+            compressor.set_source_data(data);
+        }
+        if let Some(stem) = output_stem {
+            compressor.set_output_stem(stem.to_string());
+        }
+        let mut copied_file = new_dest_dir.join(file.file_name().unwrap_or_default());
+        if let Some(stem) = output_stem {
+            copied_file.set_file_name(stem);
+            if let Some(extension) = file.extension() {
+                copied_file.set_extension(extension);
+            }
+        }
+        if let Some(archive) = archive {
+            return self.compress_file_into_archive(&compressor, file, &copied_file, archive);
+        }
+        let format = self.output_format(file);
+        let mut result = compressor.compress_to_format_reported(format);
+        if let Err(CompressError::AlreadyExists(output)) = &result {
+            if self.resume && !is_complete_image(output) {
+                compressor.set_overwrite(true);
+                result = compressor.compress_to_format_reported(format);
+            }
+        }
+        if let Err(e) = &result {
+            if self.file_error_policy == FileErrorPolicy::Copy
+                && !self.dry_run
+                && !is_already_exists(e)
+                && !copied_file.exists()
+            {
+                fs::copy(file, copied_file)?;
+            }
+        }
+        result
+    }
+
+    /// Compress a file in memory and write it into the zip archive with its relative path.
+    ///
+    /// With [`FileErrorPolicy::Copy`], the source file that cannot be compressed
+    /// is written as it is with the name of `copied_file`.
+    fn compress_file_into_archive(
+        &self,
+        compressor: &Compressor<&Path, &PathBuf>,
+        file: &Path,
+        copied_file: &Path,
+        archive: &Mutex<ZipWriter<File>>,
+    ) -> Result<CompressionReport, CompressError> {
+        let write_entry = |name: &Path, data: &[u8]| -> Result<(), CompressError> {
+            let options =
+                SimpleFileOptions::default().compression_method(CompressionMethod::Stored);
+            let mut archive = archive.lock().unwrap();
+            archive
+                .start_file(archive_entry_name(name), options)
+                .map_err(|e| CompressError::Io(io::Error::other(e)))?;
+            archive.write_all(data)?;
+            Ok(())
+        };
+        match compressor.compress_to_memory_reported(self.output_format(file)) {
+            Ok((report, data)) => {
+                write_entry(&report.path, &data)?;
+                Ok(report)
+            }
+            Err(e) => {
+                if self.file_error_policy == FileErrorPolicy::Copy {
+                    write_entry(copied_file, &fs::read(file)?)?;
+                }
+                Err(e)
+            }
+        }
+    }
+}
+
+/// Process function for multithreaded compression.
+/// Each thread pops files from the queue and compresses them until the queue is empty.
+/// The result of each file is collected in the shared state.
+///
+/// If a file fails with [`FileErrorPolicy::Abort`], the error is stored in the shared state
+/// and every thread stops before compressing its next file.
+/// Likewise, every thread stops when the compression is cancelled.
+fn process(queue: &SegQueue<PathBuf>, folder: &FolderCompressor, state: &JobState) {
+    while !is_stopped(folder, state) {
+        let Some(file) = queue.pop() else {
+            break;
+        };
+        process_file(file, None, None, folder, state);
+    }
+}
+
+/// Process function for the rayon thread pool, used when the number of threads is 0.
+/// It stops in the same way as [`process`].
+fn process_with_rayon(files: Vec<PathBuf>, folder: &FolderCompressor, state: &JobState) {
+    files.into_par_iter().for_each(|file| {
+        if !is_stopped(folder, state) {
+            process_file(file, None, None, folder, state);
+        }
+    });
+}
+
+/// Process function used when the prefetch is set.
+///
+/// A reader thread reads the files in order and sends their data through a bounded channel,
+/// and the threads, or the rayon thread pool if the number of threads is 0, compress the received data.
+/// A file that cannot be read is compressed from its path, so the error is reported as usual.
+/// If the memory is limited, the reader reserves the size of the file and the estimated size of its decoded image
+/// before reading it, and the reservation is released after the file is compressed.
+/// So the data read ahead counts against the budget, and the reader waits while the budget is full.
+/// It stops in the same way as [`process`].
+///
+/// # Error
+/// - [`CompressError::WorkerPanicked`] when any thread panicked.
+fn process_prefetched(
+    files: Vec<PathBuf>,
+    folder: &Arc<FolderCompressor>,
+    state: &Arc<JobState>,
+) -> Result<(), CompressError> {
+    let worker_count = match folder.thread_count {
+        0 => rayon::current_num_threads(),
+        n => n as usize,
+    };
+    let (tx, rx) = mpsc::sync_channel(worker_count * 2);
+    let memory_budget = state.memory_budget.clone();
+    let reader = spawn_worker("img-compress-reader".to_string(), move || {
+        for file in files {
+            let reservation = memory_budget.as_ref().map(|budget| {
+                let file_size = fs::metadata(&file).map(|m| m.len()).unwrap_or(0);
+                budget.reserve(file_size + estimate_decoded_size(&file))
+            });
+            let data = fs::read(&file);
+            // The receiver is dropped when the threads stop.
+            if tx.send((file, data, reservation)).is_err() {
+                break;
+            }
+        }
+    });
+
+    let joined = if folder.thread_count == 0 {
+        catch_rayon_panic(|| {
+            rx.into_iter()
+                .par_bridge()
+                .for_each(|(file, data, reservation)| {
+                    if !is_stopped(folder, state) {
+                        process_file(file, data.ok(), reservation, folder, state);
+                    }
+                })
+        })
+    } else {
+        let rx = Arc::new(Mutex::new(rx));
+        let mut handles = Vec::new();
+        for i in 0..folder.thread_count {
+            let arc_folder = Arc::clone(folder);
+            let arc_rx = Arc::clone(&rx);
+            let arc_state = Arc::clone(state);
+            let handle = spawn_worker(format!("img-compress-{}", i), move || {
+                while !is_stopped(&arc_folder, &arc_state) {
+                    let Ok((file, data, reservation)) = arc_rx.lock().unwrap().recv() else {
+                        break;
+                    };
+                    process_file(file, data.ok(), reservation, &arc_folder, &arc_state);
+                }
+            });
+            handles.push(handle);
+        }
+
+        join_workers(handles)
+    };
+    // The receiver is dropped by now, so the reader stops even if the threads panicked.
+    joined.and(join_workers(vec![reader]))
+}
+
+/// Spawn a thread with the name, so that the workers can be told apart in profilers and panic messages.
+fn spawn_worker<F: FnOnce() + Send + 'static>(name: String, f: F) -> thread::JoinHandle<()> {
+    thread::Builder::new()
+        .name(name)
+        .spawn(f)
+        .expect("failed to spawn thread")
+}
+
+/// Wait for every thread, and count the panicked ones instead of panicking the caller.
+///
+/// # Error
+/// - [`CompressError::WorkerPanicked`] with the message of the first panic when any thread panicked.
+fn join_workers(handles: Vec<thread::JoinHandle<()>>) -> Result<(), CompressError> {
+    let workers = handles.len();
+    let messages: Vec<String> = handles
+        .into_iter()
+        .filter_map(|h| h.join().err())
+        .map(|payload| panic_message(payload.as_ref()))
+        .collect();
+    match messages.first() {
+        None => Ok(()),
+        Some(message) => Err(CompressError::WorkerPanicked {
+            panicked: messages.len(),
+            workers,
+            message: message.clone(),
+        }),
+    }
+}
+
+/// Run the work on the rayon thread pool, and catch the panic that rayon resumes on the caller.
+///
+/// Rayon resumes only one panic, so `panicked` of the error is always 1.
+fn catch_rayon_panic<F: FnOnce()>(f: F) -> Result<(), CompressError> {
+    panic::catch_unwind(AssertUnwindSafe(f)).map_err(|payload| CompressError::WorkerPanicked {
+        panicked: 1,
+        workers: rayon::current_num_threads(),
+        message: panic_message(payload.as_ref()),
+    })
+}
+
+/// Get the message of a panic payload, which is a `&str` or a `String` for `panic!` with a message.
+fn panic_message(payload: &(dyn Any + Send)) -> String {
+    payload
+        .downcast_ref::<&str>()
+        .map(|s| s.to_string())
+        .or_else(|| payload.downcast_ref::<String>().cloned())
+        .unwrap_or_else(|| "unknown panic".to_string())
+}
+
+/// Check whether the compression is aborted, cancelled, or out of the output budget.
+fn is_stopped(folder: &FolderCompressor, state: &JobState) -> bool {
+    state.abort_error.lock().unwrap().is_some()
+        || folder.is_cancelled()
+        || state.budget_reached.load(Ordering::Relaxed)
+}
+
+/// Add the size of a compressed image to the total,
+/// and send the event when the total reaches the output budget for the first time.
+fn add_output_bytes(bytes: u64, folder: &FolderCompressor, state: &JobState) {
+    let written = state.output_bytes.fetch_add(bytes, Ordering::Relaxed) + bytes;
+    if let Some(max_bytes) = folder.max_total_output {
+        if written >= max_bytes && !state.budget_reached.swap(true, Ordering::Relaxed) {
+            folder.send_event(CompressionEvent::BudgetReached { written, max_bytes });
+        }
+    }
+}
+
+/// Compress a file, send the events of its result and the progress, and collect the result in the shared state.
+/// If the data of the file is given, the image is decoded from it.
+/// If the memory is already reserved for the file, e.g. by the reader of the prefetch, it is not reserved again.
+fn process_file(
+    file: PathBuf,
+    data: Option<Vec<u8>>,
+    reservation: Option<MemoryReservation>,
+    folder: &FolderCompressor,
+    state: &JobState,
+) {
+    let _reservation = reservation.or_else(|| {
+        state
+            .memory_budget
+            .as_ref()
+            .map(|budget| budget.reserve(estimate_decoded_size(&file)))
+    });
+    let start = Instant::now();
+    let mut status = "done";
+    let result = match folder.compress_file(
+        &file,
+        data,
+        state.flat_stems.get(&file).map(String::as_str),
+        state.archive.as_ref(),
+    ) {
+        Ok(report) => {
+            if report.skipped {
+                status = "skipped";
+                folder.send_event(CompressionEvent::FileSkipped {
+                    path: file.clone(),
+                    reason: format!("Copied the small jpg image: {}", report.path.display()),
+                });
+            } else {
+                folder.send_event(CompressionEvent::FileDone {
+                    path: report.path.clone(),
+                    original: report.original_size,
+                    compressed: report.compressed_size,
+                });
+            }
+            add_output_bytes(report.compressed_size, folder, state);
+            if let Some(on_file) = &folder.on_file {
+                on_file(&report);
+            }
+            Ok(report)
+        }
+        Err(CompressError::AlreadyExists(output)) if folder.resume => {
+            status = "skipped";
+            folder.send_event(CompressionEvent::FileSkipped {
+                path: file.clone(),
+                reason: format!("Already compressed: {}", output.display()),
+            });
+            Ok(resumed_report(&file, output))
+        }
+        Err(e) => {
+            let error = e.to_string();
+            if is_already_exists(&e) || folder.file_error_policy == FileErrorPolicy::Skip {
+                status = "skipped";
+                folder.send_event(CompressionEvent::FileSkipped {
+                    path: file.clone(),
+                    reason: error.clone(),
+                });
+            } else {
+                status = "failed";
+                if folder.file_error_policy == FileErrorPolicy::Abort {
+                    state
+                        .abort_error
+                        .lock()
+                        .unwrap()
+                        .get_or_insert_with(|| error.clone());
+                }
+                folder.send_event(CompressionEvent::FileFailed {
+                    path: file.clone(),
+                    error: error.clone(),
+                });
+            }
+            Err(error)
+        }
+    };
+    if let Some(s) = &folder.json_sender {
+        send_message(s, json_record(&file, status, &result, start.elapsed()));
+    }
+    // The progress is sent while the results are locked, so the completed counts arrive in order.
+    let mut results = state.results.lock().unwrap();
+    results.push((file, result));
+    let completed = results.len();
+    folder.send_event(CompressionEvent::Progress {
+        completed,
+        total: state.total,
+        eta: state.eta(completed),
+    });
+}
+
+/// Make the JSON record of a processed file for the JSON sender.
+///
+/// The original size is read from the source file when the file is not compressed.
+fn json_record(
+    file: &Path,
+    status: &str,
+    result: &Result<CompressionReport, String>,
+    elapsed: Duration,
+) -> String {
+    let (orig_bytes, new_bytes) = match result {
+        Ok(report) => (report.original_size, Some(report.compressed_size)),
+        Err(_) => (fs::metadata(file).map(|m| m.len()).unwrap_or(0), None),
+    };
+    serde_json::json!({
+        "path": file.to_string_lossy(),
+        "status": status,
+        "orig_bytes": orig_bytes,
+        "new_bytes": new_bytes,
+        "ms": elapsed.as_millis() as u64,
+    })
+    .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::ImageBuffer;
+    use rand::Rng;
+    use std::fs;
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::mpsc;
+
+    /// Create test directory and an image file in it.
+    fn setup<T: AsRef<Path>>(test_name: T) -> (PathBuf, Vec<PathBuf>) {
+        let test_dir = test_name.as_ref().to_path_buf();
+        if test_dir.is_dir() {
+            fs::remove_dir_all(&test_dir).unwrap();
+        }
+        fs::create_dir_all(&test_dir).unwrap();
+
+        const WIDTH: u32 = 256;
+        const HEIGHT: u32 = 256;
+        let img_stripe = ImageBuffer::from_fn(WIDTH, HEIGHT, |x, _| {
+            if x % 2 == 0 {
+                image::Luma([0u8])
+            } else {
+                image::Luma([255u8])
+            }
+        });
+        let stripe_path = test_dir.join("img_stripe.png");
+        img_stripe.save(&stripe_path).unwrap();
+        let img_random_rgb = ImageBuffer::from_fn(WIDTH, HEIGHT, |_, _| {
+            let r = rand::thread_rng().gen_range(0..256) as u8;
+            let g = rand::thread_rng().gen_range(0..256) as u8;
+            let b = rand::thread_rng().gen_range(0..256) as u8;
+            image::Rgb([r, g, b])
+        });
+        let rgb_path = test_dir.join("img_random_rgb.gif");
+        img_random_rgb.save(&rgb_path).unwrap();
+        (test_dir, vec![stripe_path, rgb_path])
+    }
+
+    fn cleanup<T: AsRef<Path>>(test_dir: T) {
+        if test_dir.as_ref().is_dir() {
+            fs::remove_dir_all(&test_dir).unwrap();
+        }
+    }
+
+    #[test]
+    fn folder_compress_test() {
+        let (test_source_dir, _) = setup("folder_compress_test_source");
+        let test_dest_dir = PathBuf::from("folder_compress_test_dest");
+        if test_dest_dir.is_dir() {
+            fs::remove_dir_all(&test_dest_dir).unwrap();
+        }
+        fs::create_dir_all(&test_dest_dir).unwrap();
+
+        let mut folder_compressor = FolderCompressor::new(&test_source_dir, &test_dest_dir);
+        folder_compressor.set_thread_count(4);
+        folder_compressor.compress().unwrap();
+        let a = get_file_list(&test_source_dir).unwrap();
+        let b = get_file_list(&test_dest_dir).unwrap();
+        let mut source_file_list = a.iter().map(|i| i.file_stem().unwrap()).collect::<Vec<_>>();
+        let mut dest_file_list = b.iter().map(|i| i.file_stem().unwrap()).collect::<Vec<_>>();
+        source_file_list.sort();
+        dest_file_list.sort();
+        assert_eq!(source_file_list, dest_file_list);
+        cleanup(test_source_dir);
+        cleanup(test_dest_dir);
+    }
+
+    /// Logger that keeps every record in memory.
+    struct CapturingLogger;
+
+    static CAPTURED_LOGS: Mutex<Vec<(log::Level, String)>> = Mutex::new(Vec::new());
+
+    impl log::Log for CapturingLogger {
+        fn enabled(&self, _: &log::Metadata) -> bool {
+            true
+        }
+
+        fn log(&self, record: &log::Record) {
+            CAPTURED_LOGS
+                .lock()
+                .unwrap()
+                .push((record.level(), record.args().to_string()));
+        }
+
+        fn flush(&self) {}
+    }
+
+    static LOGGER: CapturingLogger = CapturingLogger;
+
+    #[test]
+    fn folder_compress_logs_error_test() {
+        let _ = log::set_logger(&LOGGER);
+        log::set_max_level(log::LevelFilter::Trace);
+        let (test_source_dir, _) = setup("folder_compress_logs_error_test_source");
+        let bad_file = test_source_dir.join("bad.png");
+        fs::write(&bad_file, b"\x89PNG\r\n\x1a\nnot a png").unwrap();
+        let test_dest_dir = PathBuf::from("folder_compress_logs_error_test_dest");
+        cleanup(&test_dest_dir);
+
+        let summary = FolderCompressor::new(&test_source_dir, &test_dest_dir)
+            .compress()
+            .unwrap();
+        assert_eq!(summary.failed, 1);
+        let logs = CAPTURED_LOGS.lock().unwrap();
+        let bad_file = bad_file.display().to_string();
+        assert!(logs
+            .iter()
+            .any(|(level, message)| *level == log::Level::Error && message.contains(&bad_file)));
+        drop(logs);
+        cleanup(test_source_dir);
+        cleanup(test_dest_dir);
+    }
+
+    #[test]
+    fn folder_compress_json_sender_test() {
+        let (test_source_dir, test_images) = setup("folder_compress_json_sender_test_source");
+        let bad_file = test_source_dir.join("bad.png");
+        fs::write(&bad_file, b"\x89PNG\r\n\x1a\nnot a png").unwrap();
+        let test_dest_dir = PathBuf::from("folder_compress_json_sender_test_dest");
+        cleanup(&test_dest_dir);
+
+        let (tx, rx) = mpsc::channel();
+        let (json_tx, json_rx) = mpsc::channel();
+        let mut folder_compressor = FolderCompressor::new(&test_source_dir, &test_dest_dir);
+        folder_compressor.set_sender(tx);
+        folder_compressor.set_json_sender(json_tx);
+        folder_compressor.compress().unwrap();
+        assert!(rx.try_iter().count() > 0);
+
+        let records = json_rx
+            .try_iter()
+            .map(|line| serde_json::from_str::<serde_json::Value>(&line).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(records.len(), 3);
+        let record_of = |path: &Path| {
+            records
+                .iter()
+                .find(|r| r["path"] == path.to_string_lossy().as_ref())
+                .unwrap()
+        };
+        let done = record_of(&test_images[0]);
+        assert_eq!(done["status"], "done");
+        assert_eq!(
+            done["orig_bytes"],
+            fs::metadata(&test_images[0]).unwrap().len()
+        );
+        assert!(done["new_bytes"].as_u64().unwrap() > 0);
+        assert!(done["ms"].is_u64());
+        let failed = record_of(&bad_file);
+        assert_eq!(failed["status"], "failed");
+        assert_eq!(failed["orig_bytes"], 17);
+        assert!(failed["new_bytes"].is_null());
+        cleanup(test_source_dir);
+        cleanup(test_dest_dir);
+    }
+
+    #[test]
+    fn folder_compress_with_prefetch_test() {
+        let (test_source_dir, _) = setup("folder_compress_with_prefetch_test_source");
+        fs::write(test_source_dir.join("note.txt"), "not an image").unwrap();
+        let mut outputs = Vec::new();
+        // With a budget of a byte, the data read ahead is compressed one file at a time.
+        for (prefetch, thread_count, budget) in [
+            (false, 2, None),
+            (true, 2, None),
+            (true, 0, None),
+            (true, 2, Some(1)),
+            (true, 0, Some(1)),
+        ] {
+            let test_dest_dir = PathBuf::from(format!(
+                "folder_compress_with_prefetch_test_dest_{}_{}_{:?}",
+                prefetch, thread_count, budget
+            ));
+            cleanup(&test_dest_dir);
+            let mut folder_compressor = FolderCompressor::new(&test_source_dir, &test_dest_dir);
+            folder_compressor.set_prefetch(prefetch);
+            folder_compressor.set_thread_count(thread_count);
+            if let Some(budget) = budget {
+                folder_compressor.set_max_inflight_bytes(budget);
+            }
+            let summary = folder_compressor.compress().unwrap();
+            assert_eq!(summary.total, 3);
+            let mut files = get_file_list(&test_dest_dir)
+                .unwrap()
+                .into_iter()
+                .map(|file| {
+                    let data = fs::read(&file).unwrap();
+                    (
+                        file.strip_prefix(&test_dest_dir).unwrap().to_path_buf(),
+                        data,
+                    )
+                })
+                .collect::<Vec<_>>();
+            files.sort();
+            outputs.push(files);
+            cleanup(test_dest_dir);
+        }
+        assert_eq!(outputs[0].len(), 3);
+        assert!(outputs.iter().all(|output| *output == outputs[0]));
+        cleanup(test_source_dir);
+    }
+
+    #[test]
+    fn folder_compress_dir_test() {
+        let (first_source_dir, _) = setup("folder_compress_dir_test_source1");
+        let (second_source_dir, _) = setup("folder_compress_dir_test_source2");
+        let first_dest_dir = PathBuf::from("folder_compress_dir_test_dest1");
+        let second_dest_dir = PathBuf::from("folder_compress_dir_test_dest2");
+        cleanup(&first_dest_dir);
+        cleanup(&second_dest_dir);
+
+        let mut folder_compressor = FolderCompressor::new("unused_source", "unused_dest");
+        folder_compressor.set_thread_count(2);
+        let first = folder_compressor
+            .compress_dir(&first_source_dir, &first_dest_dir)
+            .unwrap();
+        let second = folder_compressor
+            .compress_dir(&second_source_dir, &second_dest_dir)
+            .unwrap();
+        assert_eq!(first.succeeded, 2);
+        assert_eq!(second.succeeded, 2);
+        assert_eq!(get_file_list(&first_dest_dir).unwrap().len(), 2);
+        assert_eq!(get_file_list(&second_dest_dir).unwrap().len(), 2);
+        cleanup(first_source_dir);
+        cleanup(second_source_dir);
+        cleanup(first_dest_dir);
+        cleanup(second_dest_dir);
+    }
+
+    #[test]
+    fn folder_scan_test() {
+        let (test_source_dir, _) = setup("folder_scan_test_source");
+        fs::write(test_source_dir.join("note.txt"), "not an image").unwrap();
+        fs::write(test_source_dir.join(".hidden.png"), "hidden").unwrap();
+        let test_dest_dir = PathBuf::from("folder_scan_test_dest");
+        cleanup(&test_dest_dir);
+
+        let mut folder_compressor = FolderCompressor::new(&test_source_dir, &test_dest_dir);
+        folder_compressor.set_extensions(&["png", "gif"]);
+        let scanned = folder_compressor.scan().unwrap();
+        assert_eq!(scanned.len(), 2);
+        let summary = folder_compressor.compress().unwrap();
+        assert_eq!(summary.total, scanned.len());
+        assert_eq!(summary.results.len(), scanned.len());
+        cleanup(test_source_dir);
+        cleanup(test_dest_dir);
+    }
+
+    #[test]
+    fn folder_compress_single_file_test() {
+        let (test_source_dir, test_images) = setup("folder_compress_single_file_test_source");
+        let test_dest_dir = PathBuf::from("folder_compress_single_file_test_dest");
+        cleanup(&test_dest_dir);
+
+        let folder_compressor = FolderCompressor::new(&test_images[0], &test_dest_dir);
+        assert_eq!(
+            folder_compressor.scan().unwrap(),
+            vec![test_images[0].clone()]
+        );
+        let summary = folder_compressor.compress().unwrap();
+        assert_eq!(summary.total, 1);
+        assert_eq!(summary.succeeded, 1);
+        assert_eq!(fs::read_dir(&test_dest_dir).unwrap().count(), 1);
+        assert!(test_dest_dir.join("img_stripe.jpg").is_file());
+        cleanup(test_source_dir);
+        cleanup(test_dest_dir);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn folder_compress_non_utf8_name_test() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+
+        let (test_source_dir, test_images) = setup("folder_compress_non_utf8_name_test_source");
+        let test_dest_dir = PathBuf::from("folder_compress_non_utf8_name_test_dest");
+        cleanup(&test_dest_dir);
+        let odd_file = test_source_dir.join(OsStr::from_bytes(b"caf\xe9.png"));
+        fs::copy(&test_images[0], &odd_file).unwrap();
+
+        let (tx, rx) = mpsc::channel();
+        let mut folder_compressor = FolderCompressor::new(&test_source_dir, &test_dest_dir);
+        folder_compressor.set_thread_count(2);
+        folder_compressor.set_sender(tx);
+        let summary = folder_compressor.compress().unwrap();
+        assert_eq!(summary.failed, 0);
+        assert_eq!(summary.succeeded, test_images.len() + 1);
+        assert!(test_dest_dir
+            .join(OsStr::from_bytes(b"caf\xe9.jpg"))
+            .is_file());
+        assert!(rx
+            .try_iter()
+            .any(|message| message.contains("caf\u{FFFD}.jpg")));
+        cleanup(test_source_dir);
+        cleanup(test_dest_dir);
+    }
+
+    #[test]
+    fn folder_compress_files_test() {
+        let (test_source_dir, test_images) = setup("folder_compress_files_test_source");
+        let sub_dir = test_source_dir.join("sub");
+        fs::create_dir_all(&sub_dir).unwrap();
+        fs::copy(&test_images[0], sub_dir.join("copied.png")).unwrap();
+        let test_dest_dir = PathBuf::from("folder_compress_files_test_dest");
+        cleanup(&test_dest_dir);
+
+        let files = vec![test_images[0].clone(), sub_dir.join("copied.png")];
+        let folder_compressor = FolderCompressor::new(&test_source_dir, &test_dest_dir);
+        let summary = folder_compressor.compress_files(files).unwrap();
+        assert_eq!(summary.total, 2);
+        let mut dest_files = get_file_list(&test_dest_dir).unwrap();
+        dest_files.sort();
+        assert_eq!(
+            dest_files,
+            vec![
+                test_dest_dir.join("img_stripe.jpg"),
+                test_dest_dir.join("sub").join("copied.jpg"),
+            ]
+        );
+        cleanup(test_source_dir);
+        cleanup(test_dest_dir);
+    }
+
+    #[test]
+    fn folder_compress_with_ignore_file_test() {
+        let (test_source_dir, _) = setup("folder_compress_with_ignore_file_test_source");
+        let raw_dir = test_source_dir.join("raw");
+        fs::create_dir_all(&raw_dir).unwrap();
+        fs::copy(
+            test_source_dir.join("img_stripe.png"),
+            raw_dir.join("img_stripe.png"),
+        )
+        .unwrap();
+        fs::write(test_source_dir.join(".compressignore"), "raw/\n").unwrap();
+        let test_dest_dir = PathBuf::from("folder_compress_with_ignore_file_test_dest");
+        cleanup(&test_dest_dir);
+
+        let mut folder_compressor = FolderCompressor::new(&test_source_dir, &test_dest_dir);
+        folder_compressor.set_ignore_file(".compressignore");
+        let summary = folder_compressor.compress().unwrap();
+        assert_eq!(summary.total, 2);
+        assert!(!test_dest_dir.join("raw").exists());
+        cleanup(test_source_dir);
+        cleanup(test_dest_dir);
+    }
+
+    #[test]
+    fn folder_compress_with_extensions_test() {
+        let (test_source_dir, _) = setup("folder_compress_with_extensions_test_source");
+        fs::write(test_source_dir.join("note.txt"), "not an image").unwrap();
+        let test_dest_dir = PathBuf::from("folder_compress_with_extensions_test_dest");
+        cleanup(&test_dest_dir);
+
+        let mut folder_compressor = FolderCompressor::new(&test_source_dir, &test_dest_dir);
+        folder_compressor.set_extensions(&["PNG", "gif"]);
+        let results = folder_compressor.compress().unwrap().results;
+        assert_eq!(results.len(), 2);
+        assert!(!test_dest_dir.join("note.txt").exists());
+        assert_eq!(get_file_list(&test_dest_dir).unwrap().len(), 2);
+        cleanup(test_source_dir);
+        cleanup(test_dest_dir);
+    }
+
+    #[test]
+    fn folder_compress_with_format_map_test() {
+        let (test_source_dir, _) = setup("folder_compress_with_format_map_test_source");
+        image::open(test_source_dir.join("img_stripe.png"))
+            .unwrap()
+            .save(test_source_dir.join("photo.jpg"))
+            .unwrap();
+        let test_dest_dir = PathBuf::from("folder_compress_with_format_map_test_dest");
+        cleanup(&test_dest_dir);
+
+        let mut folder_compressor = FolderCompressor::new(&test_source_dir, &test_dest_dir);
+        folder_compressor.set_format_map(HashMap::from([
+            ("png".to_string(), OutputFormat::Webp),
+            ("JPG".to_string(), OutputFormat::Jpeg),
+        ]));
+        folder_compressor.set_thread_count(2);
+        assert_eq!(folder_compressor.compress().unwrap().succeeded, 3);
+        let mut names = get_file_list(&test_dest_dir)
+            .unwrap()
+            .iter()
+            .map(|p| p.file_name().unwrap().to_string_lossy().into_owned())
+            .collect::<Vec<_>>();
+        names.sort();
+        assert_eq!(
+            names,
+            vec!["img_random_rgb.jpg", "img_stripe.webp", "photo.jpg"]
+        );
+        assert_eq!(
+            image::guess_format(&fs::read(test_dest_dir.join("img_stripe.webp")).unwrap()).unwrap(),
+            image::ImageFormat::WebP
+        );
+        cleanup(test_source_dir);
+        cleanup(test_dest_dir);
+    }
+
+    #[test]
+    fn folder_compress_with_flatten_test() {
+        let (test_source_dir, _) = setup("folder_compress_with_flatten_test_source");
+        for sub_dir in ["dir1", "dir2"] {
+            fs::create_dir_all(test_source_dir.join(sub_dir)).unwrap();
+            fs::copy(
+                test_source_dir.join("img_stripe.png"),
+                test_source_dir.join(sub_dir).join("img_stripe.png"),
+            )
+            .unwrap();
+        }
+        let test_dest_dir = PathBuf::from("folder_compress_with_flatten_test_dest");
+        cleanup(&test_dest_dir);
+
+        let mut folder_compressor = FolderCompressor::new(&test_source_dir, &test_dest_dir);
+        folder_compressor.set_flatten(true);
+        folder_compressor.set_thread_count(2);
+        let results = folder_compressor.compress().unwrap().results;
+        assert!(results.iter().all(|(_, r)| r.is_ok()));
+
+        let mut dest_file_list = get_file_list(&test_dest_dir).unwrap();
+        dest_file_list.sort();
+        assert_eq!(
+            dest_file_list,
+            vec![
+                test_dest_dir.join("img_random_rgb.jpg"),
+                test_dest_dir.join("img_stripe.jpg"),
+                test_dest_dir.join("img_stripe_1.jpg"),
+                test_dest_dir.join("img_stripe_2.jpg"),
+            ]
+        );
+        cleanup(test_source_dir);
+        cleanup(test_dest_dir);
+    }
+
+    #[test]
+    fn folder_compress_outside_source_root_test() {
+        let (test_source_dir, _) = setup("folder_compress_outside_source_root_test_source");
+        let (outside_dir, outside_images) =
+            setup("folder_compress_outside_source_root_test_outside");
+        let test_dest_dir = PathBuf::from("folder_compress_outside_source_root_test_dest");
+        cleanup(&test_dest_dir);
+
+        let mut folder_compressor = FolderCompressor::new(&test_source_dir, &test_dest_dir);
+        folder_compressor.set_preserve_tree(true);
+        let report = folder_compressor
+            .compress_file(&outside_images[0], None, None, None)
+            .unwrap();
+        assert_eq!(report.path, test_dest_dir.join("img_stripe.jpg"));
+        assert!(report.path.is_file());
+        cleanup(test_source_dir);
+        cleanup(outside_dir);
+        cleanup(test_dest_dir);
+    }
+
+    #[test]
+    fn folder_compress_with_dry_run_test() {
+        let (test_source_dir, test_images) = setup("folder_compress_with_dry_run_test_source");
+        let test_dest_dir = PathBuf::from("folder_compress_with_dry_run_test_dest");
+        cleanup(&test_dest_dir);
+
+        let (tx, rx) = mpsc::channel();
+        let mut folder_compressor = FolderCompressor::new(&test_source_dir, &test_dest_dir);
+        folder_compressor.set_dry_run(true);
+        folder_compressor.set_delete_source(true);
+        folder_compressor.set_event_sender(tx);
+        folder_compressor.compress().unwrap();
+        assert!(!test_dest_dir.exists());
+        assert!(test_images.iter().all(|image| image.is_file()));
+
+        let events = rx.try_iter().collect::<Vec<_>>();
+        let mut total_compressed = 0;
+        for event in &events {
+            if let CompressionEvent::FileDone { compressed, .. } = event {
+                assert!(*compressed > 0);
+                total_compressed += compressed;
+            }
+        }
+        assert!(events.contains(&CompressionEvent::DryRunSummary {
+            original: test_images
+                .iter()
+                .map(|image| fs::metadata(image).unwrap().len())
+                .sum(),
+            compressed: total_compressed,
+        }));
+        assert_eq!(events.last(), Some(&CompressionEvent::Finished));
+        cleanup(test_source_dir);
+    }
+
+    #[test]
+    fn folder_compress_with_output_archive_test() {
+        let (test_source_dir, _) = setup("folder_compress_with_output_archive_test_source");
+        fs::create_dir_all(test_source_dir.join("dir")).unwrap();
+        fs::copy(
+            test_source_dir.join("img_stripe.png"),
+            test_source_dir.join("dir").join("img_stripe.png"),
+        )
+        .unwrap();
+        let test_dest_dir = PathBuf::from("folder_compress_with_output_archive_test_dest");
+        cleanup(&test_dest_dir);
+        let archive_path = test_dest_dir.join("compressed.zip");
+
+        let mut folder_compressor = FolderCompressor::new(&test_source_dir, "unused_dest");
+        folder_compressor.set_output_archive(&archive_path);
+        folder_compressor.set_thread_count(2);
+        let summary = folder_compressor.compress().unwrap();
+        assert_eq!(summary.succeeded, 3);
+        assert!(!Path::new("unused_dest").exists());
+
+        let mut archive = zip::ZipArchive::new(File::open(&archive_path).unwrap()).unwrap();
+        let mut names = archive.file_names().map(String::from).collect::<Vec<_>>();
+        names.sort();
+        assert_eq!(
+            names,
+            vec!["dir/img_stripe.jpg", "img_random_rgb.jpg", "img_stripe.jpg"]
+        );
+        let entry = archive.by_name("img_stripe.jpg").unwrap();
+        assert!(entry.size() > 0);
+        cleanup(test_source_dir);
+        cleanup(test_dest_dir);
+    }
+
+    #[cfg(feature = "async")]
+    #[tokio::test]
+    async fn folder_compress_async_test() {
+        let (test_source_dir, test_images) = setup("folder_compress_async_test_source");
+        let test_dest_dir = PathBuf::from("folder_compress_async_test_dest");
+        cleanup(&test_dest_dir);
+
+        let mut folder_compressor = FolderCompressor::new(&test_source_dir, &test_dest_dir);
+        folder_compressor.set_thread_count(2);
+        let results = folder_compressor.compress_async().await.unwrap().results;
+        assert_eq!(results.len(), test_images.len());
+        assert!(results.iter().all(|(_, r)| r.is_ok()));
+        cleanup(test_source_dir);
+        cleanup(test_dest_dir);
+    }
+
+    #[test]
+    fn folder_compress_with_max_inflight_bytes_test() {
+        let (test_source_dir, test_images) =
+            setup("folder_compress_with_max_inflight_bytes_test_source");
+        let test_dest_dir = PathBuf::from("folder_compress_with_max_inflight_bytes_test_dest");
+        cleanup(&test_dest_dir);
+
+        let mut folder_compressor = FolderCompressor::new(&test_source_dir, &test_dest_dir);
+        folder_compressor.set_thread_count(4);
+        folder_compressor.set_max_inflight_bytes(1);
+        let results = folder_compressor.compress().unwrap().results;
+        assert_eq!(results.len(), test_images.len());
+        assert!(results.iter().all(|(_, r)| r.is_ok()));
+        assert_eq!(
+            get_file_list(&test_dest_dir).unwrap().len(),
+            test_images.len()
+        );
+        cleanup(test_source_dir);
+        cleanup(test_dest_dir);
+    }
+
+    #[test]
+    fn folder_compress_with_max_total_output_test() {
+        let (test_source_dir, test_images) =
+            setup("folder_compress_with_max_total_output_test_source");
+        let test_dest_dir = PathBuf::from("folder_compress_with_max_total_output_test_dest");
+        cleanup(&test_dest_dir);
+
+        let (tx, rx) = mpsc::channel();
+        let mut folder_compressor = FolderCompressor::new(&test_source_dir, &test_dest_dir);
+        folder_compressor.set_max_total_output(1);
+        folder_compressor.set_event_sender(tx);
+        let results = folder_compressor.compress().unwrap().results;
+        assert_eq!(results.len(), 1);
+        assert!(get_file_list(&test_dest_dir).unwrap().len() < test_images.len());
+        assert!(rx
+            .try_iter()
+            .any(|e| matches!(e, CompressionEvent::BudgetReached { max_bytes: 1, .. })));
+        cleanup(test_source_dir);
+        cleanup(test_dest_dir);
+    }
+
+    #[test]
+    fn schedule_files_test() {
+        let test_dir = PathBuf::from("schedule_files_test_dir");
+        cleanup(&test_dir);
+        fs::create_dir_all(&test_dir).unwrap();
+        let files = [("small", 10), ("large", 1000), ("medium", 100)]
+            .iter()
+            .map(|(name, size)| {
+                let file = test_dir.join(name);
+                fs::write(&file, vec![0u8; *size]).unwrap();
+                file
+            })
+            .collect::<Vec<_>>();
+
+        assert_eq!(schedule_files(files.clone(), Schedule::AsDiscovered), files);
+        assert_eq!(
+            schedule_files(files.clone(), Schedule::LargestFirst),
+            vec![files[1].clone(), files[2].clone(), files[0].clone()]
+        );
+        cleanup(test_dir);
+    }
+
+    #[test]
+    fn memory_budget_test() {
+        let budget = Arc::new(MemoryBudget::new(100));
+        let first = budget.reserve(60);
+        let large = thread::scope(|s| {
+            let waiting = s.spawn(|| {
+                let _second = budget.reserve(60);
+                let inflight_bytes = *budget.inflight_bytes.lock().unwrap();
+                inflight_bytes
+            });
+            thread::sleep(std::time::Duration::from_millis(50));
+            assert_eq!(*budget.inflight_bytes.lock().unwrap(), 60);
+            drop(first);
+            waiting.join().unwrap()
+        });
+        assert_eq!(large, 60);
+        assert_eq!(*budget.inflight_bytes.lock().unwrap(), 0);
+
+        let _too_large = budget.reserve(1000);
+        assert_eq!(*budget.inflight_bytes.lock().unwrap(), 1000);
+    }
+
+    #[test]
+    fn folder_compress_with_rayon_test() {
+        let test_source_dir = PathBuf::from("folder_compress_with_rayon_test_source");
+        cleanup(&test_source_dir);
+        for i in 0..32 {
+            let sub_dir = test_source_dir.join(format!("dir{}", i % 4));
+            fs::create_dir_all(&sub_dir).unwrap();
+            let size = 16 + i * 4;
+            let img =
+                ImageBuffer::from_fn(size, size, |x, y| image::Rgb([x as u8, y as u8, i as u8]));
+            img.save(sub_dir.join(format!("img{}.png", i))).unwrap();
+        }
+
+        let mut dest_file_lists = Vec::new();
+        for thread_count in [4, 0] {
+            let test_dest_dir = PathBuf::from(format!(
+                "folder_compress_with_rayon_test_dest_{}",
+                thread_count
+            ));
+            cleanup(&test_dest_dir);
+            let mut folder_compressor = FolderCompressor::new(&test_source_dir, &test_dest_dir);
+            folder_compressor.set_thread_count(thread_count);
+            let results = folder_compressor.compress().unwrap().results;
+            assert_eq!(results.len(), 32);
+            assert!(results.iter().all(|(_, r)| r.is_ok()));
+
+            let mut dest_file_list = get_file_list(&test_dest_dir)
+                .unwrap()
+                .into_iter()
+                .map(|p| p.strip_prefix(&test_dest_dir).unwrap().to_path_buf())
+                .collect::<Vec<_>>();
+            dest_file_list.sort();
+            dest_file_lists.push(dest_file_list);
+            cleanup(test_dest_dir);
+        }
+        assert_eq!(dest_file_lists[0].len(), 32);
+        assert_eq!(dest_file_lists[0], dest_file_lists[1]);
+        cleanup(test_source_dir);
+    }
+
+    #[test]
+    fn compress_all_with_auto_thread_count_test() {
+        let (test_source_dir, _) = setup("compress_all_with_auto_thread_count_test_source");
+        let test_dest_dir = PathBuf::from("compress_all_with_auto_thread_count_test_dest");
+        cleanup(&test_dest_dir);
+
+        let summary = compress_all(&test_source_dir, &test_dest_dir, Factor::default(), 0).unwrap();
+        assert_eq!(summary.total, 2);
+        assert_eq!(summary.succeeded, 2);
+        assert_eq!(get_file_list(&test_dest_dir).unwrap().len(), 2);
+        cleanup(test_source_dir);
+        cleanup(test_dest_dir);
+    }
+
+    #[test]
+    fn folder_compress_with_event_sender_test() {
+        let (test_source_dir, test_images) = setup("folder_compress_with_event_sender_test_source");
+        let test_dest_dir = PathBuf::from("folder_compress_with_event_sender_test_dest");
+        cleanup(&test_dest_dir);
+
+        let (tx, rx) = mpsc::channel();
+        let (string_tx, string_rx) = mpsc::channel();
+        let mut folder_compressor = FolderCompressor::new(&test_source_dir, &test_dest_dir);
+        folder_compressor.set_thread_count(2);
+        folder_compressor.set_event_sender(tx);
+        folder_compressor.set_sender(string_tx);
+        folder_compressor.compress().unwrap();
+
+        let events = rx.try_iter().collect::<Vec<_>>();
+        assert_eq!(
+            events.first(),
+            Some(&CompressionEvent::Started {
+                total: test_images.len()
+            })
+        );
+        assert_eq!(events.last(), Some(&CompressionEvent::Finished));
+        let done_count = events
+            .iter()
+            .filter(|e| match e {
+                CompressionEvent::FileDone {
+                    original,
+                    compressed,
+                    ..
+                } => *original > 0 && *compressed > 0,
+                _ => false,
+            })
+            .count();
+        assert_eq!(done_count, test_images.len());
+
+        // The progress is not sent as a message.
+        let messages = string_rx.try_iter().collect::<Vec<_>>();
+        let progress_count = events
+            .iter()
+            .filter(|event| matches!(event, CompressionEvent::Progress { .. }))
+            .count();
+        assert_eq!(progress_count, test_images.len());
+        assert_eq!(messages.len(), events.len() - progress_count);
+        assert!(messages.iter().all(|m| !m.starts_with("Progress")));
+        assert_eq!(messages.last().unwrap(), "Compress complete!");
+        cleanup(test_source_dir);
+        cleanup(test_dest_dir);
+    }
+
+    #[test]
+    fn folder_compress_progress_test() {
+        let (test_source_dir, test_images) = setup("folder_compress_progress_test_source");
+        let test_dest_dir = PathBuf::from("folder_compress_progress_test_dest");
+        cleanup(&test_dest_dir);
+
+        let (tx, rx) = mpsc::channel();
+        let mut folder_compressor = FolderCompressor::new(&test_source_dir, &test_dest_dir);
+        folder_compressor.set_thread_count(2);
+        folder_compressor.set_event_sender(tx);
+        folder_compressor.compress().unwrap();
+
+        let progress = rx
+            .try_iter()
+            .filter_map(|e| match e {
+                CompressionEvent::Progress {
+                    completed,
+                    total,
+                    eta,
+                } => Some((completed, total, eta)),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(progress.len(), test_images.len());
+        assert!(progress
+            .iter()
+            .enumerate()
+            .all(|(i, (completed, total, _))| *completed == i + 1 && *total == test_images.len()));
+        assert_eq!(progress.last().unwrap().2, Duration::ZERO);
+        cleanup(test_source_dir);
+        cleanup(test_dest_dir);
+    }
+
+    #[test]
+    fn folder_compress_with_overwrite_test() {
+        let (test_source_dir, test_images) = setup("folder_compress_with_overwrite_test_source");
+        let test_dest_dir = PathBuf::from("folder_compress_with_overwrite_test_dest");
+        cleanup(&test_dest_dir);
+        fs::create_dir_all(&test_dest_dir).unwrap();
+        let existing = test_dest_dir.join("img_stripe.jpg");
+        fs::write(&existing, "existing").unwrap();
+
+        let (tx, rx) = mpsc::channel();
+        let mut folder_compressor = FolderCompressor::new(&test_source_dir, &test_dest_dir);
+        folder_compressor.set_event_sender(tx);
+        folder_compressor.compress().unwrap();
+        let skipped = rx
+            .try_iter()
+            .filter(|e| matches!(e, CompressionEvent::FileSkipped { .. }))
+            .count();
+        assert_eq!(skipped, 1);
+        assert_eq!(fs::read(&existing).unwrap(), b"existing");
+
+        let (tx, rx) = mpsc::channel();
+        let mut folder_compressor = FolderCompressor::new(&test_source_dir, &test_dest_dir);
+        folder_compressor.set_event_sender(tx);
+        folder_compressor.set_overwrite(true);
+        folder_compressor.compress().unwrap();
+        let done = rx
+            .try_iter()
+            .filter(|e| matches!(e, CompressionEvent::FileDone { .. }))
+            .count();
+        assert_eq!(done, test_images.len());
+        assert_ne!(fs::read(&existing).unwrap(), b"existing");
+        cleanup(test_source_dir);
+        cleanup(test_dest_dir);
+    }
+
+    #[test]
+    fn folder_compress_with_resume_test() {
+        let (test_source_dir, _) = setup("folder_compress_with_resume_test_source");
+        let test_dest_dir = PathBuf::from("folder_compress_with_resume_test_dest");
+        cleanup(&test_dest_dir);
+        fs::create_dir_all(&test_dest_dir).unwrap();
+        let valid = test_dest_dir.join("img_stripe.jpg");
+        image::RgbImage::new(8, 8).save(&valid).unwrap();
+        let valid_data = fs::read(&valid).unwrap();
+        let truncated = test_dest_dir.join("img_random_rgb.jpg");
+        fs::write(&truncated, &valid_data[..valid_data.len() / 2]).unwrap();
+
+        let (tx, rx) = mpsc::channel();
+        let mut folder_compressor = FolderCompressor::new(&test_source_dir, &test_dest_dir);
+        folder_compressor.set_resume(true);
+        folder_compressor.set_event_sender(tx);
+        let summary = folder_compressor.compress().unwrap();
+        assert_eq!(summary.failed, 0);
+        assert_eq!(summary.succeeded, summary.total);
+
+        let events = rx.try_iter().collect::<Vec<_>>();
+        assert!(events.iter().any(|e| matches!(
+            e,
+            CompressionEvent::FileSkipped { path, .. } if path.ends_with("img_stripe.png")
+        )));
+        assert!(events.iter().any(|e| matches!(
+            e,
+            CompressionEvent::FileDone { path, .. } if *path == truncated
+        )));
+        assert_eq!(fs::read(&valid).unwrap(), valid_data);
+        assert!(is_complete_image(&truncated));
+        assert_ne!(image::image_dimensions(&truncated).unwrap(), (8, 8));
+        cleanup(test_source_dir);
+        cleanup(test_dest_dir);
+    }
+
+    #[test]
+    fn file_error_policy_test() {
+        let (test_source_dir, _) = setup("file_error_policy_test_source");
+        fs::write(test_source_dir.join("not_image.txt"), "Hello, World!").unwrap();
+        let test_dest_dir = PathBuf::from("file_error_policy_test_dest");
+
+        cleanup(&test_dest_dir);
+        let mut folder_compressor = FolderCompressor::new(&test_source_dir, &test_dest_dir);
+        folder_compressor.set_file_error_policy(FileErrorPolicy::Copy);
+        folder_compressor.compress().unwrap();
+        assert!(test_dest_dir.join("not_image.txt").is_file());
+        assert!(test_dest_dir.join("img_stripe.jpg").is_file());
+
+        cleanup(&test_dest_dir);
+        let (tx, rx) = mpsc::channel();
+        let mut folder_compressor = FolderCompressor::new(&test_source_dir, &test_dest_dir);
+        folder_compressor.set_file_error_policy(FileErrorPolicy::Skip);
+        folder_compressor.set_event_sender(tx);
+        folder_compressor.compress().unwrap();
+        assert!(!test_dest_dir.join("not_image.txt").exists());
+        assert!(test_dest_dir.join("img_stripe.jpg").is_file());
+        assert!(rx.try_iter().any(|e| matches!(
+            e,
+            CompressionEvent::FileSkipped { path, .. } if path.ends_with("not_image.txt")
+        )));
+
+        cleanup(&test_dest_dir);
+        let mut folder_compressor = FolderCompressor::new(&test_source_dir, &test_dest_dir);
+        folder_compressor.set_file_error_policy(FileErrorPolicy::Abort);
+        assert!(folder_compressor.compress().is_err());
+        assert!(!test_dest_dir.join("not_image.txt").exists());
+
+        cleanup(test_source_dir);
+        cleanup(test_dest_dir);
+    }
+
+    #[test]
+    fn folder_compress_results_test() {
+        let (test_source_dir, test_images) = setup("folder_compress_results_test_source");
+        let corrupt_file = test_source_dir.join("corrupt.png");
+        fs::write(&corrupt_file, b"\x89PNG\r\n\x1a\nnot a png").unwrap();
+        let test_dest_dir = PathBuf::from("folder_compress_results_test_dest");
+        cleanup(&test_dest_dir);
+
+        let mut folder_compressor = FolderCompressor::new(&test_source_dir, &test_dest_dir);
+        folder_compressor.set_thread_count(2);
+        let results = folder_compressor.compress().unwrap().results;
+        assert_eq!(results.len(), test_images.len() + 1);
+        for (path, result) in results {
+            if path == corrupt_file {
+                assert!(result.is_err());
+            } else {
+                assert!(test_images.contains(&path));
+                assert!(result.unwrap().path.is_file());
+            }
+        }
+        cleanup(test_source_dir);
+        cleanup(test_dest_dir);
+    }
+
+    #[test]
+    fn folder_summary_test() {
+        let (test_source_dir, test_images) = setup("folder_summary_test_source");
+        fs::write(test_source_dir.join("corrupt.png"), b"not a png").unwrap();
+        let test_dest_dir = PathBuf::from("folder_summary_test_dest");
+        cleanup(&test_dest_dir);
+
+        let mut folder_compressor = FolderCompressor::new(&test_source_dir, &test_dest_dir);
+        folder_compressor.set_file_error_policy(FileErrorPolicy::Skip);
+        let summary = folder_compressor.compress().unwrap();
+        assert_eq!(summary.total, test_images.len() + 1);
+        assert_eq!(summary.succeeded, test_images.len());
+        assert_eq!(summary.failed, 1);
+        assert_eq!(
+            summary.original_bytes,
+            test_images
+                .iter()
+                .map(|image| fs::metadata(image).unwrap().len())
+                .sum::<u64>()
+        );
+        assert_eq!(
+            summary.compressed_bytes,
+            get_file_list(&test_dest_dir)
+                .unwrap()
+                .iter()
+                .map(|file| fs::metadata(file).unwrap().len())
+                .sum::<u64>()
+        );
+        cleanup(test_source_dir);
+        cleanup(test_dest_dir);
+    }
+
+    #[test]
+    fn folder_compress_with_cancel_token_test() {
+        let (test_source_dir, test_images) = setup("folder_compress_with_cancel_token_source");
+        let test_dest_dir = PathBuf::from("folder_compress_with_cancel_token_dest");
+        cleanup(&test_dest_dir);
+
+        let cancel_token = Arc::new(AtomicBool::new(false));
+        let mut folder_compressor = FolderCompressor::new(&test_source_dir, &test_dest_dir);
+        folder_compressor.set_cancel_token(Arc::clone(&cancel_token));
+        let token = Arc::clone(&cancel_token);
+        folder_compressor.set_cal_func(move |_, _, _| {
+            token.store(true, Ordering::Relaxed);
+            Factor::default()
+        });
+        assert!(folder_compressor.compress().is_err());
+
+        let dest_files = get_file_list(&test_dest_dir).unwrap();
+        assert_eq!(dest_files.len(), 1);
+        assert!(dest_files.len() < test_images.len());
+        assert!(test_source_dir.is_dir());
+        cleanup(test_source_dir);
+        cleanup(test_dest_dir);
+    }
+
+    #[test]
+    fn folder_compress_with_on_file_test() {
+        let (test_source_dir, test_images) = setup("folder_compress_with_on_file_test_source");
+        let test_dest_dir = PathBuf::from("folder_compress_with_on_file_test_dest");
+        cleanup(&test_dest_dir);
+
+        let count = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&count);
+        let mut folder_compressor = FolderCompressor::new(&test_source_dir, &test_dest_dir);
+        folder_compressor.set_thread_count(2);
+        folder_compressor.set_on_file(move |report| {
+            assert!(report.compressed_size > 0);
+            counter.fetch_add(1, Ordering::Relaxed);
+        });
+        folder_compressor.compress().unwrap();
+        assert_eq!(count.load(Ordering::Relaxed), test_images.len());
+        cleanup(test_source_dir);
+        cleanup(test_dest_dir);
+    }
+
+    #[test]
+    fn folder_compress_thread_name_test() {
+        let (test_source_dir, test_images) = setup("folder_compress_thread_name_test_source");
+        let test_dest_dir = PathBuf::from("folder_compress_thread_name_test_dest");
+
+        for prefetch in [false, true] {
+            cleanup(&test_dest_dir);
+            let names = Arc::new(Mutex::new(HashSet::new()));
+            let arc_names = Arc::clone(&names);
+            let mut folder_compressor = FolderCompressor::new(&test_source_dir, &test_dest_dir);
+            folder_compressor.set_thread_count(2);
+            folder_compressor.set_prefetch(prefetch);
+            folder_compressor.set_on_file(move |_| {
+                let name = thread::current().name().map(str::to_string);
+                arc_names.lock().unwrap().insert(name);
+            });
+            let summary = folder_compressor.compress().unwrap();
+            assert_eq!(summary.succeeded, test_images.len());
+            let names = names.lock().unwrap();
+            assert!(!names.is_empty());
+            for name in names.iter() {
+                let name = name.as_deref().unwrap();
+                assert!(
+                    ["img-compress-0", "img-compress-1"].contains(&name),
+                    "{}",
+                    name
+                );
+            }
+        }
+        cleanup(test_source_dir);
+        cleanup(test_dest_dir);
+    }
+
+    #[test]
+    fn folder_compress_worker_panicked_test() {
+        let (test_source_dir, _) = setup("folder_compress_worker_panicked_test_source");
+        let test_dest_dir = PathBuf::from("folder_compress_worker_panicked_test_dest");
+
+        for (thread_count, prefetch) in [(2, false), (2, true), (0, false), (0, true)] {
+            cleanup(&test_dest_dir);
+            let panicked = Arc::new(AtomicBool::new(false));
+            let mut folder_compressor = FolderCompressor::new(&test_source_dir, &test_dest_dir);
+            folder_compressor.set_thread_count(thread_count);
+            folder_compressor.set_prefetch(prefetch);
+            // Only the first file panics, so the other threads keep working.
+            folder_compressor.set_on_file(move |_| {
+                if !panicked.swap(true, Ordering::SeqCst) {
+                    panic!("injected panic");
+                }
+            });
+            match folder_compressor.compress() {
+                Err(CompressError::WorkerPanicked {
+                    panicked,
+                    workers,
+                    message,
+                }) => {
+                    assert_eq!(panicked, 1);
+                    if thread_count > 0 {
+                        assert_eq!(workers, thread_count as usize);
+                    }
+                    assert_eq!(message, "injected panic");
+                }
+                other => panic!("unexpected result: {:?}", other.map(|s| s.succeeded)),
+            }
+        }
+        cleanup(test_source_dir);
+        cleanup(test_dest_dir);
+    }
+
+    #[test]
+    fn folder_compress_iter_test() {
+        let (test_source_dir, _) = setup("folder_compress_iter_test_source");
+        let test_dest_dir = PathBuf::from("folder_compress_iter_test_dest");
+        cleanup(&test_dest_dir);
+        let files = get_file_list(&test_source_dir).unwrap();
+
+        let on_file_count = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&on_file_count);
+        let mut folder_compressor = FolderCompressor::new(&test_source_dir, &test_dest_dir);
+        folder_compressor.set_on_file(move |_| {
+            counter.fetch_add(1, Ordering::SeqCst);
+        });
+        // With one thread, the files complete in the order they are found.
+        let paths = folder_compressor
+            .compress_iter()
+            .map(|result| result.unwrap().path)
+            .collect::<Vec<_>>();
+        let expected = files
+            .iter()
+            .map(|file| test_dest_dir.join(file.with_extension("jpg").file_name().unwrap()))
+            .collect::<Vec<_>>();
+        assert_eq!(paths, expected);
+        assert_eq!(on_file_count.load(Ordering::SeqCst), files.len());
+
+        cleanup(&test_dest_dir);
+        let mut folder_compressor = FolderCompressor::new(&test_source_dir, &test_dest_dir);
+        folder_compressor.set_thread_count(4);
+        let mut paths = folder_compressor
+            .compress_iter()
+            .map(|result| result.unwrap().path)
+            .collect::<Vec<_>>();
+        paths.sort();
+        let mut expected = expected;
+        expected.sort();
+        assert_eq!(paths, expected);
+        cleanup(&test_dest_dir);
+        let mut folder_compressor = FolderCompressor::new(&test_source_dir, &test_dest_dir);
+        folder_compressor.set_cancel_token(Arc::new(AtomicBool::new(true)));
+        let results = folder_compressor.compress_iter().collect::<Vec<_>>();
+        assert_eq!(results.len(), 1);
+        assert!(matches!(
+            results[0],
+            Err(CompressError::Cancelled {
+                processed: 0,
+                total: 2
+            })
+        ));
+
+        // Dropping the iterator after the first report stops the rest of the files.
+        cleanup(&test_dest_dir);
+        for i in 0..10 {
+            fs::copy(&files[0], test_source_dir.join(format!("copy_{}.png", i))).unwrap();
+        }
+        let (event_tx, event_rx) = mpsc::channel();
+        let mut folder_compressor = FolderCompressor::new(&test_source_dir, &test_dest_dir);
+        folder_compressor.set_event_sender(event_tx);
+        assert_eq!(folder_compressor.compress_iter().take(1).count(), 1);
+        // The events end when the compression returns and drops the sender.
+        let events = event_rx.into_iter().collect::<Vec<_>>();
+        let done = events
+            .iter()
+            .filter(|event| matches!(event, CompressionEvent::FileDone { .. }))
+            .count();
+        assert!(done < 12, "{} files are compressed", done);
+        assert!(fs::read_dir(&test_dest_dir).unwrap().count() < 12);
+        cleanup(test_source_dir);
+        cleanup(test_dest_dir);
+    }
+
+    #[test]
+    fn folder_compress_with_cal_func_test() {
+        let (test_source_dir, _) = setup("folder_compress_with_cal_func_test_source");
+        let test_dest_dir = PathBuf::from("folder_compress_with_cal_func_test_dest");
+        cleanup(&test_dest_dir);
+
+        let mut folder_compressor = FolderCompressor::new(&test_source_dir, &test_dest_dir);
+        folder_compressor.set_thread_count(2);
+        folder_compressor.set_cal_func(|width, height, _| {
+            if width == 256 && height == 256 {
+                Factor::new(50., 0.25)
+            } else {
+                Factor::default()
+            }
+        });
+        folder_compressor.compress().unwrap();
+        for file in get_file_list(&test_dest_dir).unwrap() {
+            assert_eq!(image::image_dimensions(&file).unwrap(), (64, 64));
+        }
+        cleanup(test_source_dir);
+        cleanup(test_dest_dir);
+    }
+}
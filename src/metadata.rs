@@ -0,0 +1,96 @@
+//! Functions to check the metadata of image files.
+//!
+//! # Examples
+//!
+//! `contains_metadata` example.
+//! ```no_run
+//! use image_compressor::metadata::contains_metadata;
+//!
+//! if contains_metadata("dest/photo.jpg").unwrap() {
+//!     println!("The photo still has private information!");
+//! }
+//! ```
+
+use std::fs::File;
+use std::io::{self, BufReader, Read};
+use std::path::Path;
+
+/// APP1 marker, which contains EXIF data or XMP data.
+const APP1: u8 = 0xE1;
+
+/// APP13 marker, which contains Photoshop IRB and IPTC data.
+const APP13: u8 = 0xED;
+
+/// Check whether the image file contains EXIF, XMP, or IPTC metadata.
+///
+/// For jpg images, it checks whether there is any APP1 or APP13 marker segment before the image data.
+/// For the other formats, it checks whether there is EXIF data that the `kamadak-exif` crate can read.
+///
+/// # Error
+/// - When the file cannot be read. The error is returned rather than `false`,
+///   so that an unreadable file is never reported as clean.
+/// - When the marker segments of a jpg image are truncated or have a bad length, for the same reason.
+pub fn contains_metadata<P: AsRef<Path>>(path: P) -> io::Result<bool> {
+    let data = std::fs::read(&path)?;
+    if data.starts_with(&[0xFF, 0xD8]) {
+        return jpg_contains_metadata(&data);
+    }
+    let mut reader = BufReader::new(File::open(path)?);
+    Ok(exif::Reader::new().read_from_container(&mut reader).is_ok())
+}
+
+/// Check whether the jpg data has an APP1 or APP13 marker segment.
+///
+/// # Error
+/// - When the marker segments before the image data are truncated or have a bad length.
+fn jpg_contains_metadata(data: &[u8]) -> io::Result<bool> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, "Invalid jpg marker segment");
+    let mut reader = &data[2..];
+    loop {
+        let mut marker = [0u8; 2];
+        reader.read_exact(&mut marker)?;
+        if marker[0] != 0xFF {
+            return Err(invalid());
+        }
+        match marker[1] {
+            APP1 | APP13 => return Ok(true),
+            // Start of scan or end of image. The metadata must be before the image data.
+            0xDA | 0xD9 => return Ok(false),
+            // Markers without a length.
+            0x01 | 0xD0..=0xD7 | 0xFF => continue,
+            _ => {}
+        }
+        let mut length = [0u8; 2];
+        reader.read_exact(&mut length)?;
+        let length = u16::from_be_bytes(length) as usize;
+        if length < 2 || length - 2 > reader.len() {
+            return Err(invalid());
+        }
+        reader = &reader[length - 2..];
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn jpg_contains_metadata_test() {
+        let app0 = [0xFF, 0xE0, 0x00, 0x04, 0x00, 0x00];
+        let app1 = [0xFF, 0xE1, 0x00, 0x04, 0x00, 0x00];
+        let app13 = [0xFF, 0xED, 0x00, 0x04, 0x00, 0x00];
+        let sos = [0xFF, 0xDA, 0x00, 0x02, 0xFF, 0xE1];
+
+        assert!(!jpg_contains_metadata(&[&[0xFF, 0xD8][..], &app0, &sos].concat()).unwrap());
+        assert!(jpg_contains_metadata(&[&[0xFF, 0xD8][..], &app0, &app1, &sos].concat()).unwrap());
+        assert!(jpg_contains_metadata(&[&[0xFF, 0xD8][..], &app13, &sos].concat()).unwrap());
+
+        // Truncated or bad segments are not reported as clean.
+        assert!(jpg_contains_metadata(&[0xFF, 0xD8, 0xFF, 0xE0, 0x00]).is_err());
+        assert!(jpg_contains_metadata(&[0xFF, 0xD8, 0xFF, 0xE0, 0x00, 0x09, 0x00]).is_err());
+        assert!(jpg_contains_metadata(&[0xFF, 0xD8, 0xFF, 0xE0, 0x00, 0x01]).is_err());
+        assert!(
+            jpg_contains_metadata(&[&[0xFF, 0xD8][..], &app0, &[0x12, 0x34]].concat()).is_err()
+        );
+    }
+}
@@ -0,0 +1,90 @@
+//! A small implementation of the structural similarity index (SSIM).
+//!
+//! The images are compared by their luma in 8x8 windows moved by 4 pixels,
+//! and the mean SSIM of the windows is returned.
+
+use image::GrayImage;
+
+/// The size of the window in pixels.
+const WINDOW_SIZE: u32 = 8;
+
+/// The step between the windows in pixels.
+const WINDOW_STEP: u32 = 4;
+
+const C1: f64 = (0.01 * 255.) * (0.01 * 255.);
+const C2: f64 = (0.03 * 255.) * (0.03 * 255.);
+
+/// Compute the mean SSIM of two grayscale images of the same size, between -1 and 1.
+/// 1 means the images are identical.
+///
+/// Images smaller than the window are compared as a single window.
+pub(crate) fn ssim(a: &GrayImage, b: &GrayImage) -> f64 {
+    assert_eq!(a.dimensions(), b.dimensions());
+    let (width, height) = a.dimensions();
+    let window_width = WINDOW_SIZE.min(width);
+    let window_height = WINDOW_SIZE.min(height);
+
+    let mut total = 0.;
+    let mut count = 0;
+    let mut y = 0;
+    while y + window_height <= height {
+        let mut x = 0;
+        while x + window_width <= width {
+            total += window_ssim(a, b, x, y, window_width, window_height);
+            count += 1;
+            x += WINDOW_STEP;
+        }
+        y += WINDOW_STEP;
+    }
+    if count == 0 {
+        return 1.;
+    }
+    total / count as f64
+}
+
+/// Compute the SSIM of the window at `(x, y)`.
+fn window_ssim(a: &GrayImage, b: &GrayImage, x: u32, y: u32, width: u32, height: u32) -> f64 {
+    let n = (width * height) as f64;
+    let (mut sum_a, mut sum_b) = (0., 0.);
+    let (mut sum_aa, mut sum_bb, mut sum_ab) = (0., 0., 0.);
+    for j in y..y + height {
+        for i in x..x + width {
+            let pa = a.get_pixel(i, j)[0] as f64;
+            let pb = b.get_pixel(i, j)[0] as f64;
+            sum_a += pa;
+            sum_b += pb;
+            sum_aa += pa * pa;
+            sum_bb += pb * pb;
+            sum_ab += pa * pb;
+        }
+    }
+    let mean_a = sum_a / n;
+    let mean_b = sum_b / n;
+    let var_a = sum_aa / n - mean_a * mean_a;
+    let var_b = sum_bb / n - mean_b * mean_b;
+    let covariance = sum_ab / n - mean_a * mean_b;
+
+    ((2. * mean_a * mean_b + C1) * (2. * covariance + C2))
+        / ((mean_a * mean_a + mean_b * mean_b + C1) * (var_a + var_b + C2))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::Luma;
+
+    #[test]
+    fn ssim_test() {
+        let a = GrayImage::from_fn(32, 32, |x, y| Luma([((x * 7 + y * 13) % 256) as u8]));
+        assert!((ssim(&a, &a) - 1.).abs() < 1e-9);
+
+        let inverted = GrayImage::from_fn(32, 32, |x, y| Luma([255 - a.get_pixel(x, y)[0]]));
+        assert!(ssim(&a, &inverted) < 0.5);
+
+        let noisy = GrayImage::from_fn(32, 32, |x, y| {
+            Luma([a.get_pixel(x, y)[0].saturating_add(((x + y) % 3) as u8)])
+        });
+        let similarity = ssim(&a, &noisy);
+        assert!(similarity > 0.9 && similarity < 1.);
+    }
+}
@@ -0,0 +1,93 @@
+//! Summary of a folder compression.
+//!
+//! [`FolderCompressor::compress`](super::FolderCompressor::compress) returns a [`FolderSummary`]
+//! with the result of each file and the totals of the compression.
+//! # Examples
+//! ```no_run
+//! use image_compressor::FolderCompressor;
+//!
+//! let comp = FolderCompressor::new("source_dir", "dest_dir");
+//! let summary = comp.compress().unwrap();
+//! println!("{}", summary);
+//! ```
+
+use crate::compressor::CompressionReport;
+use std::fmt;
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// Result of compressing a file in a folder.
+///
+/// The path is the source file, and the error describes why the file was failed or skipped.
+pub type FileResult = (PathBuf, Result<CompressionReport, String>);
+
+/// Totals of a folder compression and the result of each file.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FolderSummary {
+    /// The number of processed files.
+    pub total: usize,
+
+    /// The number of compressed files.
+    pub succeeded: usize,
+
+    /// The number of files that are failed or skipped.
+    pub failed: usize,
+
+    /// The sum of the file sizes in bytes of the compressed files before the compression.
+    pub original_bytes: u64,
+
+    /// The sum of the file sizes in bytes of the compressed files after the compression.
+    pub compressed_bytes: u64,
+
+    /// The time taken by the compression.
+    pub elapsed: Duration,
+
+    /// The result of each file.
+    pub results: Vec<FileResult>,
+}
+
+impl FolderSummary {
+    /// Aggregate the results of the files.
+    pub(crate) fn new(results: Vec<FileResult>, elapsed: Duration) -> Self {
+        let mut summary = FolderSummary {
+            total: results.len(),
+            succeeded: 0,
+            failed: 0,
+            original_bytes: 0,
+            compressed_bytes: 0,
+            elapsed,
+            results: Vec::new(),
+        };
+        for (_, result) in &results {
+            match result {
+                Ok(report) => {
+                    summary.succeeded += 1;
+                    summary.original_bytes += report.original_size;
+                    summary.compressed_bytes += report.compressed_size;
+                }
+                Err(_) => summary.failed += 1,
+            }
+        }
+        summary.results = results;
+        summary
+    }
+
+    /// The number of bytes saved by the compression.
+    pub fn saved_bytes(&self) -> u64 {
+        self.original_bytes.saturating_sub(self.compressed_bytes)
+    }
+}
+
+impl fmt::Display for FolderSummary {
+    /// Format the summary as a line like `Saved 1024 bytes across 3 files (1 failed) in 0.52s`.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Saved {} bytes across {} files ({} failed) in {:.2}s",
+            self.saved_bytes(),
+            self.succeeded,
+            self.failed,
+            self.elapsed.as_secs_f64()
+        )
+    }
+}